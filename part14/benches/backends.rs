@@ -0,0 +1,32 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use lsbasi::Interpreter;
+
+use std::io;
+
+/// Loop-heavy program where the bytecode VM should beat the tree-walker
+const LOOP: &str = r#"
+program Bench;
+var i, sum : integer;
+begin
+    i := 0;
+    sum := 0;
+    while i < 100000 do
+    begin
+        sum := sum + (i - i DIV 7 * 7);
+        i := i + 1
+    end
+end."#;
+
+fn backends(c: &mut Criterion) {
+    let mut group = c.benchmark_group("loop");
+    group.bench_function("tree-walker", |b| b.iter(|| {
+        Interpreter::new(LOOP).report(io::sink()).run().unwrap()
+    }));
+    group.bench_function("vm", |b| b.iter(|| {
+        Interpreter::new(LOOP).report(io::sink()).bytecode(true).run().unwrap()
+    }));
+    group.finish();
+}
+
+criterion_group!(benches, backends);
+criterion_main!(benches);
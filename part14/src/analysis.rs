@@ -0,0 +1,66 @@
+use crate::tokens::*;
+use crate::parser::*;
+use crate::ast::*;
+use crate::symbols::*;
+use crate::lints::*;
+use crate::optimizer::*;
+use crate::diagnostics::*;
+
+use std::collections::HashMap;
+
+/// Program with the results of its semantic analysis, the one
+/// front end of `lsbasi::analyze`, the interpreter, the language
+/// server and the symbol dump
+pub struct Analysis {
+    /// `None` after a syntax error, the only diagnostic then
+    pub program: Option<Program>,
+    /// Errors and warnings sorted by location, `{$WARN}` directives
+    /// applied
+    pub diagnostics: Vec<Diagnostic>,
+    pub scopes: Vec<SymbolTable>,
+    pub resolutions: HashMap<Span, Resolution>,
+}
+
+impl Analysis {
+    /// Parse and analyze `text`, `define` declares the builtins
+    /// before. Constant division by zero and overflow are reported
+    /// either way, with `fold` the program keeps its constant
+    /// subexpressions folded.
+    pub fn new(text: &str, define: impl FnOnce(&mut SemanticAnalyzer), fold: bool) -> Analysis {
+        let mut parser = Parser::new(text);
+        let mut program = match parser.try_parse() {
+            Ok(program) => program,
+            Err(diag) => return Analysis {
+                program: None,
+                diagnostics: vec![diag],
+                scopes: Vec::new(),
+                resolutions: HashMap::new(),
+            },
+        };
+        let mut analyzer = SemanticAnalyzer::default();
+        define(&mut analyzer);
+        analyzer.visit_program(&program);
+        let mut diagnostics = analyzer.diagnostics;
+        diagnostics.extend(lint(&analyzer.scopes));
+        let mut folder = ConstantFolder::default();
+        if fold {
+            folder.visit_program_mut(&mut program);
+        }
+        else {
+            folder.visit_program_mut(&mut program.clone());
+        }
+        diagnostics.extend(folder.diagnostics);
+        let mut diagnostics = apply_directives(diagnostics, parser.directives());
+        diagnostics.sort_by_key(|diag| (diag.span.line, diag.span.column));
+        Analysis {
+            program: Some(program),
+            diagnostics,
+            scopes: analyzer.scopes,
+            resolutions: analyzer.resolutions,
+        }
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.diagnostics.iter().any(|diag| diag.is_error())
+    }
+}
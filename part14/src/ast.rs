@@ -1,182 +1,678 @@
 use crate::tokens::*;
-use crate::lexer::*;
-
-#[derive(Debug, PartialEq)]
-pub enum Root {
-    Compound,
-    Num(VariableValue),
-    Program {
-        name: String,
+
+/// program : PROGRAM name SEMI block DOT
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct Program {
+    pub name: Ident,
+    pub block: Block,
+}
+
+/// block : declarations compound_statement
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct Block {
+    pub decls: Vec<Decl>,
+    pub body: Vec<Stmt>,
+    /// Location of the closing END
+    pub end: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum Decl {
+    Var(VarDecl),
+    Procedure(ProcedureDecl),
+}
+
+/// Single variable, `VAR a, b : INTEGER` gives two of them
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct VarDecl {
+    pub name: Ident,
+    pub typ: Type,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct ProcedureDecl {
+    pub name: Ident,
+    pub params: Vec<Param>,
+    /// `None` for `PROCEDURE name(params); FORWARD;`
+    pub block: Option<Block>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct Param {
+    pub name: Ident,
+    pub typ: Type,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct Ident {
+    pub name: String,
+    pub span: Span,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Type {
+    Integer,
+    Real,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum Stmt {
+    Compound {
+        body: Vec<Stmt>,
+        span: Span,
+        /// Location of the closing END
+        end: Span,
     },
-    VarDecl,
-    VarID {
-        name: String,
-        value: VariableValue,
+    Assign {
+        target: Ident,
+        value: Expr,
+    },
+    Call {
+        name: Ident,
+        args: Vec<Expr>,
+    },
+    If {
+        cond: Expr,
+        then_branch: Box<Stmt>,
+        else_branch: Option<Box<Stmt>>,
+        span: Span,
     },
-    ProcedureDecl {
-        name: String,
+    While {
+        cond: Expr,
+        body: Box<Stmt>,
+        span: Span,
+    },
+    /// Builtin `WRITELN(expr, ...)`
+    Writeln {
+        args: Vec<Expr>,
+        span: Span,
     },
-    Param,
-    Assign,
-    BinOp(Token),
-    UnaryOp(Token),
     NoOp,
 }
 
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum Expr {
+    Num {
+        value: VariableValue,
+        span: Span,
+    },
+    Var(Ident),
+    BinOp {
+        op: BinOp,
+        left: Box<Expr>,
+        right: Box<Expr>,
+        span: Span,
+    },
+    UnaryOp {
+        op: UnaryOp,
+        operand: Box<Expr>,
+        span: Span,
+    },
+    /// Call of a native function, see `Interpreter::register_fn`
+    Call {
+        name: Ident,
+        args: Vec<Expr>,
+    },
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    /// `/`, always real
+    Div,
+    /// `DIV`, always integer
+    IntDiv,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
 #[derive(Debug, Copy, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum UnaryOp {
+    Plus,
+    Minus,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[non_exhaustive]
 pub enum VariableValue {
-    Intereg(i32),
+    Integer(i32),
     Real(f64),
+    Boolean(bool),
+    /// Declared but not yet assigned variable of the given type
+    Undefined(Type),
     None,
 }
 
-#[derive(Debug, Default)]
-pub struct AST {
-    pub root:  Root,
-    pub left:  Option<Box<AST>>,
-    pub right: Option<Box<AST>>,
+impl Stmt {
+    /// Location of the statement start, `NoOp` has none
+    pub fn span(&self) -> Span {
+        match self {
+            Stmt::Compound{span, ..} => *span,
+            Stmt::Assign{target, ..} => target.span,
+            Stmt::Call{name, ..} => name.span,
+            Stmt::If{span, ..} => *span,
+            Stmt::While{span, ..} => *span,
+            Stmt::Writeln{span, ..} => *span,
+            Stmt::NoOp => Span::default(),
+        }
+    }
 }
 
-pub trait NodeVisitor {
-    type Result;
-    fn visit(&mut self, node: &AST) -> Self::Result;
+impl Expr {
+    pub fn span(&self) -> Span {
+        match self {
+            Expr::Num{span, ..} => *span,
+            Expr::Var(ident) => ident.span,
+            Expr::BinOp{span, ..} => *span,
+            Expr::UnaryOp{span, ..} => *span,
+            Expr::Call{name, ..} => name.span,
+        }
+    }
 }
 
+impl BinOp {
+    pub fn is_relational(&self) -> bool {
+        matches!(self,
+            BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge)
+    }
+
+    /// `/` and `DIV` fail on zero divisor, `DIV` truncates it first
+    pub(crate) fn divides_by_zero(&self, right: VariableValue) -> bool {
+        match self {
+            BinOp::Div => right.is_zero(),
+            BinOp::IntDiv => right.as_integer().is_zero(),
+            _ => false,
+        }
+    }
+
+    /// `/` gives real and `DIV` gives integer whatever the operands are,
+    /// `None` when the integer result overflows
+    pub(crate) fn apply(&self, left: VariableValue, right: VariableValue) -> Option<VariableValue> {
+        match self {
+            BinOp::Add => left.arithmetic(right, i32::checked_add, |a, b| a + b),
+            BinOp::Sub => left.arithmetic(right, i32::checked_sub, |a, b| a - b),
+            BinOp::Mul => left.arithmetic(right, i32::checked_mul, |a, b| a * b),
+            BinOp::Div => left.as_real().arithmetic(right.as_real(), i32::checked_div, |a, b| a / b),
+            BinOp::IntDiv => left.as_integer().arithmetic(right.as_integer(), i32::checked_div, |a, b| a / b),
+            _ => Some(left.compare(*self, right)),
+        }
+    }
+}
 
-impl Default for Root {
-    fn default() -> Self {
-        Root::NoOp
+/// Procedure with a body found by `procedures`
+#[derive(Debug, Copy, Clone)]
+pub struct ProcedureBody<'a> {
+    pub decl: &'a ProcedureDecl,
+    pub block: &'a Block,
+    /// Scope level of the body, the program body is 1
+    pub level: u32,
+    /// Index of the enclosing procedure, `None` at the top level
+    pub parent: Option<usize>,
+}
+
+/// Procedures with a body in declaration order, each before the ones
+/// nested in it. The backends number them by their index.
+pub fn procedures(program: &Program) -> Vec<ProcedureBody<'_>> {
+    fn collect<'a>(block: &'a Block, level: u32, parent: Option<usize>,
+        out: &mut Vec<ProcedureBody<'a>>)
+    {
+        for decl in &block.decls {
+            if let Decl::Procedure(decl @ ProcedureDecl{block: Some(block), ..}) = decl {
+                out.push(ProcedureBody { decl, block, level: level + 1, parent });
+                collect(block, level + 1, Some(out.len() - 1), out);
+            }
+        }
     }
+    let mut out = Vec::new();
+    collect(&program.block, 1, None, &mut out);
+    out
 }
 
-/// Tree implementation
-/// [Of Boxes and Trees - Smart Pointers in Rust](https://endler.dev/2017/boxes-and-trees/)
-impl AST {
-    pub fn new(root: Root) -> AST {
-        AST { root, ..Default::default() }
+/// Read-only traversal of the tree. Every method walks into
+/// the children by default, a pass overrides the nodes it cares about
+/// and calls `walk_*` to continue the traversal.
+pub trait Visitor {
+    fn visit_program(&mut self, program: &Program) {
+        walk_program(self, program);
+    }
+    fn visit_block(&mut self, block: &Block) {
+        walk_block(self, block);
+    }
+    fn visit_decl(&mut self, decl: &Decl) {
+        walk_decl(self, decl);
     }
-    pub fn left(mut self, leaf: AST) -> Self {
-        self.left = Some(Box::new(leaf));
-        self
+    fn visit_var_decl(&mut self, _decl: &VarDecl) {}
+    fn visit_procedure_decl(&mut self, decl: &ProcedureDecl) {
+        walk_procedure_decl(self, decl);
     }
-    pub fn right(mut self, leaf: AST) -> Self {
-        self.right = Some(Box::new(leaf));
-        self
+    fn visit_param(&mut self, _param: &Param) {}
+    fn visit_stmt(&mut self, stmt: &Stmt) {
+        walk_stmt(self, stmt);
     }
+    fn visit_expr(&mut self, expr: &Expr) {
+        walk_expr(self, expr);
+    }
+    /// Variable read in an expression
+    fn visit_var(&mut self, _ident: &Ident) {}
+}
 
-    pub fn get_name(&self) -> String {
-        match &self.root {
-            Root::Program{name} => name.to_string(),
-            Root::VarID{name,value} => name.to_string(),
-            Root::ProcedureDecl{name} => name.to_string(),
-            _ => unimplemented!(),
-        }
+pub fn walk_program<V: Visitor + ?Sized>(v: &mut V, program: &Program) {
+    v.visit_block(&program.block);
+}
+
+pub fn walk_block<V: Visitor + ?Sized>(v: &mut V, block: &Block) {
+    for decl in &block.decls {
+        v.visit_decl(decl);
+    }
+    for stmt in &block.body {
+        v.visit_stmt(stmt);
+    }
+}
+
+pub fn walk_decl<V: Visitor + ?Sized>(v: &mut V, decl: &Decl) {
+    match decl {
+        Decl::Var(decl) => v.visit_var_decl(decl),
+        Decl::Procedure(decl) => v.visit_procedure_decl(decl),
+    }
+}
+
+pub fn walk_procedure_decl<V: Visitor + ?Sized>(v: &mut V, decl: &ProcedureDecl) {
+    for param in &decl.params {
+        v.visit_param(param);
+    }
+    if let Some(block) = &decl.block {
+        v.visit_block(block);
+    }
+}
+
+pub fn walk_stmt<V: Visitor + ?Sized>(v: &mut V, stmt: &Stmt) {
+    match stmt {
+        Stmt::Compound{body, ..} => {
+            for stmt in body {
+                v.visit_stmt(stmt);
+            }
+        },
+        Stmt::Assign{value, ..} => v.visit_expr(value),
+        Stmt::Call{args, ..} | Stmt::Writeln{args, ..} => {
+            for arg in args {
+                v.visit_expr(arg);
+            }
+        },
+        Stmt::If{cond, then_branch, else_branch, ..} => {
+            v.visit_expr(cond);
+            v.visit_stmt(then_branch);
+            if let Some(else_branch) = else_branch {
+                v.visit_stmt(else_branch);
+            }
+        },
+        Stmt::While{cond, body, ..} => {
+            v.visit_expr(cond);
+            v.visit_stmt(body);
+        },
+        Stmt::NoOp => {},
+    }
+}
+
+pub fn walk_expr<V: Visitor + ?Sized>(v: &mut V, expr: &Expr) {
+    match expr {
+        Expr::Num{..} => {},
+        Expr::Var(ident) => v.visit_var(ident),
+        Expr::BinOp{left, right, ..} => {
+            v.visit_expr(left);
+            v.visit_expr(right);
+        },
+        Expr::UnaryOp{operand, ..} => v.visit_expr(operand),
+        Expr::Call{args, ..} => {
+            for arg in args {
+                v.visit_expr(arg);
+            }
+        },
+    }
+}
+
+/// In-place rewriting traversal with the same shape as `Visitor`.
+/// A pass may replace the visited node, e.g. `*expr = folded`, and
+/// decides whether to walk into the children before or after that.
+pub trait VisitorMut {
+    fn visit_program_mut(&mut self, program: &mut Program) {
+        walk_program_mut(self, program);
+    }
+    fn visit_block_mut(&mut self, block: &mut Block) {
+        walk_block_mut(self, block);
+    }
+    fn visit_decl_mut(&mut self, decl: &mut Decl) {
+        walk_decl_mut(self, decl);
+    }
+    fn visit_var_decl_mut(&mut self, _decl: &mut VarDecl) {}
+    fn visit_procedure_decl_mut(&mut self, decl: &mut ProcedureDecl) {
+        walk_procedure_decl_mut(self, decl);
+    }
+    fn visit_param_mut(&mut self, _param: &mut Param) {}
+    fn visit_stmt_mut(&mut self, stmt: &mut Stmt) {
+        walk_stmt_mut(self, stmt);
+    }
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        walk_expr_mut(self, expr);
+    }
+    fn visit_var_mut(&mut self, _ident: &mut Ident) {}
+}
+
+pub fn walk_program_mut<V: VisitorMut + ?Sized>(v: &mut V, program: &mut Program) {
+    v.visit_block_mut(&mut program.block);
+}
+
+pub fn walk_block_mut<V: VisitorMut + ?Sized>(v: &mut V, block: &mut Block) {
+    for decl in &mut block.decls {
+        v.visit_decl_mut(decl);
+    }
+    for stmt in &mut block.body {
+        v.visit_stmt_mut(stmt);
+    }
+}
+
+pub fn walk_decl_mut<V: VisitorMut + ?Sized>(v: &mut V, decl: &mut Decl) {
+    match decl {
+        Decl::Var(decl) => v.visit_var_decl_mut(decl),
+        Decl::Procedure(decl) => v.visit_procedure_decl_mut(decl),
+    }
+}
+
+pub fn walk_procedure_decl_mut<V: VisitorMut + ?Sized>(v: &mut V, decl: &mut ProcedureDecl) {
+    for param in &mut decl.params {
+        v.visit_param_mut(param);
+    }
+    if let Some(block) = &mut decl.block {
+        v.visit_block_mut(block);
+    }
+}
+
+pub fn walk_stmt_mut<V: VisitorMut + ?Sized>(v: &mut V, stmt: &mut Stmt) {
+    match stmt {
+        Stmt::Compound{body, ..} => {
+            for stmt in body {
+                v.visit_stmt_mut(stmt);
+            }
+        },
+        Stmt::Assign{value, ..} => v.visit_expr_mut(value),
+        Stmt::Call{args, ..} | Stmt::Writeln{args, ..} => {
+            for arg in args {
+                v.visit_expr_mut(arg);
+            }
+        },
+        Stmt::If{cond, then_branch, else_branch, ..} => {
+            v.visit_expr_mut(cond);
+            v.visit_stmt_mut(then_branch);
+            if let Some(else_branch) = else_branch {
+                v.visit_stmt_mut(else_branch);
+            }
+        },
+        Stmt::While{cond, body, ..} => {
+            v.visit_expr_mut(cond);
+            v.visit_stmt_mut(body);
+        },
+        Stmt::NoOp => {},
+    }
+}
+
+pub fn walk_expr_mut<V: VisitorMut + ?Sized>(v: &mut V, expr: &mut Expr) {
+    match expr {
+        Expr::Num{..} => {},
+        Expr::Var(ident) => v.visit_var_mut(ident),
+        Expr::BinOp{left, right, ..} => {
+            v.visit_expr_mut(left);
+            v.visit_expr_mut(right);
+        },
+        Expr::UnaryOp{operand, ..} => v.visit_expr_mut(operand),
+        Expr::Call{args, ..} => {
+            for arg in args {
+                v.visit_expr_mut(arg);
+            }
+        },
     }
 }
 
 impl VariableValue {
-    pub fn assign(&mut self, rhs: VariableValue) -> Self {
+    pub(crate) fn assign(&mut self, rhs: VariableValue) -> Self {
+        if let Self::Undefined(typ) = *self {
+            *self = Self::from(typ);
+        }
         *self = match (&self, rhs) {
-            (Self::Intereg(_), Self::Intereg(v)) => Self::Intereg(v),
-            (Self::Intereg(_), Self::Real(v)) => Self::Real(v as f64),
-            (Self::Real(_), Self::Intereg(v)) => Self::Real(v as f64),
+            (Self::Integer(_), Self::Integer(v)) => Self::Integer(v),
+            (Self::Real(_), Self::Integer(v)) => Self::Real(v as f64),
             (Self::Real(_), Self::Real(v)) => Self::Real(v),
             _ => unimplemented!()
         };
         *self
     }
 
-    pub fn as_integer(&self) -> Self {
+    pub(crate) fn as_integer(&self) -> Self {
         match self {
-            Self::Intereg(n) => Self::Intereg(*n),
-            Self::Real(n) => Self::Intereg(*n as i32),
+            Self::Integer(n) => Self::Integer(*n),
+            Self::Real(n) => Self::Integer(*n as i32),
             _ => unimplemented!()
         }
     }
 
-    pub fn as_real(&self) -> Self {
+    pub(crate) fn as_real(&self) -> Self {
         match self {
-            Self::Intereg(n) => Self::Real(*n as f64),
+            Self::Integer(n) => Self::Real(*n as f64),
             Self::Real(n) => Self::Real(*n),
             _ => unimplemented!()
         }
     }
-}
 
-use std::convert::From;
+    /// `None` when the integer overflows
+    pub(crate) fn checked_neg(self) -> Option<Self> {
+        match self {
+            Self::Real(n) => Some(Self::Real(-n)),
+            Self::Integer(n) => n.checked_neg().map(Self::Integer),
+            _ => unimplemented!()
+        }
+    }
 
-impl From<Keyword> for VariableValue {
-    fn from(kw: Keyword) -> Self {
-        match kw {
-            Keyword::INTEREG => VariableValue::Intereg(0 as i32),
-            Keyword::REAL => VariableValue::Real(0 as f64),
+    /// `int` of two integers, `real` when either is real
+    fn arithmetic(self, rhs: Self, int: fn(i32, i32) -> Option<i32>,
+        real: fn(f64, f64) -> f64) -> Option<Self>
+    {
+        match (self, rhs) {
+            (Self::Integer(a), Self::Integer(b)) => int(a, b).map(Self::Integer),
+            (Self::Integer(a), Self::Real(b)) => Some(Self::Real(real(a as f64, b))),
+            (Self::Real(a), Self::Integer(b)) => Some(Self::Real(real(a, b as f64))),
+            (Self::Real(a), Self::Real(b)) => Some(Self::Real(real(a, b))),
             _ => unimplemented!()
         }
     }
-}
 
-use std::ops::{Neg, Add, Sub, Mul, Div};
+    pub fn is_zero(&self) -> bool {
+        match self {
+            Self::Integer(n) => *n == 0,
+            Self::Real(n) => *n == 0.0,
+            _ => false,
+        }
+    }
 
-impl Neg for VariableValue
-{
-    type Output = Self;
-    fn neg(self) -> Self {
+    /// Type of the value
+    pub(crate) fn type_of(&self) -> Type {
         match self {
-            Self::Real(n) => Self::Real(-n),
-            Self::Intereg(n) => Self::Intereg(-n),
+            Self::Integer(_) => Type::Integer,
+            Self::Real(_) => Type::Real,
+            Self::Undefined(typ) => *typ,
             _ => unimplemented!()
         }
     }
-}
 
-impl Add for VariableValue {
-    type Output = Self;
-    fn add(self, rhs: Self) -> Self {
-        match (self, rhs) {
-            (Self::Intereg(a), Self::Intereg(b)) => Self::Intereg(a+b),
-            (Self::Intereg(a), Self::Real(b)) => Self::Real((a as f64)+b),
-            (Self::Real(a), Self::Intereg(b)) => Self::Real(a+(b as f64)),
-            (Self::Real(a), Self::Real(b)) => Self::Real(a+b),
+    /// Relational operators, mixed operands are compared as reals
+    pub(crate) fn compare(&self, op: BinOp, rhs: Self) -> Self {
+        use std::cmp::Ordering;
+        let ord = match (*self, rhs) {
+            (Self::Integer(a), Self::Integer(b)) => a.partial_cmp(&b),
+            (a, b) => match (a.as_real(), b.as_real()) {
+                (Self::Real(a), Self::Real(b)) => a.partial_cmp(&b),
+                _ => unreachable!()
+            },
+        };
+        let res = match (op, ord) {
+            (_, None) => false,
+            (BinOp::Eq, Some(ord)) => ord == Ordering::Equal,
+            (BinOp::Ne, Some(ord)) => ord != Ordering::Equal,
+            (BinOp::Lt, Some(ord)) => ord == Ordering::Less,
+            (BinOp::Le, Some(ord)) => ord != Ordering::Greater,
+            (BinOp::Gt, Some(ord)) => ord == Ordering::Greater,
+            (BinOp::Ge, Some(ord)) => ord != Ordering::Less,
             _ => unimplemented!()
+        };
+        Self::Boolean(res)
+    }
+}
+
+use std::convert::From;
+
+impl From<Type> for VariableValue {
+    fn from(typ: Type) -> Self {
+        match typ {
+            Type::Integer => VariableValue::Integer(0),
+            Type::Real => VariableValue::Real(0.0),
         }
     }
 }
 
-impl Sub for VariableValue {
-    type Output = Self;
-    fn sub(self, rhs: Self) -> Self {
-        match (self, rhs) {
-            (Self::Intereg(a), Self::Intereg(b)) => Self::Intereg(a-b),
-            (Self::Intereg(a), Self::Real(b)) => Self::Real((a as f64)-b),
-            (Self::Real(a), Self::Intereg(b)) => Self::Real(a-(b as f64)),
-            (Self::Real(a), Self::Real(b)) => Self::Real(a-b),
-            _ => unimplemented!()
+impl From<i32> for VariableValue {
+    fn from(n: i32) -> Self {
+        VariableValue::Integer(n)
+    }
+}
+
+impl From<f64> for VariableValue {
+    fn from(n: f64) -> Self {
+        VariableValue::Real(n)
+    }
+}
+
+impl From<bool> for VariableValue {
+    fn from(b: bool) -> Self {
+        VariableValue::Boolean(b)
+    }
+}
+
+use std::convert::TryFrom;
+
+/// The error gives the value back
+impl TryFrom<VariableValue> for i32 {
+    type Error = VariableValue;
+    fn try_from(value: VariableValue) -> Result<Self, Self::Error> {
+        match value {
+            VariableValue::Integer(n) => Ok(n),
+            value => Err(value),
         }
     }
 }
 
-impl Mul for VariableValue {
-    type Output = Self;
-    fn mul(self, rhs: Self) -> Self {
-        match (self, rhs) {
-            (Self::Intereg(a), Self::Intereg(b)) => Self::Intereg(a*b),
-            (Self::Intereg(a), Self::Real(b)) => Self::Real((a as f64)*b),
-            (Self::Real(a), Self::Intereg(b)) => Self::Real(a*(b as f64)),
-            (Self::Real(a), Self::Real(b)) => Self::Real(a*b),
-            _ => unimplemented!()
+/// Integers widen to real
+impl TryFrom<VariableValue> for f64 {
+    type Error = VariableValue;
+    fn try_from(value: VariableValue) -> Result<Self, Self::Error> {
+        match value {
+            VariableValue::Integer(n) => Ok(n as f64),
+            VariableValue::Real(n) => Ok(n),
+            value => Err(value),
         }
     }
 }
 
-impl Div for VariableValue {
-    type Output = Self;
-    fn div(self, rhs: Self) -> Self {
-        match (self, rhs) {
-            (Self::Intereg(a), Self::Intereg(b)) => Self::Intereg(a/b),
-            (Self::Intereg(a), Self::Real(b)) => Self::Real((a as f64)/b),
-            (Self::Real(a), Self::Intereg(b)) => Self::Real(a/(b as f64)),
-            (Self::Real(a), Self::Real(b)) => Self::Real(a/b),
-            _ => unimplemented!()
+impl TryFrom<VariableValue> for bool {
+    type Error = VariableValue;
+    fn try_from(value: VariableValue) -> Result<Self, Self::Error> {
+        match value {
+            VariableValue::Boolean(b) => Ok(b),
+            value => Err(value),
+        }
+    }
+}
+
+impl Type {
+    /// Whether a variable of the type can hold `value`, integers
+    /// convert to real but not the other way round
+    pub fn accepts(&self, value: VariableValue) -> bool {
+        matches!((self, value),
+            (Type::Integer, VariableValue::Integer(_)) |
+            (Type::Real, VariableValue::Integer(_)) |
+            (Type::Real, VariableValue::Real(_)))
+    }
+}
+
+use std::fmt;
+
+/// Text printed by `WRITELN`, reals with six decimals
+impl fmt::Display for VariableValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Integer(n) => write!(f, "{}", n),
+            Self::Real(n) => write!(f, "{:.6}", n),
+            Self::Boolean(b) => write!(f, "{}", if *b { "TRUE" } else { "FALSE" }),
+            Self::Undefined(_) | Self::None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::*;
+
+    /// Drop `+x` and replace IF with a constant condition by its branch
+    struct Desugar;
+
+    impl VisitorMut for Desugar {
+        fn visit_stmt_mut(&mut self, stmt: &mut Stmt) {
+            walk_stmt_mut(self, stmt);
+            if let Stmt::If{cond: Expr::BinOp{op: BinOp::Eq, left, right, ..}, then_branch, ..} = stmt {
+                if let (Expr::Num{value: a, ..}, Expr::Num{value: b, ..}) = (&**left, &**right) {
+                    if a == b {
+                        *stmt = std::mem::replace(then_branch, Stmt::NoOp);
+                    }
+                }
+            }
+        }
+
+        fn visit_expr_mut(&mut self, expr: &mut Expr) {
+            walk_expr_mut(self, expr);
+            if let Expr::UnaryOp{op: UnaryOp::Plus, operand, ..} = expr {
+                let operand = std::mem::replace(&mut **operand,
+                    Expr::Num{value: VariableValue::None, span: Span::default()});
+                *expr = operand;
+            }
+        }
+    }
+
+    #[test]
+    fn rewrite() {
+        let mut program = Parser::new("var x : integer; begin if 1 = 1 then x := + + 2 end.")
+            .parse();
+        Desugar.visit_program_mut(&mut program);
+        match &program.block.body[0] {
+            Stmt::Assign{target, value: Expr::Num{value, ..}} => {
+                assert_eq!(target.name, "X");
+                assert_eq!(*value, VariableValue::Integer(2));
+            },
+            stmt => panic!("Unexpected {:?}", stmt),
         }
     }
 }
@@ -0,0 +1,671 @@
+use crate::tokens::*;
+use crate::ast::*;
+
+use std::fmt;
+use std::io::{self, Read, Write};
+
+/// File starts with the magic and the format version
+pub const MAGIC: &[u8; 4] = b"LSBC";
+pub const VERSION: u16 = 1;
+
+/// Stack machine instruction
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Op {
+    /// Push constant from the pool
+    Const(u16),
+    /// Push variable `slot` of the frame `depth` static links up
+    Load {
+        depth: u8,
+        slot: u16,
+    },
+    /// Pop value and assign it to the variable
+    Store {
+        depth: u8,
+        slot: u16,
+    },
+    Neg,
+    /// Pop right, then left operand and push the result
+    Binary(BinOp),
+    Jump(u32),
+    /// Pop condition and jump when it's false
+    JumpIfFalse(u32),
+    /// Call procedure, arguments are on the stack. The procedure is
+    /// declared in the scope `depth` static links up from the caller.
+    Call {
+        proc: u16,
+        depth: u8,
+    },
+    /// Pop `count` values and print them on one line
+    Writeln(u16),
+    Return,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Procedure {
+    pub name: String,
+    /// Nesting level of the body, the program is 1
+    pub level: u32,
+    pub params: u16,
+    /// Parameters then local variables
+    pub locals: Vec<(String, Type)>,
+    pub code: Vec<Op>,
+    /// Source location of every instruction
+    pub spans: Vec<Span>,
+}
+
+/// Compiled program
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Module {
+    pub constants: Vec<VariableValue>,
+    /// The program body is the procedure 0
+    pub procedures: Vec<Procedure>,
+}
+
+impl Procedure {
+    pub fn new(name: impl Into<String>, level: u32) -> Self {
+        Procedure {
+            name: name.into(),
+            level,
+            params: 0,
+            locals: Vec::new(),
+            code: Vec::new(),
+            spans: Vec::new(),
+        }
+    }
+}
+
+/// Failure to load a bytecode file
+#[derive(Debug)]
+pub enum FormatError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u16),
+    Malformed(String),
+}
+
+impl From<io::Error> for FormatError {
+    fn from(e: io::Error) -> Self {
+        FormatError::Io(e)
+    }
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FormatError::Io(e) => write!(f, "{}", e),
+            FormatError::BadMagic => write!(f, "not a bytecode file"),
+            FormatError::UnsupportedVersion(v) =>
+                write!(f, "unsupported bytecode version {}, expected {}", v, VERSION),
+            FormatError::Malformed(msg) => write!(f, "malformed bytecode: {}", msg),
+        }
+    }
+}
+
+/// Operators in the order of their encoding
+const BIN_OPS: [BinOp; 11] = [
+    BinOp::Add, BinOp::Sub, BinOp::Mul, BinOp::Div, BinOp::IntDiv,
+    BinOp::Eq, BinOp::Ne, BinOp::Lt, BinOp::Le, BinOp::Gt, BinOp::Ge,
+];
+
+/// Little-endian encoder of the file sections
+struct Writer<'a, W: Write> {
+    out: &'a mut W,
+}
+
+impl<'a, W: Write> Writer<'a, W> {
+    fn u8(&mut self, v: u8) -> io::Result<()> {
+        self.out.write_all(&[v])
+    }
+    fn u16(&mut self, v: u16) -> io::Result<()> {
+        self.out.write_all(&v.to_le_bytes())
+    }
+    fn u32(&mut self, v: u32) -> io::Result<()> {
+        self.out.write_all(&v.to_le_bytes())
+    }
+    fn len(&mut self, v: usize) -> io::Result<()> {
+        self.u32(v as u32)
+    }
+    fn str(&mut self, v: &str) -> io::Result<()> {
+        self.len(v.len())?;
+        self.out.write_all(v.as_bytes())
+    }
+    fn typ(&mut self, typ: Type) -> io::Result<()> {
+        self.u8(match typ {
+            Type::Integer => 0,
+            Type::Real => 1,
+        })
+    }
+
+    fn value(&mut self, value: &VariableValue) -> io::Result<()> {
+        match value {
+            VariableValue::Integer(n) => {
+                self.u8(0)?;
+                self.out.write_all(&n.to_le_bytes())
+            },
+            VariableValue::Real(n) => {
+                self.u8(1)?;
+                self.out.write_all(&n.to_le_bytes())
+            },
+            VariableValue::Boolean(b) => {
+                self.u8(2)?;
+                self.u8(*b as u8)
+            },
+            _ => unreachable!("constant {:?}", value),
+        }
+    }
+
+    fn op(&mut self, op: &Op) -> io::Result<()> {
+        match *op {
+            Op::Const(index) => {
+                self.u8(0)?;
+                self.u16(index)
+            },
+            Op::Load{depth, slot} => {
+                self.u8(1)?;
+                self.u8(depth)?;
+                self.u16(slot)
+            },
+            Op::Store{depth, slot} => {
+                self.u8(2)?;
+                self.u8(depth)?;
+                self.u16(slot)
+            },
+            Op::Neg => self.u8(3),
+            Op::Binary(op) => {
+                self.u8(4)?;
+                self.u8(BIN_OPS.iter().position(|o| *o == op).unwrap() as u8)
+            },
+            Op::Jump(target) => {
+                self.u8(5)?;
+                self.u32(target)
+            },
+            Op::JumpIfFalse(target) => {
+                self.u8(6)?;
+                self.u32(target)
+            },
+            Op::Call{proc, depth} => {
+                self.u8(7)?;
+                self.u16(proc)?;
+                self.u8(depth)
+            },
+            Op::Return => self.u8(8),
+            Op::Writeln(count) => {
+                self.u8(9)?;
+                self.u16(count)
+            },
+        }
+    }
+
+    fn procedure(&mut self, procedure: &Procedure) -> io::Result<()> {
+        self.str(&procedure.name)?;
+        self.u32(procedure.level)?;
+        self.u16(procedure.params)?;
+        self.len(procedure.locals.len())?;
+        for (name, typ) in &procedure.locals {
+            self.str(name)?;
+            self.typ(*typ)?;
+        }
+        self.len(procedure.code.len())?;
+        for op in &procedure.code {
+            self.op(op)?;
+        }
+        // line table, one entry per instruction
+        for span in &procedure.spans {
+            self.len(span.line)?;
+            self.len(span.column)?;
+            self.len(span.len)?;
+        }
+        Ok(())
+    }
+}
+
+/// Decoder of the sections written by `Writer`
+struct Reader<'a, R: Read> {
+    input: &'a mut R,
+}
+
+impl<'a, R: Read> Reader<'a, R> {
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N], FormatError> {
+        let mut buf = [0; N];
+        self.input.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+    fn u8(&mut self) -> Result<u8, FormatError> {
+        Ok(self.bytes::<1>()?[0])
+    }
+    fn u16(&mut self) -> Result<u16, FormatError> {
+        Ok(u16::from_le_bytes(self.bytes()?))
+    }
+    fn u32(&mut self) -> Result<u32, FormatError> {
+        Ok(u32::from_le_bytes(self.bytes()?))
+    }
+    fn str(&mut self) -> Result<String, FormatError> {
+        let len = self.u32()? as usize;
+        let mut buf = Vec::new();
+        self.input.by_ref().take(len as u64).read_to_end(&mut buf)?;
+        if buf.len() != len {
+            return Err(FormatError::Malformed("truncated string".into()));
+        }
+        String::from_utf8(buf).map_err(|_| FormatError::Malformed("invalid name".into()))
+    }
+    fn typ(&mut self) -> Result<Type, FormatError> {
+        match self.u8()? {
+            0 => Ok(Type::Integer),
+            1 => Ok(Type::Real),
+            tag => Err(FormatError::Malformed(format!("unknown type {}", tag))),
+        }
+    }
+
+    fn value(&mut self) -> Result<VariableValue, FormatError> {
+        match self.u8()? {
+            0 => Ok(VariableValue::Integer(i32::from_le_bytes(self.bytes()?))),
+            1 => Ok(VariableValue::Real(f64::from_le_bytes(self.bytes()?))),
+            2 => Ok(VariableValue::Boolean(self.u8()? != 0)),
+            tag => Err(FormatError::Malformed(format!("unknown constant {}", tag))),
+        }
+    }
+
+    fn op(&mut self) -> Result<Op, FormatError> {
+        Ok(match self.u8()? {
+            0 => Op::Const(self.u16()?),
+            1 => Op::Load { depth: self.u8()?, slot: self.u16()? },
+            2 => Op::Store { depth: self.u8()?, slot: self.u16()? },
+            3 => Op::Neg,
+            4 => match BIN_OPS.get(self.u8()? as usize) {
+                Some(op) => Op::Binary(*op),
+                None => return Err(FormatError::Malformed("unknown operator".into())),
+            },
+            5 => Op::Jump(self.u32()?),
+            6 => Op::JumpIfFalse(self.u32()?),
+            7 => Op::Call { proc: self.u16()?, depth: self.u8()? },
+            8 => Op::Return,
+            9 => Op::Writeln(self.u16()?),
+            code => return Err(FormatError::Malformed(format!("unknown opcode {}", code))),
+        })
+    }
+
+    fn procedure(&mut self) -> Result<Procedure, FormatError> {
+        let mut procedure = Procedure::new(self.str()?, self.u32()?);
+        procedure.params = self.u16()?;
+        for _ in 0..self.u32()? {
+            procedure.locals.push((self.str()?, self.typ()?));
+        }
+        let len = self.u32()?;
+        for _ in 0..len {
+            procedure.code.push(self.op()?);
+        }
+        for _ in 0..len {
+            procedure.spans.push(Span {
+                line: self.u32()? as usize,
+                column: self.u32()? as usize,
+                len: self.u32()? as usize,
+            });
+        }
+        Ok(procedure)
+    }
+}
+
+impl Module {
+    /// Serialize: header, constant pool, procedure table with
+    /// the code and line table of each procedure
+    pub fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let mut w = Writer { out };
+        w.out.write_all(MAGIC)?;
+        w.u16(VERSION)?;
+        w.len(self.constants.len())?;
+        for value in &self.constants {
+            w.value(value)?;
+        }
+        w.len(self.procedures.len())?;
+        for procedure in &self.procedures {
+            w.procedure(procedure)?;
+        }
+        Ok(())
+    }
+
+    /// Load module written by `Module::write`
+    pub fn read<R: Read>(input: &mut R) -> Result<Module, FormatError> {
+        let mut r = Reader { input };
+        if &r.bytes::<4>()? != MAGIC {
+            return Err(FormatError::BadMagic);
+        }
+        let version = r.u16()?;
+        if version != VERSION {
+            return Err(FormatError::UnsupportedVersion(version));
+        }
+        let mut module = Module::default();
+        for _ in 0..r.u32()? {
+            module.constants.push(r.value()?);
+        }
+        for _ in 0..r.u32()? {
+            module.procedures.push(r.procedure()?);
+        }
+        module.verify()?;
+        Ok(module)
+    }
+
+    /// Check that operands refer to existing constants, procedures,
+    /// slots and addresses, that calls follow the nesting of the
+    /// procedures and that every path keeps the operand stack balanced
+    fn verify(&self) -> Result<(), FormatError> {
+        let malformed = |msg: String| Err(FormatError::Malformed(msg));
+        match self.procedures.first() {
+            None => return malformed("no program".into()),
+            Some(main) if main.level != 1 || main.params != 0 =>
+                return malformed(format!("{}: not a program", main.name)),
+            _ => {},
+        }
+        for (index, procedure) in self.procedures.iter().enumerate() {
+            if procedure.params as usize > procedure.locals.len() {
+                return malformed(format!("{}: too many parameters", procedure.name));
+            }
+            if index > 0 && self.owner(index, 1).is_none() {
+                return malformed(format!("{}: no enclosing procedure", procedure.name));
+            }
+            if procedure.spans.len() != procedure.code.len() {
+                return malformed(format!("{}: line table does not match the code", procedure.name));
+            }
+            for (addr, op) in procedure.code.iter().enumerate() {
+                let valid = match *op {
+                    Op::Const(index) => (index as usize) < self.constants.len(),
+                    Op::Load{depth, slot} | Op::Store{depth, slot} => self.owner(index, depth)
+                        .is_some_and(|owner| (slot as usize) < self.procedures[owner].locals.len()),
+                    Op::Jump(target) | Op::JumpIfFalse(target) =>
+                        (target as usize) < procedure.code.len(),
+                    // the static link of the callee is the frame `depth`
+                    // links up, which must be the one of its parent
+                    Op::Call{proc, depth} => proc != 0 && (proc as usize) < self.procedures.len()
+                        && self.owner(index, depth).is_some()
+                        && self.owner(index, depth) == self.owner(proc as usize, 1),
+                    _ => true,
+                };
+                if !valid {
+                    return malformed(format!("{}: invalid operand at {:04}", procedure.name, addr));
+                }
+            }
+            if procedure.code.last() != Some(&Op::Return) {
+                return malformed(format!("{}: missing RETURN", procedure.name));
+            }
+            if let Err(addr) = self.verify_stack(procedure) {
+                return malformed(format!("{}: unbalanced stack at {:04}", procedure.name, addr));
+            }
+        }
+        Ok(())
+    }
+
+    /// Follow every path through `procedure` with the kinds of the
+    /// values on the stack, which must be the same whichever way an
+    /// instruction is reached. Gives the address of the first
+    /// instruction with missing or wrong operands.
+    fn verify_stack(&self, procedure: &Procedure) -> Result<(), usize> {
+        let mut states: Vec<Option<Vec<Kind>>> = vec![None; procedure.code.len()];
+        states[0] = Some(Vec::new());
+        let mut pending = vec![0];
+        while let Some(addr) = pending.pop() {
+            let mut stack = states[addr].clone().unwrap();
+            let pop = |stack: &mut Vec<Kind>, kind| stack.pop() == Some(kind);
+            let (valid, next) = match procedure.code[addr] {
+                Op::Const(index) => {
+                    stack.push(match self.constants[index as usize] {
+                        VariableValue::Boolean(_) => Kind::Boolean,
+                        _ => Kind::Number,
+                    });
+                    (true, vec![addr + 1])
+                },
+                Op::Load{..} => {
+                    stack.push(Kind::Number);
+                    (true, vec![addr + 1])
+                },
+                Op::Store{..} => (pop(&mut stack, Kind::Number), vec![addr + 1]),
+                Op::Neg => {
+                    let valid = pop(&mut stack, Kind::Number);
+                    stack.push(Kind::Number);
+                    (valid, vec![addr + 1])
+                },
+                Op::Binary(op) => {
+                    let valid = pop(&mut stack, Kind::Number) && pop(&mut stack, Kind::Number);
+                    stack.push(if op.is_relational() { Kind::Boolean } else { Kind::Number });
+                    (valid, vec![addr + 1])
+                },
+                Op::Jump(target) => (true, vec![target as usize]),
+                Op::JumpIfFalse(target) =>
+                    (pop(&mut stack, Kind::Boolean), vec![addr + 1, target as usize]),
+                Op::Call{proc, ..} => {
+                    let params = self.procedures[proc as usize].params;
+                    ((0..params).all(|_| pop(&mut stack, Kind::Number)), vec![addr + 1])
+                },
+                Op::Writeln(count) => match stack.len().checked_sub(count as usize) {
+                    Some(len) => {
+                        stack.truncate(len);
+                        (true, vec![addr + 1])
+                    },
+                    None => (false, Vec::new()),
+                },
+                Op::Return => (stack.is_empty(), Vec::new()),
+            };
+            if !valid {
+                return Err(addr);
+            }
+            for next in next {
+                match &states[next] {
+                    None => {
+                        states[next] = Some(stack.clone());
+                        pending.push(next);
+                    },
+                    Some(state) if *state == stack => {},
+                    Some(_) => return Err(next),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What `Module::verify` knows about a value on the stack, variables
+/// hold numbers only
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Kind {
+    Number,
+    Boolean,
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Op::Const(index) => write!(f, "CONST {}", index),
+            Op::Load{depth, slot} => write!(f, "LOAD {} {}", depth, slot),
+            Op::Store{depth, slot} => write!(f, "STORE {} {}", depth, slot),
+            Op::Neg => write!(f, "NEG"),
+            Op::Binary(op) => write!(f, "{}", match op {
+                BinOp::Add => "ADD",
+                BinOp::Sub => "SUB",
+                BinOp::Mul => "MUL",
+                BinOp::Div => "DIV",
+                BinOp::IntDiv => "INT_DIV",
+                BinOp::Eq => "EQ",
+                BinOp::Ne => "NE",
+                BinOp::Lt => "LT",
+                BinOp::Le => "LE",
+                BinOp::Gt => "GT",
+                BinOp::Ge => "GE",
+            }),
+            Op::Jump(target) => write!(f, "JUMP {:04}", target),
+            Op::JumpIfFalse(target) => write!(f, "JUMP_IF_FALSE {:04}", target),
+            Op::Call{proc, depth} => write!(f, "CALL {} {}", proc, depth),
+            Op::Writeln(count) => write!(f, "WRITELN {}", count),
+            Op::Return => write!(f, "RETURN"),
+        }
+    }
+}
+
+/// Disassembler listing
+impl fmt::Display for Module {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "constants:")?;
+        for (index, value) in self.constants.iter().enumerate() {
+            writeln!(f, "    {:>4}  {:?}", index, value)?;
+        }
+        for (index, procedure) in self.procedures.iter().enumerate() {
+            writeln!(f, "procedure {} {} (level {}, params {}):",
+                index, procedure.name, procedure.level, procedure.params)?;
+            for (slot, (name, typ)) in procedure.locals.iter().enumerate() {
+                writeln!(f, "    slot {:>4}  {} : {:?}", slot, name, typ)?;
+            }
+            for (addr, (op, span)) in procedure.code.iter().zip(&procedure.spans).enumerate() {
+                let comment = match *op {
+                    Op::Const(index) => format!("{:?}", self.constants[index as usize]),
+                    Op::Load{depth, slot} | Op::Store{depth, slot} => self.local_name(index, depth, slot),
+                    Op::Call{proc, ..} => self.procedures[proc as usize].name.to_string(),
+                    _ => String::new(),
+                };
+                let line = format!("{:04}  {:<7} {}", addr, span.to_string(), op);
+                if comment.is_empty() {
+                    writeln!(f, "    {}", line)?;
+                }
+                else {
+                    writeln!(f, "    {:<40} ; {}", line, comment)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Module {
+    /// Index of the procedure `depth` scopes up from the procedure
+    /// `proc`. Procedures are numbered in declaration order, so the
+    /// parent is the closest preceding procedure one level up.
+    fn owner(&self, proc: usize, depth: u8) -> Option<usize> {
+        let mut owner = proc;
+        for _ in 0..depth {
+            let level = self.procedures[owner].level.checked_sub(1)?;
+            owner = (0..owner).rev().find(|p| self.procedures[*p].level == level)?;
+        }
+        Some(owner)
+    }
+
+    /// Name of the variable `depth` scopes up from the procedure `proc`
+    fn local_name(&self, proc: usize, depth: u8, slot: u16) -> String {
+        match self.owner(proc, depth).and_then(|p| self.procedures[p].locals.get(slot as usize)) {
+            Some((name, _)) => name.to_string(),
+            None => "?".into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::Interpreter;
+    use crate::vm::Vm;
+
+    const TEXT: &str = r#"
+    program Loops;
+    var i, sum : integer;
+        x : real;
+    procedure Add(n : integer);
+    begin
+        sum := sum + n
+    end;
+    begin
+        i := 0;
+        sum := 0;
+        while i < 5 do
+        begin
+            Add(i);
+            i := i + 1
+        end;
+        x := -sum / 2
+    end."#;
+
+    #[test]
+    fn round_trip() {
+        let module = Interpreter::new(TEXT).report(io::sink()).compile().unwrap();
+        let mut file = Vec::new();
+        module.write(&mut file).unwrap();
+        assert_eq!(&file[..4], MAGIC);
+        let loaded = Module::read(&mut file.as_slice()).unwrap();
+        assert_eq!(loaded, module);
+        let ctx = Vm::new(&loaded).run().unwrap();
+        assert_eq!(ctx.get_var("sum"), Some(VariableValue::Integer(10)));
+        assert_eq!(ctx.get_var("x"), Some(VariableValue::Real(-5.0)));
+    }
+
+    #[test]
+    fn invalid() {
+        let module = Interpreter::new(TEXT).report(io::sink()).compile().unwrap();
+        let mut file = Vec::new();
+        module.write(&mut file).unwrap();
+        let mut bad = file.clone();
+        bad[0] = b'X';
+        assert!(matches!(Module::read(&mut bad.as_slice()), Err(FormatError::BadMagic)));
+        let mut bad = file.clone();
+        bad[4] = 2;
+        assert!(matches!(Module::read(&mut bad.as_slice()), Err(FormatError::UnsupportedVersion(2))));
+        let bad = &file[..file.len() - 1];
+        assert!(matches!(Module::read(&mut &bad[..]), Err(FormatError::Io(_))));
+    }
+
+    #[test]
+    fn disassemble() {
+        let listing = Interpreter::new(TEXT).report(io::sink()).compile().unwrap().to_string();
+        assert!(listing.contains("procedure 1 ADD (level 2, params 1):"));
+        assert!(listing.contains("    slot    0  N : Integer"));
+        assert!(listing.contains("STORE 1 1"));
+    }
+
+    fn program(code: Vec<Op>) -> Module {
+        let mut main = Procedure::new("P", 1);
+        main.locals.push(("X".into(), Type::Integer));
+        main.spans = vec![Span::default(); code.len()];
+        main.code = code;
+        Module {
+            constants: vec![VariableValue::Integer(1), VariableValue::Boolean(true)],
+            procedures: vec![main],
+        }
+    }
+
+    fn verify(module: &Module) -> String {
+        match module.verify() {
+            Ok(()) => "ok".into(),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn unbalanced() {
+        let store = Op::Store { depth: 0, slot: 0 };
+        assert_eq!(verify(&program(vec![Op::Const(0), store, Op::Return])), "ok");
+        assert_eq!(verify(&program(vec![store, Op::Return])),
+            "malformed bytecode: P: unbalanced stack at 0000");
+        assert_eq!(verify(&program(vec![Op::Const(0), Op::Return])),
+            "malformed bytecode: P: unbalanced stack at 0001");
+        assert_eq!(verify(&program(vec![Op::Const(1), store, Op::Return])),
+            "malformed bytecode: P: unbalanced stack at 0001");
+        assert_eq!(verify(&program(vec![Op::Const(0), Op::Writeln(2), Op::Return])),
+            "malformed bytecode: P: unbalanced stack at 0001");
+        // the branches meet with different stacks
+        assert_eq!(verify(&program(vec![Op::Const(1), Op::JumpIfFalse(3), Op::Const(0), Op::Return])),
+            "malformed bytecode: P: unbalanced stack at 0003");
+    }
+
+    #[test]
+    fn wrong_frame() {
+        let mut module = program(vec![Op::Call { proc: 1, depth: 0 }, Op::Return]);
+        let mut a = Procedure::new("A", 2);
+        a.code = vec![Op::Call { proc: 3, depth: 0 }, Op::Return];
+        a.spans = vec![Span::default(); 2];
+        let mut b = Procedure::new("B", 2);
+        b.locals.push(("Y".into(), Type::Integer));
+        b.code = vec![Op::Return];
+        b.spans = vec![Span::default()];
+        // nested in B, called by A with the frame of A as the static link
+        let mut c = Procedure::new("C", 3);
+        c.code = vec![Op::Load { depth: 1, slot: 0 }, Op::Writeln(1), Op::Return];
+        c.spans = vec![Span::default(); 3];
+        module.procedures.extend(vec![a, b, c]);
+        assert_eq!(verify(&module), "malformed bytecode: A: invalid operand at 0000");
+        module.procedures[1].code[0] = Op::Return;
+        assert_eq!(verify(&module), "ok");
+    }
+}
@@ -0,0 +1,490 @@
+use crate::tokens::*;
+use crate::ast::*;
+use crate::symbols::*;
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+/// Runtime support of the generated program. Division by zero is
+/// reported like `RuntimeError::DivisionByZero` and exits with 1.
+const PRELUDE: &str = r#"#include <inttypes.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+static inline void division_by_zero(int line, int column)
+{
+    fflush(stdout);
+    fprintf(stderr, "%d:%d: runtime error: division by zero\n", line, column);
+    exit(1);
+}
+
+static inline int32_t int_div(int32_t a, int32_t b, int line, int column)
+{
+    if (b == 0)
+        division_by_zero(line, column);
+    return a / b;
+}
+
+static inline double real_div(double a, double b, int line, int column)
+{
+    if (b == 0.0)
+        division_by_zero(line, column);
+    return a / b;
+}
+"#;
+
+/// Procedure with a body as seen by the generated code
+struct CProc {
+    /// Unique C name, nested procedures may share Pascal names
+    name: String,
+    /// Nesting level of the body
+    level: u32,
+    /// Index of the enclosing procedure, `None` for the program
+    parent: Option<usize>,
+    params: Vec<(String, Type)>,
+    locals: Vec<(String, Type)>,
+}
+
+/// Translate the analyzed tree into C99. Globals become C globals,
+/// every procedure keeps its parameters and locals in an environment
+/// struct linked to the environment of the enclosing procedure, so
+/// nested procedures reach outer variables through `env.up->`.
+/// Pascal names get a prefix, `g_` for globals and `v_` for the rest,
+/// so they never clash with C keywords, macros or library functions.
+pub struct CGenerator<'a> {
+    resolutions: &'a HashMap<Span, Resolution>,
+    procs: Vec<CProc>,
+    /// Indices of `procs` keyed by location of the declaration
+    proc_index: HashMap<Span, usize>,
+    /// Procedure being generated, `None` for the program body
+    current: Option<usize>,
+    out: String,
+    indent: usize,
+}
+
+impl<'a> CGenerator<'a> {
+    pub fn new(resolutions: &'a HashMap<Span, Resolution>) -> Self {
+        CGenerator {
+            resolutions,
+            procs: Vec::new(),
+            proc_index: HashMap::new(),
+            current: None,
+            out: String::new(),
+            indent: 0,
+        }
+    }
+
+    pub fn generate(mut self, program: &Program) -> String {
+        let procedures = procedures(program);
+        self.collect(&procedures);
+        self.out.push_str(PRELUDE);
+
+        self.out.push('\n');
+        for decl in &program.block.decls {
+            if let Decl::Var(var) = decl {
+                let _ = writeln!(self.out, "static {} g_{} = 0;", c_type(var.typ), var.name.name);
+            }
+        }
+
+        if !self.procs.is_empty() {
+            self.out.push('\n');
+            for proc in &self.procs {
+                let _ = writeln!(self.out, "struct {}_env;", proc.name);
+            }
+            for index in 0..self.procs.len() {
+                self.env_struct(index);
+            }
+            self.out.push('\n');
+            for index in 0..self.procs.len() {
+                let _ = writeln!(self.out, "{};", self.signature(index));
+            }
+        }
+
+        for (index, body) in procedures.iter().enumerate() {
+            self.body(index, body.block);
+        }
+        self.out.push_str("\nint main(void)\n{\n");
+        self.indent = 1;
+        for stmt in &program.block.body {
+            self.statement(stmt);
+        }
+        self.line("return 0;");
+        self.out.push_str("}\n");
+        self.out
+    }
+
+    /// Name the procedures and list their variables
+    fn collect(&mut self, procedures: &[ProcedureBody]) {
+        for (index, body) in procedures.iter().enumerate() {
+            let name = &body.decl.name;
+            self.procs.push(CProc {
+                name: format!("p{}_{}", index, name.name),
+                level: body.level,
+                parent: body.parent,
+                params: body.decl.params.iter()
+                    .map(|param| (param.name.name.to_string(), param.typ))
+                    .collect(),
+                locals: body.block.decls.iter()
+                    .filter_map(|decl| match decl {
+                        Decl::Var(var) => Some((var.name.name.to_string(), var.typ)),
+                        Decl::Procedure(_) => None,
+                    })
+                    .collect(),
+            });
+            self.proc_index.insert(name.span, index);
+        }
+    }
+
+    fn env_struct(&mut self, index: usize) {
+        let proc = &self.procs[index];
+        let mut text = format!("\nstruct {}_env {{\n", proc.name);
+        match proc.parent {
+            Some(parent) => {
+                let _ = writeln!(text, "    struct {}_env *up;", self.procs[parent].name);
+            },
+            None => text.push_str("    void *up;\n"),
+        }
+        for (name, typ) in proc.params.iter().chain(&proc.locals) {
+            let _ = writeln!(text, "    {} v_{};", c_type(*typ), name);
+        }
+        text.push_str("};\n");
+        self.out.push_str(&text);
+    }
+
+    fn signature(&self, index: usize) -> String {
+        let proc = &self.procs[index];
+        let up = match proc.parent {
+            Some(parent) => format!("struct {}_env *up", self.procs[parent].name),
+            None => "void *up".to_string(),
+        };
+        let params: Vec<_> = std::iter::once(up)
+            .chain(proc.params.iter().map(|(name, typ)| format!("{} v_{}", c_type(*typ), name)))
+            .collect();
+        format!("static void {}({})", proc.name, params.join(", "))
+    }
+
+    /// Function of the procedure `index`
+    fn body(&mut self, index: usize, block: &Block) {
+        self.current = Some(index);
+        let _ = write!(self.out, "\n{}\n{{\n", self.signature(index));
+        self.indent = 1;
+        let proc = &self.procs[index];
+        let mut init = vec![
+            format!("struct {}_env env;", proc.name),
+            "env.up = up;".to_string(),
+        ];
+        for (name, _) in &proc.params {
+            init.push(format!("env.v_{0} = v_{0};", name));
+        }
+        for (name, _) in &proc.locals {
+            init.push(format!("env.v_{} = 0;", name));
+        }
+        for line in init {
+            self.line(&line);
+        }
+        for stmt in &block.body {
+            self.statement(stmt);
+        }
+        self.out.push_str("}\n");
+        self.current = None;
+    }
+
+    fn level(&self) -> u32 {
+        match self.current {
+            Some(index) => self.procs[index].level,
+            None => 1,
+        }
+    }
+
+    fn line(&mut self, text: &str) {
+        for _ in 0..self.indent {
+            self.out.push_str("    ");
+        }
+        self.out.push_str(text);
+        self.out.push('\n');
+    }
+
+    /// Statement as a block, used for the branches of IF and WHILE
+    fn nested(&mut self, head: &str, stmt: &Stmt) {
+        self.line(&format!("{} {{", head));
+        self.indent += 1;
+        self.statement(stmt);
+        self.indent -= 1;
+    }
+
+    fn statement(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Compound{body, ..} => {
+                for stmt in body {
+                    self.statement(stmt);
+                }
+            },
+            Stmt::Assign{target, value} => {
+                let line = format!("{} = {};", self.variable(target), self.expr(value));
+                self.line(&line);
+            },
+            Stmt::Call{name, args} => {
+                let resolution = self.resolutions[&name.span];
+                let index = self.proc_index[&resolution.decl];
+                let mut call_args = vec![self.static_link(resolution.level)];
+                call_args.extend(args.iter().map(|arg| self.expr(arg)));
+                let line = format!("{}({});", self.procs[index].name, call_args.join(", "));
+                self.line(&line);
+            },
+            Stmt::If{cond, then_branch, else_branch, ..} => {
+                self.nested(&format!("if ({})", self.expr(cond)), then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.line("}");
+                    self.nested("else", else_branch);
+                }
+                self.line("}");
+            },
+            Stmt::While{cond, body, ..} => {
+                self.nested(&format!("while ({})", self.expr(cond)), body);
+                self.line("}");
+            },
+            Stmt::Writeln{args, ..} => {
+                let mut format = String::new();
+                let mut values = Vec::new();
+                for arg in args {
+                    format.push_str(match type_of(arg, self.resolutions).unwrap() {
+                        Type::Integer => "%\" PRId32 \"",
+                        Type::Real => "%.6f",
+                    });
+                    values.push(self.expr(arg));
+                }
+                let line = match values.is_empty() {
+                    true => "printf(\"\\n\");".to_string(),
+                    false => format!("printf(\"{}\\n\", {});", format, values.join(", ")),
+                };
+                self.line(&line);
+            },
+            Stmt::NoOp => {},
+        }
+    }
+
+    fn expr(&self, expr: &Expr) -> String {
+        match expr {
+            Expr::Num{value: VariableValue::Integer(n), ..} => n.to_string(),
+            Expr::Num{value: VariableValue::Real(n), ..} => format!("{:?}", n),
+            Expr::Num{value: VariableValue::Boolean(b), ..} => (*b as i32).to_string(),
+            Expr::Num{value, ..} => unreachable!("constant {:?}", value),
+            Expr::Var(var) => self.variable(var),
+            Expr::BinOp{op, left, right, span} => {
+                let (l, r) = (self.expr(left), self.expr(right));
+                match op {
+                    BinOp::Div => format!("real_div({}, {}, {}, {})", l, r, span.line, span.column),
+                    BinOp::IntDiv => format!("int_div((int32_t)({}), (int32_t)({}), {}, {})",
+                        l, r, span.line, span.column),
+                    _ => format!("({} {} {})", l, c_operator(*op), r),
+                }
+            },
+            Expr::UnaryOp{op: UnaryOp::Plus, operand, ..} => self.expr(operand),
+            Expr::UnaryOp{op: UnaryOp::Minus, operand, ..} => format!("(-{})", self.expr(operand)),
+            Expr::Call{name, ..} => unreachable!("native function {}", name.name),
+        }
+    }
+
+    /// Access to the variable through the chain of environments
+    fn variable(&self, var: &Ident) -> String {
+        let level = self.resolutions[&var.span].level;
+        if level == 1 {
+            return format!("g_{}", var.name);
+        }
+        format!("env.{}v_{}", "up->".repeat((self.level() - level) as usize), var.name)
+    }
+
+    /// Environment of the scope at `level` passed to a procedure declared there
+    fn static_link(&self, level: u32) -> String {
+        let current = self.level();
+        if level == 1 {
+            "NULL".to_string()
+        }
+        else if level == current {
+            "&env".to_string()
+        }
+        else {
+            format!("env.up{}", "->up".repeat((current - level - 1) as usize))
+        }
+    }
+}
+
+fn c_type(typ: Type) -> &'static str {
+    match typ {
+        Type::Integer => "int32_t",
+        Type::Real => "double",
+    }
+}
+
+fn c_operator(op: BinOp) -> &'static str {
+    match op {
+        BinOp::Add => "+",
+        BinOp::Sub => "-",
+        BinOp::Mul => "*",
+        BinOp::Eq => "==",
+        BinOp::Ne => "!=",
+        BinOp::Lt => "<",
+        BinOp::Le => "<=",
+        BinOp::Gt => ">",
+        BinOp::Ge => ">=",
+        BinOp::Div | BinOp::IntDiv => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interpreter::Interpreter;
+    use crate::Capture;
+
+    use std::io;
+    use std::env;
+    use std::fs;
+    use std::process::Command;
+
+    /// Compile the C output with the system compiler and run it
+    fn run_c(name: &str, text: &str) -> (bool, String) {
+        let source = Interpreter::new(text).report(io::sink()).transpile().unwrap();
+        let dir = env::temp_dir().join(format!("lsbasi-cgen-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let c_file = dir.join("main.c");
+        let exe = dir.join("main");
+        fs::write(&c_file, &source).unwrap();
+        let cc = Command::new(env::var("CC").unwrap_or_else(|_| "cc".into()))
+            .args(["-std=c99", "-pedantic", "-Wall", "-Werror", "-o"])
+            .arg(&exe)
+            .arg(&c_file)
+            .output()
+            .expect("C compiler");
+        assert!(cc.status.success(), "{}\n{}", source, String::from_utf8_lossy(&cc.stderr));
+        let res = Command::new(&exe).output().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        (res.status.success(), String::from_utf8(res.stdout).unwrap())
+    }
+
+    /// Output of the C program must match the interpreter and the VM
+    fn compare(name: &str, text: &str) -> String {
+        let output = Capture::default();
+        Interpreter::new(text).report(io::sink()).output(output.clone()).exec();
+        let expected = output.text();
+        let output = Capture::default();
+        Interpreter::new(text).report(io::sink()).bytecode(true).output(output.clone()).exec();
+        assert_eq!(output.text(), expected);
+        let (ok, actual) = run_c(name, text);
+        assert!(ok);
+        assert_eq!(actual, expected);
+        actual
+    }
+
+    #[test]
+    fn arithmetic() {
+        let output = compare("arithmetic", r#"
+        program Arithmetic;
+        var a, b : integer;
+            x : real;
+        begin
+            a := 7;
+            b := -a DIV 2;
+            x := a / 2 + 0.25;
+            writeln(a, b);
+            writeln(x);
+            writeln(a * 3 - 1, b - x);
+            writeln
+        end."#);
+        assert_eq!(output, "7-3\n3.750000\n20-6.750000\n\n");
+    }
+
+    #[test]
+    fn nested_procedures() {
+        compare("nested", r#"
+        program Nested;
+        var a, r : integer;
+        procedure P1(n : integer);
+            var a : integer;
+            procedure P2;
+            begin
+                a := a + n;
+                r := r + 1;
+                writeln(a, n, r)
+            end;
+            procedure P3;
+                var a : real;
+            begin
+                a := 0.5;
+                P2;
+                writeln(a)
+            end;
+        begin
+            a := 10;
+            P3;
+            if n > 0 then P1(n - 1);
+            r := r * 2 + a
+        end;
+        begin
+            r := 0;
+            a := 7;
+            P1(2);
+            writeln(a, r)
+        end."#);
+    }
+
+    #[test]
+    fn recursion() {
+        compare("recursion", r#"
+        program Recursion;
+        var i, even : integer;
+        procedure IsOdd(n : integer); forward;
+        procedure IsEven(n : integer);
+        begin
+            if n = 0 then even := 1 else IsOdd(n - 1)
+        end;
+        procedure IsOdd(n : integer);
+        begin
+            if n = 0 then even := 0 else IsEven(n - 1)
+        end;
+        begin
+            i := 0;
+            while i < 5 do
+            begin
+                IsEven(i);
+                writeln(i, even);
+                i := i + 1
+            end
+        end."#);
+    }
+
+    #[test]
+    fn c_names() {
+        compare("names", r#"
+        program Names;
+        var eof, null, stdout, printf : integer;
+        procedure main(errno : integer);
+            var exit : real;
+        begin
+            exit := errno / 2;
+            writeln(errno, exit)
+        end;
+        begin
+            eof := 1;
+            null := eof + 1;
+            stdout := null * 3;
+            printf := stdout - eof;
+            main(printf);
+            writeln(eof, null, stdout, printf)
+        end."#);
+    }
+
+    #[test]
+    fn division_by_zero() {
+        let (ok, output) = run_c("zero", r#"
+        program Zero;
+        var a : integer;
+        begin
+            a := 0;
+            writeln(1);
+            writeln(7 DIV a)
+        end."#);
+        assert!(!ok);
+        assert_eq!(output, "1\n");
+    }
+}
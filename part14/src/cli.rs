@@ -0,0 +1,639 @@
+use crate::lexer::*;
+use crate::parser::*;
+use crate::symbols::*;
+use crate::analysis::Analysis;
+use crate::Error;
+use crate::formatter::*;
+use crate::interpreter::*;
+use crate::repl::{Repl, Shared};
+use crate::lsp::*;
+use crate::dap::*;
+use crate::debugger::*;
+use crate::profiler::*;
+use crate::limits::*;
+use crate::bytecode::{Module, MAGIC};
+use crate::vm::Vm;
+
+use std::fs;
+use std::io::{self, IsTerminal, Read, Write};
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+pub const USAGE: &str = "\
+Usage: lsbasi [OPTIONS] [FILE]
+       lsbasi fmt [--lower | --upper] [FILE]
+       lsbasi repl
+       lsbasi lsp
+       lsbasi dap
+
+Run a Pascal program, FILE is read from stdin when missing or \"-\".
+Without FILE on a terminal an interactive session is started.
+The lsp command serves the Language Server Protocol over stdio,
+the dap command the Debug Adapter Protocol.
+
+Options:
+    --dump-tokens   print the tokens with their locations
+    --dump-ast      print the syntax tree
+    --dump-symbols  print the scopes found by the semantic analysis
+    --dump-ir       print the three-address code of the program
+    --dump-cfg      print the control-flow graphs in Graphviz dot
+    --optimize      fold constants before running, with --dump-ir
+                    or --dump-cfg also run the SSA optimizations
+    --trace         print each executed statement to stderr
+    --profile       print the time spent per procedure and statement
+                    to stderr when the program ends
+    --folded=OUT    write the time spent per call stack to OUT in the
+                    folded format of flamegraph.pl
+    --max-steps=N   stop after N statements and expressions
+    --max-depth=N   stop when more than N procedure calls are active
+    --max-memory=N  stop when the variables take more than N bytes
+    --timeout=SECS  stop when the program runs longer than SECS
+    --emit-bytecode=OUT
+                    compile to bytecode and write it to OUT instead of
+                    running, a bytecode FILE runs on the VM
+    --debug         stop before the first statement and read debugger
+                    commands from stdin, see :help
+    -h, --help      print this help";
+
+/// Parse or semantic errors
+pub const EXIT_COMPILE: i32 = 1;
+/// Bad arguments or unreadable input
+pub const EXIT_USAGE: i32 = 2;
+/// Error raised while running the program
+pub const EXIT_RUNTIME: i32 = 3;
+/// Bug of the interpreter, the code of a Rust panic
+pub const EXIT_INTERNAL: i32 = 101;
+
+/// Stack of the thread running `main`
+const STACK_SIZE: usize = 256 << 20;
+/// Stack the tree-walker may take, the rest is left for the output
+const STACK_LIMIT: usize = STACK_SIZE - (1 << 20);
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Mode {
+    Run,
+    DumpTokens,
+    DumpAst,
+    DumpSymbols,
+    DumpIr,
+    DumpCfg,
+    Format(KeywordCase),
+    Repl,
+    Lsp,
+    Dap,
+    Help,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    pub mode: Mode,
+    pub trace: bool,
+    pub debug: bool,
+    pub profile: bool,
+    pub optimize: bool,
+    /// Destination of the folded stacks
+    pub folded: Option<String>,
+    pub limits: Limits,
+    /// Destination of the compiled bytecode
+    pub emit_bytecode: Option<String>,
+    /// Source file, stdin when `None`
+    pub path: Option<String>,
+}
+
+impl Options {
+    pub fn parse(args: &[String]) -> Result<Options, String> {
+        let mut options = Options {
+            mode: Mode::Run,
+            trace: false,
+            debug: false,
+            profile: false,
+            optimize: false,
+            folded: None,
+            limits: Limits::default(),
+            emit_bytecode: None,
+            path: None,
+        };
+        let mut args = args.iter().map(String::as_str).peekable();
+        match args.peek() {
+            Some(&"fmt") => options.mode = Mode::Format(KeywordCase::Upper),
+            Some(&"repl") => options.mode = Mode::Repl,
+            Some(&"lsp") => options.mode = Mode::Lsp,
+            Some(&"dap") => options.mode = Mode::Dap,
+            _ => {},
+        }
+        if options.mode != Mode::Run {
+            args.next();
+        }
+        for arg in args {
+            let mode = match (options.mode, arg) {
+                (_, "-h") | (_, "--help") => Mode::Help,
+                (Mode::Format(_), "--lower") => Mode::Format(KeywordCase::Lower),
+                (Mode::Format(_), "--upper") => Mode::Format(KeywordCase::Upper),
+                (Mode::Run, "--dump-tokens") => Mode::DumpTokens,
+                (Mode::Run, "--dump-ast") => Mode::DumpAst,
+                (Mode::Run, "--dump-symbols") => Mode::DumpSymbols,
+                (Mode::Run, "--dump-ir") => Mode::DumpIr,
+                (Mode::Run, "--dump-cfg") => Mode::DumpCfg,
+                (Mode::Run, "--optimize") | (Mode::DumpIr, "--optimize")
+                    | (Mode::DumpCfg, "--optimize") => {
+                    options.optimize = true;
+                    options.mode
+                },
+                (Mode::Run, "--trace") => {
+                    options.trace = true;
+                    Mode::Run
+                },
+                (Mode::Run, "--debug") => {
+                    options.debug = true;
+                    Mode::Run
+                },
+                (Mode::Run, "--profile") => {
+                    options.profile = true;
+                    Mode::Run
+                },
+                (Mode::Run, arg) if arg.starts_with("--folded=") => {
+                    options.folded = Some(arg["--folded=".len()..].to_string());
+                    Mode::Run
+                },
+                (Mode::Run, arg) if arg.starts_with("--max-steps=") => {
+                    options.limits.steps = Some(value(arg)?);
+                    Mode::Run
+                },
+                (Mode::Run, arg) if arg.starts_with("--max-depth=") => {
+                    options.limits.depth = Some(value(arg)?);
+                    Mode::Run
+                },
+                (Mode::Run, arg) if arg.starts_with("--max-memory=") => {
+                    options.limits.memory = Some(value(arg)?);
+                    Mode::Run
+                },
+                (Mode::Run, arg) if arg.starts_with("--emit-bytecode=") => {
+                    options.emit_bytecode = Some(arg["--emit-bytecode=".len()..].to_string());
+                    Mode::Run
+                },
+                (Mode::Run, arg) if arg.starts_with("--timeout=") => {
+                    let secs: f64 = value(arg)?;
+                    let timeout = Duration::try_from_secs_f64(secs)
+                        .map_err(|_| format!("invalid value in \"{}\"", arg))?;
+                    options.limits.timeout = Some(timeout);
+                    Mode::Run
+                },
+                (Mode::Repl, arg) | (Mode::Lsp, arg) | (Mode::Dap, arg) => return Err(format!("unexpected argument \"{}\"", arg)),
+                (_, "-") => {
+                    options.path = None;
+                    options.mode
+                },
+                (_, arg) if arg.starts_with('-') =>
+                    return Err(format!("unexpected option \"{}\"", arg)),
+                (_, arg) if options.path.is_none() => {
+                    options.path = Some(arg.to_string());
+                    options.mode
+                },
+                (_, arg) => return Err(format!("unexpected argument \"{}\"", arg)),
+            };
+            options.mode = mode;
+        }
+        if options.debug && options.path.is_none() {
+            return Err("--debug reads commands from stdin, the program must be in FILE".into());
+        }
+        Ok(options)
+    }
+}
+
+/// Value of an `--option=VALUE` argument
+fn value<T: FromStr>(arg: &str) -> Result<T, String> {
+    let value = arg.split_once('=').map(|(_, value)| value).unwrap_or("");
+    value.parse().map_err(|_| format!("invalid value in \"{}\"", arg))
+}
+
+/// Entry point of the binary, runs on a thread with a stack large
+/// enough for deep recursion of the program
+pub fn main(args: &[String]) -> i32 {
+    let args = args.to_vec();
+    thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(move || start(&args))
+        .expect("spawn the main thread")
+        .join()
+        .unwrap_or_else(|_| {
+            // the panic hook printed the message
+            eprintln!("internal error: the interpreter crashed, please report a bug");
+            EXIT_INTERNAL
+        })
+}
+
+fn start(args: &[String]) -> i32 {
+    let mut options = match Options::parse(args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, USAGE);
+            return EXIT_USAGE;
+        },
+    };
+    if options.mode == Mode::Help {
+        println!("{}", USAGE);
+        return 0;
+    }
+    options.limits.stack = Some(STACK_LIMIT);
+    if options.mode == Mode::Lsp {
+        return Server::new().run(io::stdin().lock());
+    }
+    if options.mode == Mode::Dap {
+        return DapServer::new().run(io::BufReader::new(io::stdin()));
+    }
+    let terminal = options.path.is_none() && io::stdin().is_terminal();
+    if options.mode == Mode::Repl || (options.mode == Mode::Run && terminal) {
+        Repl::new().run(io::stdin().lock());
+        return 0;
+    }
+    let name = options.path.as_deref().unwrap_or("stdin");
+    let input = match &options.path {
+        Some(path) => fs::read(path),
+        None => {
+            let mut input = Vec::new();
+            io::stdin().read_to_end(&mut input).map(|_| input)
+        },
+    };
+    let input = match input {
+        Ok(input) => input,
+        Err(e) => {
+            eprintln!("error: {}: {}", name, e);
+            return EXIT_USAGE;
+        },
+    };
+    if input.starts_with(MAGIC) {
+        return run_bytecode(&options, &input, io::stdout(), io::stderr());
+    }
+    match String::from_utf8(input) {
+        Ok(text) => run(&options, text, io::stdout(), io::stderr()),
+        Err(_) => {
+            eprintln!("error: {}: stream did not contain valid UTF-8", name);
+            EXIT_USAGE
+        },
+    }
+}
+
+/// Process the program text as `options` say, program output and
+/// dumps go to `out`, errors, warnings and reports to `err`
+pub fn run(options: &Options, text: String, mut out: impl Write + 'static,
+    err: impl Write + 'static) -> i32
+{
+    let mut report = Shared::new(err);
+    match options.mode {
+        Mode::DumpTokens => {
+            dump_tokens(&text, &mut out);
+            0
+        },
+        Mode::DumpAst => match Parser::new(text).try_parse() {
+            Ok(program) => {
+                writeln!(out, "{:#?}", program).expect("write output");
+                0
+            },
+            Err(diag) => {
+                writeln!(report, "{}", diag).expect("write report");
+                EXIT_COMPILE
+            },
+        },
+        Mode::DumpSymbols => dump_symbols(text, &mut out, &mut report),
+        Mode::DumpIr | Mode::DumpCfg => {
+            let res = Interpreter::new(text)
+                .report(report.clone())
+                .optimize(options.optimize)
+                .lower();
+            match res {
+                Ok(module) if options.mode == Mode::DumpCfg => {
+                    write!(out, "{}", module.to_dot()).expect("write output");
+                    0
+                },
+                Ok(module) => {
+                    write!(out, "{}", module).expect("write output");
+                    0
+                },
+                Err(e) => failure(e, &mut report),
+            }
+        },
+        Mode::Format(case) => match Formatter::new(text).keyword_case(case).format() {
+            Ok(text) => {
+                write!(out, "{}", text).expect("write output");
+                0
+            },
+            Err(diag) => {
+                writeln!(report, "{}", diag).expect("write report");
+                EXIT_COMPILE
+            },
+        },
+        Mode::Run if options.emit_bytecode.is_some() => {
+            let path = options.emit_bytecode.as_ref().unwrap();
+            let res = Interpreter::new(text)
+                .report(report.clone())
+                .optimize(options.optimize)
+                .compile();
+            let module = match res {
+                Ok(module) => module,
+                Err(e) => return failure(e, &mut report),
+            };
+            let res = fs::File::create(path).and_then(|mut file| module.write(&mut file));
+            match res {
+                Ok(()) => 0,
+                Err(e) => {
+                    writeln!(report, "error: {}: {}", path, e).expect("write report");
+                    EXIT_USAGE
+                },
+            }
+        },
+        Mode::Run => {
+            let mut interpreter = Interpreter::new(text)
+                .output(out)
+                .report(report.clone())
+                .optimize(options.optimize)
+                .limits(options.limits);
+            if options.trace {
+                interpreter = interpreter.trace(report.clone());
+            }
+            if options.profile || options.folded.is_some() {
+                let mut profiler = Profiler::new();
+                if options.profile {
+                    profiler = profiler.report(report.clone());
+                }
+                if let Some(path) = &options.folded {
+                    match fs::File::create(path) {
+                        Ok(file) => profiler = profiler.folded(file),
+                        Err(e) => {
+                            writeln!(report, "error: {}: {}", path, e).expect("write report");
+                            return EXIT_USAGE;
+                        },
+                    }
+                }
+                interpreter = interpreter.profiler(profiler);
+            }
+            if options.debug {
+                let debugger = console(io::stdin().lock(), io::stdout()).stop_on_entry(true);
+                interpreter = interpreter.debugger(debugger);
+            }
+            match interpreter.run() {
+                Ok(_) => 0,
+                Err(e) => failure(e, &mut report),
+            }
+        },
+        Mode::Repl | Mode::Lsp | Mode::Dap | Mode::Help => unreachable!(),
+    }
+}
+
+/// Exit code of the failed `Interpreter`, the diagnostics of an
+/// invalid program are already in the report
+fn failure(err: Error, report: &mut impl Write) -> i32 {
+    match err {
+        Error::Invalid(_) => EXIT_COMPILE,
+        Error::Runtime(e) => {
+            writeln!(report, "{}", e).expect("write report");
+            EXIT_RUNTIME
+        },
+    }
+}
+
+/// Run a module written by `--emit-bytecode` on the VM, of the
+/// `options` only the limits apply
+pub fn run_bytecode(options: &Options, mut bytecode: &[u8], out: impl Write,
+    mut err: impl Write) -> i32
+{
+    if options.mode != Mode::Run || options.trace || options.debug || options.profile
+        || options.optimize || options.folded.is_some() || options.emit_bytecode.is_some()
+    {
+        writeln!(err, "error: a bytecode file can only be run").expect("write report");
+        return EXIT_USAGE;
+    }
+    let module = match Module::read(&mut bytecode) {
+        Ok(module) => module,
+        Err(e) => {
+            writeln!(err, "error: {}", e).expect("write report");
+            return EXIT_USAGE;
+        },
+    };
+    match Vm::new(&module).limits(options.limits).output(out).run() {
+        Ok(_) => 0,
+        Err(e) => {
+            writeln!(err, "{}", e).expect("write report");
+            EXIT_RUNTIME
+        },
+    }
+}
+
+fn dump_tokens(text: &str, out: &mut impl Write) {
+    let mut lexer = Lexer::new(text);
+    while let Some(tok) = lexer.get_next_token() {
+        writeln!(out, "{}\t{}", lexer.span, tok).expect("write output");
+    }
+}
+
+/// Print the scopes outermost first, symbols in declaration order
+fn dump_symbols(text: String, out: &mut impl Write, report: &mut impl Write) -> i32 {
+    let analysis = Analysis::new(&text, |_| {}, false);
+    for diag in &analysis.diagnostics {
+        writeln!(report, "{}", diag).expect("write report");
+    }
+    // a syntax error leaves no scopes
+    let mut scopes: Vec<_> = analysis.scopes.iter().collect();
+    scopes.sort_by_key(|scope| scope.scope_level);
+    for scope in scopes {
+        writeln!(out, "scope {} (level {})", scope.scope_name, scope.scope_level)
+            .expect("write output");
+        let mut symbols: Vec<_> = scope.procedures.iter()
+            .map(|(name, symbol)| {
+                let params: Vec<_> = symbol.params.iter()
+                    .map(|(name, typ)| format!("{} : {:?}", name, typ))
+                    .collect();
+                (symbol.span, format!("procedure {}({})", name, params.join("; ")))
+            })
+            .chain(scope.variables.iter().map(|(name, symbol)| {
+                let kind = match symbol.kind {
+                    VarKind::Variable => "var",
+                    VarKind::Parameter => "param",
+                };
+                (symbol.span, format!("{} {} : {:?}", kind, name, symbol.typ))
+            }))
+            .collect();
+        symbols.sort_by_key(|(span, _)| (span.line, span.column));
+        for (span, symbol) in symbols {
+            writeln!(out, "    {}: {}", span, symbol).expect("write output");
+        }
+    }
+    if analysis.has_errors() {
+        EXIT_COMPILE
+    }
+    else {
+        0
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Capture;
+
+    /// Output which fails like a closed pipe
+    struct Closed;
+
+    impl Write for Closed {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn run_text(options: &[&str], text: &str) -> (i32, String) {
+        let options = Options::parse(&args(options)).unwrap();
+        let out = Capture::default();
+        let code = run(&options, text.into(), out.clone(), io::sink());
+        (code, out.text())
+    }
+
+    #[test]
+    fn options() {
+        assert_eq!(Options::parse(&args(&["--trace", "a.pas"])), Ok(Options {
+            mode: Mode::Run,
+            trace: true,
+            debug: false,
+            profile: false,
+            optimize: false,
+            folded: None,
+            limits: Limits::default(),
+            emit_bytecode: None,
+            path: Some("a.pas".into()),
+        }));
+        assert_eq!(Options::parse(&args(&["fmt", "--lower", "-"])), Ok(Options {
+            mode: Mode::Format(KeywordCase::Lower),
+            trace: false,
+            debug: false,
+            profile: false,
+            optimize: false,
+            folded: None,
+            limits: Limits::default(),
+            emit_bytecode: None,
+            path: None,
+        }));
+        assert_eq!(Options::parse(&args(&["--profile", "--folded=out.folded", "a.pas"])), Ok(Options {
+            mode: Mode::Run,
+            trace: false,
+            debug: false,
+            profile: true,
+            optimize: false,
+            folded: Some("out.folded".into()),
+            limits: Limits::default(),
+            emit_bytecode: None,
+            path: Some("a.pas".into()),
+        }));
+        assert!(Options::parse(&args(&["--dump-ast", "--trace"])).is_err());
+        assert!(Options::parse(&args(&["a.pas", "b.pas"])).is_err());
+        assert!(Options::parse(&args(&["--lower"])).is_err());
+        assert!(Options::parse(&args(&["fmt", "--optimize"])).is_err());
+        assert_eq!(Options::parse(&args(&["repl"])).map(|options| options.mode), Ok(Mode::Repl));
+        assert!(Options::parse(&args(&["repl", "a.pas"])).is_err());
+        assert_eq!(Options::parse(&args(&["dap"])).map(|options| options.mode), Ok(Mode::Dap));
+        assert!(Options::parse(&args(&["--debug"])).is_err());
+        assert_eq!(Options::parse(&args(&["--max-steps=100", "--timeout=0.5"])).map(|options| options.limits),
+            Ok(Limits::new().steps(100).timeout(Duration::from_millis(500))));
+        assert!(Options::parse(&args(&["--max-depth=deep"])).is_err());
+        assert!(Options::parse(&args(&["--timeout=-1"])).is_err());
+    }
+
+    #[test]
+    fn exit_codes() {
+        let text = fs::read_to_string("testdata/part14.pas").unwrap();
+        assert_eq!(run_text(&[], &text), (0, "3\n3.500000\n".into()));
+        let (code, _) = run_text(&[], "begin x := 1 end.");
+        assert_eq!(code, EXIT_COMPILE);
+        let (code, _) = run_text(&[], "begin end");
+        assert_eq!(code, EXIT_COMPILE);
+        let (code, _) = run_text(&["fmt"], "program A; begin x := ; end.");
+        assert_eq!(code, EXIT_COMPILE);
+        let (code, out) = run_text(&[], "var x : integer; begin writeln(1); x := 1 div 0 end.");
+        assert_eq!((code, out.as_str()), (EXIT_RUNTIME, "1\n"));
+    }
+
+    #[test]
+    fn constant_faults() {
+        // reported the same with and without folding
+        let text = "var x : integer; begin writeln(1); x := 1 div 0 end.";
+        let expected = "1:43: warning W007: division by zero\n\
+            1:43: runtime error: division by zero\n";
+        for options in [&[][..], &["--optimize"]] {
+            let options = Options::parse(&args(options)).unwrap();
+            let err = Capture::default();
+            let code = run(&options, text.into(), io::sink(), err.clone());
+            assert_eq!((code, err.text()), (EXIT_RUNTIME, expected.into()));
+        }
+    }
+
+    #[test]
+    fn output_failure() {
+        let text = "program P;\nbegin\n    writeln(1)\nend.";
+        let expected = "3:5: runtime error: can not write the output: broken pipe\n";
+        let options = Options::parse(&[]).unwrap();
+        let err = Capture::default();
+        let code = run(&options, text.into(), Closed, err.clone());
+        assert_eq!((code, err.text()), (EXIT_RUNTIME, expected.into()));
+        let mut bytecode = Vec::new();
+        Interpreter::new(text).compile().unwrap().write(&mut bytecode).unwrap();
+        let err = Capture::default();
+        let code = run_bytecode(&options, &bytecode, Closed, err.clone());
+        assert_eq!((code, err.text()), (EXIT_RUNTIME, expected.into()));
+    }
+
+    #[test]
+    fn dumps() {
+        let text = "program P; var x : integer; procedure Q(n : real); begin x := 2 end; begin Q(x) end.";
+        let (code, out) = run_text(&["--dump-tokens"], "x := 2;");
+        assert_eq!(code, 0);
+        assert_eq!(out, "1:1\tID \"X\"\n1:3\tASSIGN\n1:6\t2\n1:7\tSEMI\n");
+        let (code, out) = run_text(&["--dump-symbols"], text);
+        assert_eq!(code, 0);
+        assert_eq!(out, "\
+scope global (level 1)
+    1:16: var X : Integer
+    1:39: procedure Q(N : Real)
+scope Q (level 2)
+    1:41: param N : Real
+");
+        let (code, out) = run_text(&["--dump-ast"], text);
+        assert_eq!(code, 0);
+        assert!(out.starts_with("Program {"));
+        let text = "program P; var x : integer; begin x := 2 * 3; writeln(x + 1) end.";
+        let (code, out) = run_text(&["--dump-ir"], text);
+        assert_eq!(code, 0);
+        assert!(out.contains("    X = 2 * 3\n"));
+        let (code, out) = run_text(&["--dump-ir", "--optimize"], text);
+        assert_eq!(code, 0);
+        assert!(out.contains("    writeln(7)\n"));
+        let (code, out) = run_text(&["--dump-cfg"], text);
+        assert_eq!(code, 0);
+        assert!(out.starts_with("digraph"));
+    }
+
+    #[test]
+    fn bytecode() {
+        let path = std::env::temp_dir().join(format!("lsbasi-{}.lbc", std::process::id()));
+        let emit = format!("--emit-bytecode={}", path.display());
+        let text = fs::read_to_string("testdata/part14.pas").unwrap();
+        assert_eq!(run_text(&[&emit], &text), (0, "".into()));
+        let bytecode = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(bytecode.starts_with(MAGIC));
+        let options = Options::parse(&[]).unwrap();
+        let out = Capture::default();
+        assert_eq!(run_bytecode(&options, &bytecode, out.clone(), io::sink()), 0);
+        assert_eq!(out.text(), "3\n3.500000\n");
+        let err = Capture::default();
+        let code = run_bytecode(&options, &bytecode[..bytecode.len() - 1], io::sink(), err.clone());
+        assert_eq!((code, err.text()), (EXIT_USAGE, "error: failed to fill whole buffer\n".into()));
+        let options = Options::parse(&args(&["--trace"])).unwrap();
+        assert_eq!(run_bytecode(&options, &bytecode, io::sink(), io::sink()), EXIT_USAGE);
+    }
+}
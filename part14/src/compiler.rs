@@ -0,0 +1,253 @@
+use crate::tokens::*;
+use crate::ast::*;
+use crate::symbols::*;
+use crate::bytecode::*;
+use crate::diagnostics::*;
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+/// Translate the analyzed tree into bytecode. Variables become slots
+/// of the procedure frame, references are resolved with the
+/// `SemanticAnalyzer` resolutions.
+pub struct Compiler<'a> {
+    resolutions: &'a HashMap<Span, Resolution>,
+    module: Module,
+    /// Procedure indices keyed by location of the declaration
+    procedures: HashMap<Span, usize>,
+    /// Variable slots keyed by location of the declaration
+    slots: HashMap<Span, u16>,
+    /// Procedure being compiled
+    current: usize,
+    /// First operand which does not fit its instruction
+    error: Option<Diagnostic>,
+}
+
+impl<'a> Compiler<'a> {
+    pub fn new(resolutions: &'a HashMap<Span, Resolution>) -> Self {
+        Compiler {
+            resolutions,
+            module: Module::default(),
+            procedures: HashMap::new(),
+            slots: HashMap::new(),
+            current: 0,
+            error: None,
+        }
+    }
+
+    /// Fails when the program has more constants, variables or
+    /// procedures, or deeper nesting than the operands can address
+    pub fn compile(mut self, program: &Program) -> Result<Module, Diagnostic> {
+        // number the procedures first, a call may precede the body
+        self.module.procedures.push(Procedure::new(program.name.name.as_str(), 1));
+        for body in procedures(program) {
+            self.procedures.insert(body.decl.name.span, self.module.procedures.len());
+            self.module.procedures.push(Procedure::new(body.decl.name.name.as_str(), body.level));
+        }
+        self.block(&program.block);
+        self.emit(Op::Return, Span::default());
+        match self.error {
+            Some(diag) => Err(diag),
+            None => Ok(self.module),
+        }
+    }
+
+    fn procedure(&mut self, decl: &ProcedureDecl) {
+        let block = match &decl.block {
+            Some(block) => block,
+            None => return,
+        };
+        let outer = self.current;
+        self.current = self.procedures[&decl.name.span];
+        for param in &decl.params {
+            self.local(&param.name, param.typ);
+        }
+        let params = self.operand(decl.params.len(), "parameters", decl.name.span);
+        self.module.procedures[self.current].params = params;
+        self.block(block);
+        self.emit(Op::Return, Span::default());
+        self.current = outer;
+    }
+
+    fn local(&mut self, name: &Ident, typ: Type) {
+        let slot = self.operand(self.module.procedures[self.current].locals.len(), "variables", name.span);
+        self.slots.insert(name.span, slot);
+        self.module.procedures[self.current].locals.push((name.name.to_string(), typ));
+    }
+
+    fn block(&mut self, block: &Block) {
+        for decl in &block.decls {
+            if let Decl::Var(var) = decl {
+                self.local(&var.name, var.typ);
+            }
+        }
+        for decl in &block.decls {
+            if let Decl::Procedure(decl) = decl {
+                self.procedure(decl);
+            }
+        }
+        for stmt in &block.body {
+            self.statement(stmt);
+        }
+    }
+
+    fn statement(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Compound{body, ..} => {
+                for stmt in body {
+                    self.statement(stmt);
+                }
+            },
+            Stmt::Assign{target, value} => {
+                self.expr(value);
+                let (depth, slot) = self.variable(target);
+                self.emit(Op::Store { depth, slot }, target.span);
+            },
+            Stmt::Call{name, args} => {
+                for arg in args {
+                    self.expr(arg);
+                }
+                let resolution = self.resolutions[&name.span];
+                let proc = self.operand(self.procedures[&resolution.decl], "procedures", name.span);
+                let depth = self.depth(resolution.level, name.span);
+                self.emit(Op::Call { proc, depth }, name.span);
+            },
+            Stmt::If{cond, then_branch, else_branch, span} => {
+                self.expr(cond);
+                let jump_else = self.emit(Op::JumpIfFalse(0), *span);
+                self.statement(then_branch);
+                if let Some(else_branch) = else_branch {
+                    let jump_end = self.emit(Op::Jump(0), *span);
+                    self.patch(jump_else);
+                    self.statement(else_branch);
+                    self.patch(jump_end);
+                }
+                else {
+                    self.patch(jump_else);
+                }
+            },
+            Stmt::While{cond, body, span} => {
+                let start = self.code().len() as u32;
+                self.expr(cond);
+                let jump_end = self.emit(Op::JumpIfFalse(0), *span);
+                self.statement(body);
+                self.emit(Op::Jump(start), *span);
+                self.patch(jump_end);
+            },
+            Stmt::Writeln{args, span} => {
+                for arg in args {
+                    self.expr(arg);
+                }
+                let count = self.operand(args.len(), "arguments", *span);
+                self.emit(Op::Writeln(count), *span);
+            },
+            Stmt::NoOp => {},
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Num{value, span} => {
+                let index = self.constant(*value, *span);
+                self.emit(Op::Const(index), *span);
+            },
+            Expr::Var(var) => {
+                let (depth, slot) = self.variable(var);
+                self.emit(Op::Load { depth, slot }, var.span);
+            },
+            Expr::BinOp{op, left, right, span} => {
+                self.expr(left);
+                self.expr(right);
+                self.emit(Op::Binary(*op), *span);
+            },
+            Expr::UnaryOp{op: UnaryOp::Plus, operand, ..} => self.expr(operand),
+            Expr::UnaryOp{op: UnaryOp::Minus, operand, span} => {
+                self.expr(operand);
+                self.emit(Op::Neg, *span);
+            },
+            Expr::Call{name, ..} => unreachable!("native function {}", name.name),
+        }
+    }
+
+    /// `value` narrowed to the operand type, zero and an error at
+    /// `span` when it does not fit
+    fn operand<T: TryFrom<usize> + Default>(&mut self, value: usize, what: &str, span: Span) -> T {
+        T::try_from(value).unwrap_or_else(|_| {
+            self.error.get_or_insert_with(|| Diagnostic::error(span,
+                format!("too many {} for the bytecode", what)));
+            T::default()
+        })
+    }
+
+    /// Static links between the current procedure and the scope at `level`
+    fn depth(&mut self, level: u32, span: Span) -> u8 {
+        let depth = self.module.procedures[self.current].level - level;
+        self.operand(depth as usize, "nested procedures", span)
+    }
+
+    fn variable(&mut self, var: &Ident) -> (u8, u16) {
+        let resolution = self.resolutions[&var.span];
+        (self.depth(resolution.level, var.span), self.slots[&resolution.decl])
+    }
+
+    fn constant(&mut self, value: VariableValue, span: Span) -> u16 {
+        let constants = &mut self.module.constants;
+        let index = match constants.iter().position(|c| *c == value) {
+            Some(index) => index,
+            None => {
+                constants.push(value);
+                constants.len() - 1
+            },
+        };
+        self.operand(index, "constants", span)
+    }
+
+    fn code(&mut self) -> &mut Vec<Op> {
+        &mut self.module.procedures[self.current].code
+    }
+
+    /// Append instruction, returns its address
+    fn emit(&mut self, op: Op, span: Span) -> usize {
+        let procedure = &mut self.module.procedures[self.current];
+        procedure.code.push(op);
+        procedure.spans.push(span);
+        procedure.code.len() - 1
+    }
+
+    /// Point the jump at `addr` to the next instruction
+    fn patch(&mut self, addr: usize) {
+        let target = self.code().len() as u32;
+        match &mut self.code()[addr] {
+            Op::Jump(to) | Op::JumpIfFalse(to) => *to = target,
+            _ => unreachable!()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile(program: &Program) -> Result<Module, Diagnostic> {
+        let mut analyzer = SemanticAnalyzer::default();
+        analyzer.visit_program(program);
+        Compiler::new(&analyzer.resolutions).compile(program)
+    }
+
+    #[test]
+    fn operand_range() {
+        // built directly, lexing that much text takes too long
+        let ident = |n: usize| Ident { name: format!("V{}", n), span: Span { line: 1, column: n + 1, len: 1 } };
+        let mut program = Program {
+            name: Ident { name: "P".into(), span: Span::default() },
+            block: Block { decls: Vec::new(), body: Vec::new(), end: Span::default() },
+        };
+        program.block.decls = (0..=u16::MAX as usize + 1)
+            .map(|n| Decl::Var(VarDecl { name: ident(n), typ: Type::Integer }))
+            .collect();
+        let err = compile(&program).unwrap_err();
+        assert_eq!(err, Diagnostic::error(ident(65536).span, "too many variables for the bytecode"));
+        program.block.decls.pop();
+        assert!(compile(&program).is_ok());
+    }
+}
@@ -0,0 +1,506 @@
+use crate::ast::*;
+use crate::interpreter::*;
+use crate::debugger::*;
+use crate::lsp::read_message;
+use crate::cli::{EXIT_COMPILE, EXIT_RUNTIME};
+use crate::Error;
+
+use serde_json::{json, Value};
+
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+/// Debug adapter speaking the Debug Adapter Protocol over a byte
+/// stream, as editors run it over stdio. One program is launched
+/// per session and runs on the thread of `run`, requests are read
+/// by a second thread so a running program can be paused.
+///
+/// Frames are numbered from the program frame up, a frame's
+/// variables are referenced by its number plus one.
+pub struct DapServer {
+    output: Box<dyn Write>,
+}
+
+/// State shared by the request loop, the debugger and the
+/// program output
+struct Session {
+    messages: Receiver<Result<Value, String>>,
+    output: Box<dyn Write>,
+    seq: i64,
+    /// Path and text of the launched program
+    program: Option<(String, String)>,
+    stop_on_entry: bool,
+    started: bool,
+    /// Set by `disconnect`
+    quit: bool,
+}
+
+/// What to do after a request
+enum Next {
+    Wait,
+    Resume(Command),
+    /// Pause the running program
+    Interrupt,
+    /// Start the program
+    Run,
+    Quit,
+}
+
+const THREAD_ID: i64 = 1;
+
+impl Default for DapServer {
+    fn default() -> Self {
+        DapServer::new()
+    }
+}
+
+impl DapServer {
+    pub fn new() -> DapServer {
+        DapServer {
+            output: Box::new(io::stdout()),
+        }
+    }
+
+    /// Send messages to `out` instead of stdout
+    #[cfg(test)]
+    pub fn output(mut self, out: impl Write + 'static) -> Self {
+        self.output = Box::new(out);
+        self
+    }
+
+    /// Serve requests from `input` until `disconnect` or the end
+    /// of input, returns the process exit code
+    pub fn run(self, input: impl BufRead + Send + 'static) -> i32 {
+        let (sender, messages) = mpsc::channel();
+        thread::spawn(move || {
+            let mut input = input;
+            while let Some(message) = read_message(&mut input) {
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+        let session = Rc::new(RefCell::new(Session {
+            messages,
+            output: self.output,
+            seq: 0,
+            program: None,
+            stop_on_entry: false,
+            started: false,
+            quit: false,
+        }));
+        // breakpoints set before the launch
+        let mut breakpoints = BTreeSet::new();
+        loop {
+            let message = match session.borrow_mut().receive() {
+                Some(message) => message,
+                None => break,
+            };
+            let next = session.borrow_mut().handle(&message, None, &mut breakpoints);
+            match next {
+                Next::Run => launch(&session, &breakpoints),
+                Next::Quit => break,
+                _ => {},
+            }
+            if session.borrow().quit {
+                break;
+            }
+        }
+        // a client disconnects before it exits
+        if session.borrow().quit { 0 } else { 1 }
+    }
+}
+
+/// Run the launched program under a debugger reporting to the client
+fn launch(session: &Rc<RefCell<Session>>, breakpoints: &BTreeSet<usize>) {
+    let (text, stop_on_entry) = {
+        let mut session = session.borrow_mut();
+        session.started = true;
+        match &session.program {
+            Some((_, text)) => (text.clone(), session.stop_on_entry),
+            None => return,
+        }
+    };
+    let handler_session = session.clone();
+    let poll_session = session.clone();
+    let mut debugger = Debugger::new(move |pause| {
+        let mut session = handler_session.borrow_mut();
+        if session.quit {
+            return Command::Stop;
+        }
+        let reason = match pause.reason {
+            Reason::Entry => "entry",
+            Reason::Breakpoint => "breakpoint",
+            Reason::Step => "step",
+            Reason::Pause => "pause",
+        };
+        session.event("stopped", json!({
+            "reason": reason,
+            "threadId": THREAD_ID,
+            "allThreadsStopped": true,
+        }));
+        loop {
+            let message = match session.receive() {
+                Some(message) => message,
+                None => return Command::Stop,
+            };
+            match session.handle(&message, Some(pause.frames), pause.breakpoints) {
+                Next::Resume(command) => return command,
+                Next::Quit => return Command::Stop,
+                _ => {},
+            }
+        }
+    })
+    .poll(move |breakpoints| {
+        let mut session = poll_session.borrow_mut();
+        while let Ok(message) = session.messages.try_recv() {
+            let message = match message {
+                Ok(message) => message,
+                Err(e) => {
+                    eprintln!("dap: {}", e);
+                    continue;
+                },
+            };
+            match session.handle(&message, None, breakpoints) {
+                Next::Interrupt | Next::Quit => return true,
+                _ => {},
+            }
+        }
+        false
+    })
+    .stop_on_entry(stop_on_entry);
+    for line in breakpoints {
+        debugger = debugger.breakpoint(*line);
+    }
+    let interpreter = Interpreter::new(text)
+        .output(Events::new(session, "stdout"))
+        .report(Events::new(session, "stderr"))
+        .debugger(debugger);
+    let res = interpreter.run();
+    let mut session = session.borrow_mut();
+    if session.quit {
+        return;
+    }
+    let exit_code = match res {
+        Ok(_) => 0,
+        // the diagnostics went to the report
+        Err(Error::Invalid(_)) => EXIT_COMPILE,
+        Err(Error::Runtime(e)) => {
+            session.event("output", json!({ "category": "stderr", "output": format!("{}\n", e) }));
+            EXIT_RUNTIME
+        },
+    };
+    session.event("exited", json!({ "exitCode": exit_code }));
+    session.event("terminated", json!({}));
+}
+
+impl Session {
+    /// Next request, `None` at the end of input
+    fn receive(&mut self) -> Option<Value> {
+        loop {
+            match self.messages.recv() {
+                Ok(Ok(message)) => return Some(message),
+                Ok(Err(e)) => eprintln!("dap: {}", e),
+                Err(_) => return None,
+            }
+        }
+    }
+
+    /// Answer a request, `frames` is the call stack while the
+    /// program is stopped
+    fn handle(&mut self, request: &Value, frames: Option<&[ActivationRecord]>,
+        breakpoints: &mut BTreeSet<usize>) -> Next
+    {
+        let command = request["command"].as_str().unwrap_or("");
+        let args = &request["arguments"];
+        let mut next = Next::Wait;
+        let body = match (command, frames) {
+            ("initialize", _) => {
+                self.respond(request, json!({
+                    "supportsConfigurationDoneRequest": true,
+                }));
+                self.event("initialized", json!({}));
+                return Next::Wait;
+            },
+            ("launch", _) => {
+                let path = args["program"].as_str().unwrap_or("");
+                let text = match fs::read_to_string(path) {
+                    Ok(text) => text,
+                    Err(e) => return self.fail(request, &format!("{}: {}", path, e)),
+                };
+                self.program = Some((path.to_string(), text));
+                self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+                Value::Null
+            },
+            ("setBreakpoints", _) => {
+                let lines: Vec<u64> = args["breakpoints"].as_array()
+                    .map(|points| points.iter().filter_map(|point| point["line"].as_u64()).collect())
+                    .unwrap_or_default();
+                breakpoints.clear();
+                breakpoints.extend(lines.iter().map(|line| *line as usize));
+                let points: Vec<_> = lines.iter()
+                    .map(|line| json!({ "verified": true, "line": line }))
+                    .collect();
+                json!({ "breakpoints": points })
+            },
+            ("setExceptionBreakpoints", _) => Value::Null,
+            ("configurationDone", _) => {
+                if self.program.is_none() {
+                    return self.fail(request, "no program launched");
+                }
+                if !self.started {
+                    next = Next::Run;
+                }
+                Value::Null
+            },
+            ("threads", _) => json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] }),
+            ("stackTrace", Some(frames)) => {
+                let path = self.program.as_ref().map(|(path, _)| path.as_str()).unwrap_or("");
+                let name = path.rsplit('/').next().unwrap_or(path);
+                let stack: Vec<_> = frames.iter().enumerate().rev()
+                    .map(|(id, frame)| json!({
+                        "id": id,
+                        "name": frame.name,
+                        "line": frame.span.line,
+                        "column": frame.span.column,
+                        "source": { "name": name, "path": path },
+                    }))
+                    .collect();
+                json!({ "stackFrames": stack, "totalFrames": frames.len() })
+            },
+            ("scopes", Some(frames)) => {
+                let id = args["frameId"].as_u64().unwrap_or(0) as usize;
+                if id >= frames.len() {
+                    return self.fail(request, &format!("no frame {}", id));
+                }
+                json!({ "scopes": [{
+                    "name": "Locals",
+                    "variablesReference": id + 1,
+                    "expensive": false,
+                }] })
+            },
+            ("variables", Some(frames)) => {
+                let reference = args["variablesReference"].as_u64().unwrap_or(0) as usize;
+                let frame = match reference.checked_sub(1).and_then(|id| frames.get(id)) {
+                    Some(frame) => frame,
+                    None => return self.fail(request, &format!("no variables {}", reference)),
+                };
+                let variables: Vec<_> = frame.variables().into_iter()
+                    .map(|(name, value)| json!({
+                        "name": name,
+                        "value": match value {
+                            VariableValue::Undefined(_) => "undefined".to_string(),
+                            value => value.to_string(),
+                        },
+                        "variablesReference": 0,
+                    }))
+                    .collect();
+                json!({ "variables": variables })
+            },
+            ("stackTrace", None) | ("scopes", None) | ("variables", None) =>
+                return self.fail(request, "program is not stopped"),
+            ("continue", _) => {
+                next = Next::Resume(Command::Continue);
+                json!({ "allThreadsContinued": true })
+            },
+            ("next", _) => {
+                next = Next::Resume(Command::StepOver);
+                Value::Null
+            },
+            ("stepIn", _) => {
+                next = Next::Resume(Command::StepIn);
+                Value::Null
+            },
+            ("stepOut", _) => {
+                next = Next::Resume(Command::StepOut);
+                Value::Null
+            },
+            ("pause", _) => {
+                next = Next::Interrupt;
+                Value::Null
+            },
+            ("disconnect", _) => {
+                self.quit = true;
+                next = Next::Quit;
+                Value::Null
+            },
+            _ => return self.fail(request, &format!("unknown command \"{}\"", command)),
+        };
+        self.respond(request, body);
+        next
+    }
+
+    fn respond(&mut self, request: &Value, body: Value) {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "success": true,
+            "command": request["command"],
+        });
+        if !body.is_null() {
+            response["body"] = body;
+        }
+        self.send(response);
+    }
+
+    fn fail(&mut self, request: &Value, message: &str) -> Next {
+        self.send(json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "success": false,
+            "command": request["command"],
+            "message": message,
+        }));
+        Next::Wait
+    }
+
+    fn event(&mut self, event: &str, body: Value) {
+        self.send(json!({ "type": "event", "event": event, "body": body }));
+    }
+
+    fn send(&mut self, mut message: Value) {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let body = message.to_string();
+        write!(self.output, "Content-Length: {}\r\n\r\n{}", body.len(), body)
+            .expect("write output");
+        self.output.flush().expect("write output");
+    }
+}
+
+/// Program output sent as `output` events a line at a time
+struct Events {
+    session: Rc<RefCell<Session>>,
+    category: &'static str,
+    line: Vec<u8>,
+}
+
+impl Events {
+    fn new(session: &Rc<RefCell<Session>>, category: &'static str) -> Events {
+        Events { session: session.clone(), category, line: Vec::new() }
+    }
+
+    fn send(&mut self, end: usize) {
+        let text: Vec<u8> = self.line.drain(..end).collect();
+        self.session.borrow_mut().event("output", json!({
+            "category": self.category,
+            "output": String::from_utf8_lossy(&text),
+        }));
+    }
+}
+
+impl Write for Events {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.line.extend_from_slice(buf);
+        while let Some(end) = self.line.iter().position(|b| *b == b'\n') {
+            self.send(end + 1);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.line.is_empty() {
+            self.send(self.line.len());
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::sync::mpsc::Sender;
+    use std::time::Duration;
+
+    /// One end of a byte stream between threads
+    struct Pipe {
+        sender: Sender<Vec<u8>>,
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let _ = self.sender.send(buf.to_vec());
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Other end of the stream, gives up when nothing comes
+    struct PipeReader {
+        receiver: Receiver<Vec<u8>>,
+        buffer: io::Cursor<Vec<u8>>,
+    }
+
+    impl Read for PipeReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.buffer.position() as usize == self.buffer.get_ref().len() {
+                match self.receiver.recv_timeout(Duration::from_secs(10)) {
+                    Ok(bytes) => self.buffer = io::Cursor::new(bytes),
+                    Err(mpsc::RecvTimeoutError::Timeout) =>
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, "no message")),
+                    Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(0),
+                }
+            }
+            self.buffer.read(buf)
+        }
+    }
+
+    fn pipe() -> (Pipe, io::BufReader<PipeReader>) {
+        let (sender, receiver) = mpsc::channel();
+        let reader = PipeReader { receiver, buffer: io::Cursor::new(Vec::new()) };
+        (Pipe { sender }, io::BufReader::new(reader))
+    }
+
+    /// Play a recorded session, `-> ` lines are sent to the server
+    /// and `<- ` lines are the messages expected back before the
+    /// next request is sent
+    fn replay(path: &str) {
+        let transcript = fs::read_to_string(path).unwrap();
+        let (mut client, input) = pipe();
+        let (output, mut server) = pipe();
+        let handle = thread::spawn(move || DapServer::new().output(output).run(input));
+        for (n, line) in transcript.lines().enumerate() {
+            let at = format!("{}:{}", path, n + 1);
+            if let Some(message) = line.strip_prefix("-> ") {
+                let message: Value = serde_json::from_str(message).expect(&at);
+                let body = message.to_string();
+                write!(client, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+            }
+            else if let Some(expected) = line.strip_prefix("<- ") {
+                let expected: Value = serde_json::from_str(expected).expect(&at);
+                let message = match read_message(&mut server) {
+                    Some(Ok(message)) => message,
+                    other => panic!("{}: expected {}, got {:?}", at, expected, other),
+                };
+                assert_eq!(message, expected, "{}", at);
+            }
+        }
+        drop(client);
+        assert_eq!(handle.join().unwrap(), 0);
+        assert!(read_message(&mut server).is_none(), "{}: unexpected message", path);
+    }
+
+    #[test]
+    fn breakpoints() {
+        replay("testdata/dap/breakpoints.dap");
+    }
+
+    #[test]
+    fn stop_on_entry() {
+        replay("testdata/dap/entry.dap");
+    }
+
+    #[test]
+    fn pause() {
+        replay("testdata/dap/pause.dap");
+    }
+}
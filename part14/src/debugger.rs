@@ -0,0 +1,331 @@
+use crate::tokens::*;
+use crate::ast::*;
+use crate::interpreter::*;
+
+use std::collections::BTreeSet;
+use std::io::{BufRead, Write};
+
+/// Why the program stopped
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum Reason {
+    /// First statement of the program
+    Entry,
+    Breakpoint,
+    Step,
+    /// Requested while running, see `Debugger::poll`
+    Pause,
+}
+
+/// How to go on after a pause
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum Command {
+    /// Run up to the next breakpoint
+    Continue,
+    /// Stop at the next statement, entering procedure calls
+    StepIn,
+    /// Stop at the next statement of this or a calling procedure
+    StepOver,
+    /// Stop at the next statement of a calling procedure
+    StepOut,
+    /// Abort the program with `RuntimeError::Stopped`
+    Stop,
+}
+
+/// State of the program stopped before a statement
+#[non_exhaustive]
+pub struct Pause<'a> {
+    pub reason: Reason,
+    /// Statement about to run
+    pub span: Span,
+    /// Call stack, the program frame first
+    pub frames: &'a [ActivationRecord],
+    /// Lines with breakpoints, the handler may change them
+    pub breakpoints: &'a mut BTreeSet<usize>,
+}
+
+/// Stops the interpreter at breakpoints and after steps and asks
+/// the handler what to do next. Only statements are stopped at,
+/// BEGIN ... END blocks are not.
+pub struct Debugger {
+    /// Lines with breakpoints
+    breakpoints: BTreeSet<usize>,
+    /// Current command and the call depth it was given at
+    command: Command,
+    depth: usize,
+    /// Frame depth and location of the last statement, a breakpoint
+    /// stops once when the statements of its line run left to right
+    last: (usize, Span),
+    handler: Box<dyn FnMut(&mut Pause) -> Command>,
+    poll: Option<Poll>,
+}
+
+type Poll = Box<dyn FnMut(&mut BTreeSet<usize>) -> bool>;
+
+impl Debugger {
+    pub fn new(handler: impl FnMut(&mut Pause) -> Command + 'static) -> Debugger {
+        Debugger {
+            breakpoints: BTreeSet::new(),
+            command: Command::Continue,
+            depth: 0,
+            last: (0, Span::default()),
+            handler: Box::new(handler),
+            poll: None,
+        }
+    }
+
+    /// Call `poll` before every statement, e.g. to look for requests
+    /// of a client, it may change the breakpoints and returns true
+    /// to stop the program
+    pub fn poll(mut self, poll: impl FnMut(&mut BTreeSet<usize>) -> bool + 'static) -> Self {
+        self.poll = Some(Box::new(poll));
+        self
+    }
+
+    /// Stop before the first statement
+    pub fn stop_on_entry(mut self, on: bool) -> Self {
+        self.command = if on { Command::StepIn } else { Command::Continue };
+        self
+    }
+
+    /// Stop at the statements of `line`
+    pub fn breakpoint(mut self, line: usize) -> Self {
+        self.breakpoints.insert(line);
+        self
+    }
+
+    /// Called by the interpreter before each statement
+    pub(crate) fn statement(&mut self, span: Span, frames: &[ActivationRecord]) -> Result<(), RuntimeError> {
+        let depth = frames.len();
+        let interrupted = match self.poll.as_mut() {
+            Some(poll) => poll(&mut self.breakpoints),
+            None => false,
+        };
+        let stepped = match self.command {
+            Command::Continue | Command::Stop => false,
+            Command::StepIn => true,
+            Command::StepOver => depth <= self.depth,
+            Command::StepOut => depth < self.depth,
+        };
+        let (last_depth, last) = self.last;
+        let same_line = last_depth == depth && last.line == span.line && last.column < span.column;
+        let breakpoint = self.breakpoints.contains(&span.line) && !same_line;
+        self.last = (depth, span);
+        let reason = match (stepped, breakpoint, interrupted) {
+            (false, false, false) => return Ok(()),
+            (true, _, _) if self.depth == 0 => Reason::Entry,
+            (_, true, _) => Reason::Breakpoint,
+            (_, _, true) => Reason::Pause,
+            _ => Reason::Step,
+        };
+        let mut pause = Pause { reason, span, frames, breakpoints: &mut self.breakpoints };
+        self.command = (self.handler)(&mut pause);
+        self.depth = depth;
+        match self.command {
+            Command::Stop => Err(RuntimeError::Stopped { span }),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl ActivationRecord {
+    /// Variables and parameters sorted by name
+    pub fn variables(&self) -> Vec<(&str, VariableValue)> {
+        let mut variables: Vec<_> = self.members.iter()
+            .map(|(name, value)| (name.as_str(), *value))
+            .collect();
+        variables.sort_by(|a, b| a.0.cmp(b.0));
+        variables
+    }
+}
+
+pub const HELP: &str = "\
+    :break LINE     stop at LINE
+    :delete LINE    remove the breakpoint at LINE
+    :step           run the statement, entering procedures
+    :next           run the statement, stepping over procedures
+    :finish         run until the procedure returns
+    :continue       run up to the next breakpoint
+    :quit           stop the program
+    :stack          print the call stack
+    :vars [FRAME]   print the variables of a frame, the current one by default
+    :help           print this help";
+
+/// Debugger driven by `:` commands read from `input`, used by
+/// the command line driver
+pub fn console(input: impl BufRead + 'static, mut output: impl Write + 'static) -> Debugger {
+    let mut lines = input.lines();
+    Debugger::new(move |pause| {
+        let frames = pause.frames;
+        let reason = match pause.reason {
+            Reason::Entry => "entry",
+            Reason::Breakpoint => "breakpoint",
+            Reason::Step => "step",
+            Reason::Pause => "pause",
+        };
+        writeln!(output, "stopped at {} in {} ({})",
+            pause.span, frames[frames.len() - 1].name, reason).expect("write output");
+        loop {
+            write!(output, "debug> ").expect("write output");
+            output.flush().expect("write output");
+            // the program runs to the end when input is over
+            let line = match lines.next() {
+                Some(Ok(line)) => line,
+                _ => return Command::Continue,
+            };
+            let mut words = line.split_whitespace();
+            let command = words.next().unwrap_or("");
+            let arg = words.next();
+            let number: Option<usize> = arg.and_then(|arg| arg.parse().ok());
+            match (command, number) {
+                (":break", Some(number)) => {
+                    pause.breakpoints.insert(number);
+                },
+                (":delete", Some(number)) => {
+                    pause.breakpoints.remove(&number);
+                },
+                (":step", _) => return Command::StepIn,
+                (":next", _) => return Command::StepOver,
+                (":finish", _) => return Command::StepOut,
+                (":continue", _) => return Command::Continue,
+                (":quit", _) => return Command::Stop,
+                (":stack", _) => {
+                    for (n, frame) in frames.iter().enumerate().rev() {
+                        writeln!(output, "#{} {} at {}", n, frame.name, frame.span)
+                            .expect("write output");
+                    }
+                },
+                (":vars", _) => {
+                    let index = match arg {
+                        None => Some(frames.len() - 1),
+                        Some(_) => number.filter(|n| *n < frames.len()),
+                    };
+                    match index {
+                        Some(index) => for (name, value) in frames[index].variables() {
+                            writeln!(output, "{} = {}", name, value).expect("write output");
+                        },
+                        None => writeln!(output, "error: no frame {}", arg.unwrap_or(""))
+                            .expect("write output"),
+                    }
+                },
+                (":help", _) => writeln!(output, "{}", HELP).expect("write output"),
+                _ => writeln!(output, "error: unknown command \"{}\", try :help", line.trim())
+                    .expect("write output"),
+            }
+        }
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Capture;
+    use std::cell::RefCell;
+    use std::io;
+    use std::rc::Rc;
+
+    const TEXT: &str = r#"program Nested;
+var a, r : integer;
+procedure P1(n : integer);
+    var b : integer;
+    procedure P2;
+    begin
+        b := b + n;
+        r := r + 1
+    end;
+begin
+    b := 10;
+    P2;
+    r := r + b
+end;
+begin
+    a := 2;
+    P1(a);
+    a := r
+end."#;
+
+    /// Run with `commands` given at each pause in turn, returns
+    /// the stops as `line name depth`
+    fn stops(debugger: Debugger, commands: &[Command]) -> Vec<String> {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let commands = commands.to_vec();
+        let handler_log = log.clone();
+        let mut debugger = debugger;
+        debugger.handler = Box::new(move |pause| {
+            let mut log = handler_log.borrow_mut();
+            let frame = pause.frames.last().unwrap();
+            log.push(format!("{} {} {}", pause.span.line, frame.name, pause.frames.len()));
+            commands.get(log.len() - 1).copied().unwrap_or(Command::Continue)
+        });
+        Interpreter::new(TEXT).report(io::sink()).debugger(debugger).exec();
+        let log = log.borrow().clone();
+        log
+    }
+
+    #[test]
+    fn steps() {
+        let debugger = Debugger::new(|_| Command::Continue).stop_on_entry(true);
+        assert_eq!(stops(debugger, &[Command::StepOver, Command::StepIn, Command::StepIn,
+                Command::StepIn, Command::StepOut, Command::StepOut]), vec![
+            "16 NESTED 1",
+            "17 NESTED 1",
+            "11 P1 2",
+            "12 P1 2",
+            "7 P2 3",
+            "13 P1 2",
+            "18 NESTED 1",
+        ]);
+        // breakpoints stop a step over the call
+        let debugger = Debugger::new(|_| Command::Continue).breakpoint(12).breakpoint(8);
+        assert_eq!(stops(debugger, &[Command::StepOver, Command::StepOver]), vec![
+            "12 P1 2",
+            "8 P2 3",
+            "13 P1 2",
+        ]);
+    }
+
+    #[test]
+    fn inspection() {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let log = seen.clone();
+        let debugger = Debugger::new(move |pause| {
+            for frame in pause.frames {
+                let vars: Vec<_> = frame.variables().iter()
+                    .map(|(name, value)| format!("{}={}", name, value))
+                    .collect();
+                log.borrow_mut().push(format!("{} at {}: {}", frame.name, frame.span, vars.join(" ")));
+            }
+            pause.breakpoints.clear();
+            Command::Continue
+        }).breakpoint(8);
+        Interpreter::new(TEXT).report(io::sink()).debugger(debugger).exec();
+        assert_eq!(*seen.borrow(), vec![
+            "NESTED at 17:5: A=2 R=0",
+            "P1 at 12:5: B=12 N=2",
+            "P2 at 8:9: ",
+        ]);
+    }
+
+    #[test]
+    fn commands() {
+        let input = ":break 8\n:continue\n:stack\n:vars 1\n:vars 5\n:finish\n:frobnicate\n:continue\n";
+        let output = Capture::default();
+        let debugger = console(io::Cursor::new(input), output.clone()).stop_on_entry(true);
+        let (ctx, _) = Interpreter::new(TEXT).report(io::sink()).debugger(debugger).exec();
+        assert_eq!(ctx.get_var("a"), Some(VariableValue::Integer(13)));
+        assert_eq!(output.text(), "\
+stopped at 16:5 in NESTED (entry)
+debug> debug> stopped at 8:9 in P2 (breakpoint)
+debug> #2 P2 at 8:9
+#1 P1 at 12:5
+#0 NESTED at 17:5
+debug> B = 12
+N = 2
+debug> error: no frame 5
+debug> stopped at 13:5 in P1 (step)
+debug> error: unknown command \":frobnicate\", try :help
+debug> ");
+    }
+}
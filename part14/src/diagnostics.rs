@@ -0,0 +1,147 @@
+use crate::tokens::*;
+
+use std::fmt;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// Warning kinds, the codes are stable and used by `{$WARN code OFF}`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum WarningCode {
+    Uninitialized,
+    UnusedVariable,
+    UnusedParameter,
+    UnreadVariable,
+    UnusedProcedure,
+    /// Off by default, enabled with `{$WARN W006 ON}`
+    Shadowing,
+    /// Constant division by zero or integer overflow, fails when run
+    ConstantFault,
+}
+
+const WARNING_CODES: &[(&str, WarningCode)] = &[
+    ("W001", WarningCode::Uninitialized),
+    ("W002", WarningCode::UnusedVariable),
+    ("W003", WarningCode::UnusedParameter),
+    ("W004", WarningCode::UnreadVariable),
+    ("W005", WarningCode::UnusedProcedure),
+    ("W006", WarningCode::Shadowing),
+    ("W007", WarningCode::ConstantFault),
+];
+
+/// Message reported by the semantic analysis
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: Option<WarningCode>,
+    pub message: String,
+    pub span: Span,
+    /// Related locations, e.g. the previous declaration
+    pub notes: Vec<(Span, String)>,
+}
+
+impl WarningCode {
+    pub fn code(&self) -> &'static str {
+        WARNING_CODES.iter()
+            .find(|(_, c)| c == self)
+            .map(|(code, _)| *code)
+            .unwrap()
+    }
+
+    pub fn from_code(code: &str) -> Option<WarningCode> {
+        WARNING_CODES.iter()
+            .find(|(s, _)| s.eq_ignore_ascii_case(code))
+            .map(|(_, c)| *c)
+    }
+
+    pub fn enabled_by_default(&self) -> bool {
+        *self != WarningCode::Shadowing
+    }
+}
+
+impl Diagnostic {
+    pub fn warning(code: WarningCode, span: Span, message: impl Into<String>) -> Self {
+        Diagnostic {
+            severity: Severity::Warning,
+            code: Some(code),
+            message: message.into(),
+            span,
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn error(span: Span, message: impl Into<String>) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            code: None,
+            message: message.into(),
+            span,
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn note(mut self, span: Span, message: impl Into<String>) -> Self {
+        self.notes.push((span, message.into()));
+        self
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+}
+
+/// Drop warnings switched off by `{$WARN code OFF}` directives.
+/// A directive is in effect from its position up to the next one for
+/// the same code, `{$WARN code ON}` enables the warning.
+pub fn apply_directives(diagnostics: Vec<Diagnostic>, directives: &[Directive]) -> Vec<Diagnostic> {
+    let position = |span: &Span| (span.line, span.column);
+    diagnostics.into_iter().filter(|diag| {
+        let code = match diag.code {
+            Some(code) => code,
+            None => return true,
+        };
+        let mut enabled = code.enabled_by_default();
+        for directive in directives {
+            if position(&directive.span) > position(&diag.span) {
+                break;
+            }
+            match (directive.name.as_str(), directive.args.as_slice()) {
+                ("WARN", [c, state]) if WarningCode::from_code(c) == Some(code) => {
+                    enabled = state != "OFF";
+                },
+                _ => {},
+            }
+        }
+        enabled
+    }).collect()
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // errors found outside of any source text have no location
+        if self.span != Span::default() {
+            write!(f, "{}: ", self.span)?;
+        }
+        match self.code {
+            Some(code) => write!(f, "{} {}: {}", self.severity, code.code(), self.message)?,
+            None => write!(f, "{}: {}", self.severity, self.message)?,
+        }
+        for (span, message) in &self.notes {
+            write!(f, "\n    {}: note: {}", span, message)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,439 @@
+use crate::tokens::*;
+use crate::ast::*;
+use crate::parser::*;
+use crate::diagnostics::*;
+
+/// Casing of keywords in the formatted text
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum KeywordCase {
+    Upper,
+    Lower,
+}
+
+/// Re-emit a program with canonical layout: four spaces per level,
+/// one declaration per line, one statement per line. Identifiers
+/// keep the spelling of the source, comments are kept on their own
+/// line before the following declaration or statement, or at the
+/// end of the line they followed.
+pub struct Formatter {
+    source: Vec<Vec<char>>,
+    parser: Parser,
+    keyword_case: KeywordCase,
+    comments: Vec<Comment>,
+    /// Next comment to emit
+    next_comment: usize,
+    lines: Vec<String>,
+    indent: usize,
+    /// Source line of the last emitted construct
+    prev_line: usize,
+}
+
+impl Formatter {
+    pub fn new<S: Into<String>>(text: S) -> Formatter {
+        let text = text.into();
+        Formatter {
+            source: text.split('\n').map(|line| line.chars().collect()).collect(),
+            parser: Parser::new(text),
+            keyword_case: KeywordCase::Upper,
+            comments: Vec::new(),
+            next_comment: 0,
+            lines: Vec::new(),
+            indent: 0,
+            prev_line: 0,
+        }
+    }
+
+    pub fn keyword_case(mut self, case: KeywordCase) -> Self {
+        self.keyword_case = case;
+        self
+    }
+
+    /// Formatted text, the syntax error when the program has one
+    pub fn format(mut self) -> Result<String, Diagnostic> {
+        let program = self.parser.try_parse()?;
+        self.comments = self.parser.comments().to_vec();
+        self.program(&program);
+        let mut text = self.lines.join("\n");
+        text.push('\n');
+        Ok(text)
+    }
+
+    fn kw(&self, keyword: &str) -> String {
+        match self.keyword_case {
+            KeywordCase::Upper => keyword.to_ascii_uppercase(),
+            KeywordCase::Lower => keyword.to_ascii_lowercase(),
+        }
+    }
+
+    /// Text of the identifier as written in the source
+    fn ident(&self, ident: &Ident) -> String {
+        let span = ident.span;
+        match self.source.get(span.line.wrapping_sub(1)) {
+            Some(line) if span.len > 0 => line.iter().skip(span.column - 1).take(span.len).collect(),
+            _ => ident.name.to_string(),
+        }
+    }
+
+    fn line(&mut self, text: String) {
+        self.lines.push(format!("{}{}", "    ".repeat(self.indent), text));
+    }
+
+    /// Append to the last emitted line
+    fn append(&mut self, text: &str) {
+        if let Some(last) = self.lines.last_mut() {
+            last.push_str(text);
+        }
+    }
+
+    /// Separate sections by an empty line, keeping a comment that
+    /// follows the previous construct on its line
+    fn blank(&mut self) {
+        while let Some(comment) = self.comments.get(self.next_comment) {
+            if comment.span.line != self.prev_line || !self.ends_line(comment) {
+                break;
+            }
+            let text = format!(" {}", comment.text);
+            self.next_comment += 1;
+            self.append(&text);
+        }
+        self.lines.push(String::new());
+    }
+
+    /// Nothing but blanks follows the comment on its line
+    fn ends_line(&self, comment: &Comment) -> bool {
+        let span = comment.span;
+        self.source.get(span.line - 1).is_none_or(|line| {
+            line.iter().skip(span.column - 1 + span.len).all(|c| c.is_whitespace())
+        })
+    }
+
+    /// Emit comments placed before `span`, a comment on the line of
+    /// the previous construct stays at the end of that line
+    fn comments_before(&mut self, span: Span) {
+        while let Some(comment) = self.comments.get(self.next_comment) {
+            let before = span == Span::default()
+                || (comment.span.line, comment.span.column) < (span.line, span.column);
+            if !before {
+                break;
+            }
+            let comment = comment.clone();
+            self.next_comment += 1;
+            if comment.span.line == self.prev_line && !self.lines.is_empty() {
+                self.append(&format!(" {}", comment.text));
+            }
+            else {
+                self.line(comment.text);
+            }
+            self.prev_line = comment.span.line;
+        }
+        if span != Span::default() {
+            self.prev_line = span.line;
+        }
+    }
+
+    fn program(&mut self, program: &Program) {
+        if program.name.span != Span::default() {
+            self.comments_before(program.name.span);
+            let name = self.ident(&program.name);
+            self.line(format!("{} {};", self.kw("program"), name));
+            self.blank();
+        }
+        self.block(&program.block, false);
+        self.append(".");
+        self.comments_before(Span::default());
+    }
+
+    /// Declarations and body, nested procedures get one more level
+    fn block(&mut self, block: &Block, nested: bool) {
+        let mut vars = block.decls.iter()
+            .filter_map(|decl| match decl {
+                Decl::Var(var) => Some(var),
+                _ => None,
+            })
+            .peekable();
+        if let Some(first) = vars.peek() {
+            self.comments_before(first.name.span);
+            self.line(self.kw("var"));
+            self.indent += 1;
+            for var in vars {
+                self.comments_before(var.name.span);
+                let text = format!("{} : {};", self.ident(&var.name), self.typ(var.typ));
+                self.line(text);
+            }
+            self.indent -= 1;
+            self.blank();
+        }
+        if nested {
+            self.indent += 1;
+        }
+        for decl in &block.decls {
+            if let Decl::Procedure(decl) = decl {
+                self.procedure(decl);
+                self.blank();
+            }
+        }
+        if nested {
+            self.indent -= 1;
+        }
+        self.compound(&block.body, block.end);
+    }
+
+    fn typ(&self, typ: Type) -> String {
+        match typ {
+            Type::Integer => self.kw("integer"),
+            Type::Real => self.kw("real"),
+        }
+    }
+
+    fn procedure(&mut self, decl: &ProcedureDecl) {
+        self.comments_before(decl.name.span);
+        let mut head = format!("{} {}", self.kw("procedure"), self.ident(&decl.name));
+        if !decl.params.is_empty() {
+            // consecutive parameters of the same type share the type
+            let mut groups: Vec<(Vec<String>, Type)> = Vec::new();
+            for param in &decl.params {
+                let name = self.ident(&param.name);
+                match groups.last_mut() {
+                    Some((names, typ)) if *typ == param.typ => names.push(name),
+                    _ => groups.push((vec![name], param.typ)),
+                }
+            }
+            let groups: Vec<_> = groups.into_iter()
+                .map(|(names, typ)| format!("{} : {}", names.join(", "), self.typ(typ)))
+                .collect();
+            head.push_str(&format!("({})", groups.join("; ")));
+        }
+        head.push(';');
+        match &decl.block {
+            None => self.line(format!("{} {};", head, self.kw("forward"))),
+            Some(block) => {
+                self.line(head);
+                self.block(block, true);
+                self.append(";");
+            },
+        }
+    }
+
+    /// BEGIN ... END at the current indent
+    fn compound(&mut self, body: &[Stmt], end: Span) {
+        self.line(self.kw("begin"));
+        self.indent += 1;
+        let body: Vec<_> = body.iter().filter(|stmt| **stmt != Stmt::NoOp).collect();
+        for (n, stmt) in body.iter().enumerate() {
+            self.statement(stmt);
+            if n + 1 < body.len() {
+                self.append(";");
+            }
+        }
+        self.comments_before(end);
+        self.indent -= 1;
+        self.line(self.kw("end"));
+    }
+
+    fn statement(&mut self, stmt: &Stmt) {
+        self.comments_before(stmt.span());
+        match stmt {
+            Stmt::Compound{body, end, ..} => self.compound(body, *end),
+            Stmt::Assign{target, value} => {
+                let text = format!("{} := {}", self.ident(target), self.expr(value, 0));
+                self.line(text);
+            },
+            Stmt::Call{name, args} => {
+                let text = format!("{}{}", self.ident(name), self.args(args));
+                self.line(text);
+            },
+            Stmt::Writeln{args, span} => {
+                let name = Ident { name: "WRITELN".into(), span: *span };
+                let text = format!("{}{}", self.ident(&name), self.args(args));
+                self.line(text);
+            },
+            Stmt::If{cond, then_branch, else_branch, ..} => {
+                let text = format!("{} {} {}", self.kw("if"), self.expr(cond, 0), self.kw("then"));
+                self.line(text);
+                self.branch(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.line(self.kw("else"));
+                    self.branch(else_branch);
+                }
+            },
+            Stmt::While{cond, body, ..} => {
+                let text = format!("{} {} {}", self.kw("while"), self.expr(cond, 0), self.kw("do"));
+                self.line(text);
+                self.branch(body);
+            },
+            Stmt::NoOp => {},
+        }
+    }
+
+    /// Body of IF or WHILE, a compound one stays at the current indent
+    fn branch(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Compound{..} => self.statement(stmt),
+            _ => {
+                self.indent += 1;
+                self.statement(stmt);
+                self.indent -= 1;
+            },
+        }
+    }
+
+    fn args(&self, args: &[Expr]) -> String {
+        if args.is_empty() {
+            return String::new();
+        }
+        let args: Vec<_> = args.iter().map(|arg| self.expr(arg, 0)).collect();
+        format!("({})", args.join(", "))
+    }
+
+    /// Expression with the parentheses required when it is an operand
+    /// of an operator with precedence `outer`
+    fn expr(&self, expr: &Expr, outer: u8) -> String {
+        match expr {
+            // literals keep their source spelling, e.g. `1.50`
+            Expr::Num{value, span} => self.ident(&Ident { name: value.to_string(), span: *span }),
+            Expr::Var(var) => self.ident(var),
+            Expr::BinOp{op, left, right, ..} => {
+                let prec = precedence(*op);
+                // operators are left associative
+                let text = format!("{} {} {}",
+                    self.expr(left, prec),
+                    self.operator(*op),
+                    self.expr(right, prec + 1));
+                if prec < outer {
+                    format!("({})", text)
+                }
+                else {
+                    text
+                }
+            },
+            Expr::UnaryOp{op, operand, ..} => {
+                let sign = match op {
+                    UnaryOp::Plus => "+",
+                    UnaryOp::Minus => "-",
+                };
+                let operand = self.expr(operand, 3);
+                // `- -x` must not become `--x`
+                let space = if operand.starts_with('-') || operand.starts_with('+') { " " } else { "" };
+                format!("{}{}{}", sign, space, operand)
+            },
+            // `F()` must not become the variable `F`
+            Expr::Call{name, args} if args.is_empty() => format!("{}()", self.ident(name)),
+            Expr::Call{name, args} => format!("{}{}", self.ident(name), self.args(args)),
+        }
+    }
+
+    fn operator(&self, op: BinOp) -> String {
+        match op {
+            BinOp::Add => "+".into(),
+            BinOp::Sub => "-".into(),
+            BinOp::Mul => "*".into(),
+            BinOp::Div => "/".into(),
+            BinOp::IntDiv => self.kw("div"),
+            BinOp::Eq => "=".into(),
+            BinOp::Ne => "<>".into(),
+            BinOp::Lt => "<".into(),
+            BinOp::Le => "<=".into(),
+            BinOp::Gt => ">".into(),
+            BinOp::Ge => ">=".into(),
+        }
+    }
+}
+
+fn precedence(op: BinOp) -> u8 {
+    match op {
+        BinOp::Add | BinOp::Sub => 1,
+        BinOp::Mul | BinOp::Div | BinOp::IntDiv => 2,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "program  Main; { entry }
+var x,y : integer;   z:real;
+procedure Alpha(a, b : integer; c : real); { nested }
+   var t : integer;
+   procedure Beta; begin t := a end;
+begin Beta; if a > b then t := (a + b) * 2 else begin t := a - (b - 1); end; end;
+begin { start }
+  x := 1; y := -(x + 2) div 3; { tail }
+  while x < 10 do x := x + 1;
+  Alpha(x, y, z);
+  { last }
+end.";
+
+    #[test]
+    fn layout() {
+        let text = Formatter::new(SOURCE).format().unwrap();
+        assert_eq!(text, "\
+PROGRAM Main; { entry }
+
+VAR
+    x : INTEGER;
+    y : INTEGER;
+    z : REAL;
+
+PROCEDURE Alpha(a, b : INTEGER; c : REAL); { nested }
+VAR
+    t : INTEGER;
+
+    PROCEDURE Beta;
+    BEGIN
+        t := a
+    END;
+
+BEGIN
+    Beta;
+    IF a > b THEN
+        t := (a + b) * 2
+    ELSE
+    BEGIN
+        t := a - (b - 1)
+    END
+END;
+
+BEGIN
+    { start }
+    x := 1;
+    y := -(x + 2) DIV 3; { tail }
+    WHILE x < 10 DO
+        x := x + 1;
+    Alpha(x, y, z)
+    { last }
+END.
+");
+    }
+
+    #[test]
+    fn idempotent() {
+        let once = Formatter::new(SOURCE).format().unwrap();
+        let twice = Formatter::new(once.clone()).format().unwrap();
+        assert_eq!(once, twice);
+    }
+
+    #[test]
+    fn lower_case() {
+        let text = Formatter::new("PROGRAM P; VAR N : INTEGER; BEGIN N := 7 DIV 2 END.")
+            .keyword_case(KeywordCase::Lower)
+            .format()
+            .unwrap();
+        assert_eq!(text, "\
+program P;
+
+var
+    N : integer;
+
+begin
+    N := 7 div 2
+end.
+");
+    }
+
+    #[test]
+    fn syntax_error() {
+        let err = Formatter::new("program A; begin x := ; end.").format().unwrap_err();
+        assert_eq!(err.to_string(), "1:23: error: Unexpected SEMI at factor");
+    }
+}
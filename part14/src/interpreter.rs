@@ -1,144 +1,841 @@
 use crate::tokens::*;
-use crate::lexer::*;
-use crate::parser::*;
 use crate::ast::*;
 use crate::symbols::*;
+use crate::analysis::Analysis;
+use crate::diagnostics::Diagnostic;
+use crate::Error;
+use crate::bytecode::*;
+use crate::compiler::*;
+use crate::vm::*;
+use crate::cgen::*;
+use crate::wat::*;
+use crate::ir::*;
+use crate::passes;
+use crate::debugger::*;
+use crate::profiler::*;
+use crate::limits::*;
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
 pub struct Interpreter {
-    parser: Parser,
+    text: String,
     context: Context,
+    track_undefined: bool,
+    optimize: bool,
+    bytecode: bool,
+    /// Destination of `WRITELN`
+    output: Box<dyn Write>,
+    /// Destination of the execution trace
+    trace: Option<Box<dyn Write>>,
+    /// Destination of warnings and errors
+    report: Box<dyn Write>,
+    debugger: Option<Debugger>,
+    profiler: Option<Profiler>,
+    limits: Limits,
+    meter: Meter,
+    call_stack: Vec<ActivationRecord>,
+    /// Declarations found by `SemanticAnalyzer`
+    resolutions: HashMap<Span, Resolution>,
+    /// Warnings of the analysis
+    warnings: Vec<Diagnostic>,
+    /// Procedures keyed by location of the name
+    procedures: HashMap<Span, Rc<ProcedureDecl>>,
+    /// Native procedures and functions keyed by name
+    natives: HashMap<String, Native>,
+}
+
+/// Procedure or function implemented by the host, see
+/// `Interpreter::register_fn`
+pub struct Native {
+    pub params: Vec<Type>,
+    pub result: Option<Type>,
+    func: NativeFn,
 }
 
-#[derive(Default)]
+type NativeFn = Box<dyn FnMut(&[VariableValue]) -> VariableValue>;
+
+#[derive(Debug, Default, Clone)]
 pub struct Context {
     pub variables: VariableTable,
 }
 
+/// Frame of the program or procedure call
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct ActivationRecord {
+    pub name: String,
+    pub nesting_level: u32,
+    /// Index of the frame of the lexically enclosing scope
+    pub static_link: Option<usize>,
+    pub members: VariableTable,
+    /// Statement being run in the frame, the call for the callers
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum RuntimeError {
+    /// Read of a variable which was never assigned
+    UndefinedVariable {
+        name: String,
+        span: Span,
+    },
+    DivisionByZero {
+        span: Span,
+    },
+    /// INTEGER result out of its range
+    IntegerOverflow {
+        span: Span,
+    },
+    /// Aborted by `Command::Stop` of the debugger
+    Stopped {
+        span: Span,
+    },
+    /// Over the limits set by `Interpreter::limits`
+    StepLimit {
+        limit: u64,
+        span: Span,
+    },
+    DepthLimit {
+        limit: usize,
+        span: Span,
+    },
+    MemoryLimit {
+        limit: usize,
+        span: Span,
+    },
+    Timeout {
+        limit: Duration,
+        span: Span,
+    },
+    /// Native function of `Interpreter::register_fn` returned a value
+    /// its result type does not accept
+    NativeResult {
+        name: String,
+        expected: Type,
+        got: VariableValue,
+        span: Span,
+    },
+    /// Calls and expressions nested deeper than the native stack
+    /// of `Limits::stack`
+    StackOverflow {
+        span: Span,
+    },
+    /// `WRITELN` failed to write to the output
+    Output {
+        error: String,
+        span: Span,
+    },
+    /// Native function of `Interpreter::register_fn` panicked, with
+    /// the message of the panic
+    NativePanic {
+        name: String,
+        message: String,
+        span: Span,
+    },
+}
+
 impl Context {
     pub fn get_var<S: Into<String>>(&self, name: S) -> Option<VariableValue> {
         let key = name.into().to_ascii_uppercase();
-        if self.variables.contains_key(&key) {
-            Some(*self.variables.get(&key).unwrap())
-        }
-        else {
-            None
+        self.variables.get(&key).copied()
+    }
+
+    /// Value of the variable `name` converted to a Rust type, `None`
+    /// when it is not defined or does not convert, e.g. `get::<i32>("x")`
+    pub fn get<T: TryFrom<VariableValue>>(&self, name: &str) -> Option<T> {
+        T::try_from(self.get_var(name)?).ok()
+    }
+
+    /// Assign `value` to the defined variable `name` when its type
+    /// accepts the value, returns whether it was assigned
+    pub fn set(&mut self, name: &str, value: impl Into<VariableValue>) -> bool {
+        let value = value.into();
+        match self.variables.get_mut(&name.to_ascii_uppercase()) {
+            Some(var) if var.type_of().accepts(value) => {
+                var.assign(value);
+                true
+            },
+            _ => false,
         }
     }
 }
 
 impl Interpreter {
     pub fn new<S: Into<String>>(text: S) -> Interpreter {
-        let text = text.into();
-        //println!("{}", text);
         Interpreter {
-            parser: Parser::new(text),
+            text: text.into(),
             context: Context::default(),
+            track_undefined: false,
+            optimize: false,
+            bytecode: false,
+            output: Box::new(io::stdout()),
+            trace: None,
+            report: Box::new(io::stderr()),
+            debugger: None,
+            profiler: None,
+            limits: Limits::default(),
+            meter: Meter::default(),
+            call_stack: Vec::new(),
+            resolutions: HashMap::new(),
+            warnings: Vec::new(),
+            procedures: HashMap::new(),
+            natives: HashMap::new(),
+        }
+    }
+
+    /// Leave declared variables undefined instead of zero-initialized,
+    /// reading them before assignment is a runtime error
+    pub fn track_undefined(mut self, on: bool) -> Self {
+        self.track_undefined = on;
+        self
+    }
+
+    /// Fold constant subexpressions before execution, `lower` also
+    /// runs the SSA optimizations over the IR
+    pub fn optimize(mut self, on: bool) -> Self {
+        self.optimize = on;
+        self
+    }
+
+    /// Compile to bytecode and execute it on `Vm` instead of walking the tree
+    pub fn bytecode(mut self, on: bool) -> Self {
+        self.bytecode = on;
+        self
+    }
+
+    /// Send program output to `out` instead of stdout
+    pub fn output(mut self, out: impl Write + 'static) -> Self {
+        self.output = Box::new(out);
+        self
+    }
+
+    /// Write a line per executed statement with its location
+    /// and outcome to `out`
+    pub fn trace(mut self, out: impl Write + 'static) -> Self {
+        self.trace = Some(Box::new(out));
+        self
+    }
+
+    /// Send warnings and errors to `out` instead of stderr
+    pub fn report(mut self, out: impl Write + 'static) -> Self {
+        self.report = Box::new(out);
+        self
+    }
+
+    /// Stop at breakpoints and steps of `debugger`, the tree-walker
+    /// only
+    pub fn debugger(mut self, debugger: Debugger) -> Self {
+        self.debugger = Some(debugger);
+        self
+    }
+
+    /// Measure statements and procedure calls with `profiler`,
+    /// the tree-walker only
+    pub fn profiler(mut self, profiler: Profiler) -> Self {
+        self.profiler = Some(profiler);
+        self
+    }
+
+    /// Abort with a `RuntimeError` when the program goes over `limits`
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Make `func` callable from the program as the procedure `name`,
+    /// or as a function when it has a `result` type. The arguments are
+    /// converted to the `params` types and type-checked like the ones
+    /// of declared procedures, a declaration of the same name hides it.
+    /// A result the `result` type does not accept fails the call with
+    /// `RuntimeError::NativeResult`, a panic of `func` with
+    /// `RuntimeError::NativePanic`. The tree-walker only.
+    pub fn register_fn(mut self, name: &str, params: &[Type], result: Option<Type>,
+        func: impl FnMut(&[VariableValue]) -> VariableValue + 'static) -> Self
+    {
+        self.natives.insert(name.to_ascii_uppercase(), Native {
+            params: params.to_vec(),
+            result,
+            func: Box::new(func),
+        });
+        self
+    }
+
+    /// Start with the global variables of `context`, e.g. kept from
+    /// a previous run, the tree-walker initializes only the missing ones
+    pub fn context(mut self, context: Context) -> Self {
+        self.context = context;
+        self
+    }
+
+    /// Parse, analyze and optionally optimize the program, the
+    /// diagnostics go to the report
+    fn analyze(&mut self) -> Result<Program, Error> {
+        let natives = &self.natives;
+        let analysis = Analysis::new(&self.text, |analyzer| {
+            for (name, native) in natives {
+                analyzer.define_builtin(name, &native.params, native.result);
+            }
+        }, self.optimize);
+        for diag in &analysis.diagnostics {
+            writeln!(self.report, "{}", diag).expect("write report");
+        }
+        let errors = analysis.has_errors();
+        match analysis.program {
+            Some(program) if !errors => {
+                self.resolutions = analysis.resolutions;
+                self.warnings = analysis.diagnostics;
+                Ok(program)
+            },
+            _ => Err(Error::Invalid(analysis.diagnostics)),
+        }
+    }
+
+    /// Program which `backend` can translate, the natives are only
+    /// known to the tree-walker
+    fn analyze_for(&mut self, backend: &str) -> Result<Program, Error> {
+        let program = self.analyze()?;
+        let native = self.resolutions.iter()
+            .filter(|(_, resolution)| resolution.level == 0)
+            .map(|(span, _)| *span)
+            .min_by_key(|span| (span.line, span.column));
+        match native {
+            Some(span) => Err(self.invalid(Diagnostic::error(span,
+                format!("{} can not call native procedures", backend)))),
+            None => Ok(program),
+        }
+    }
+
+    /// Report an error found after the analysis
+    fn invalid(&mut self, diag: Diagnostic) -> Error {
+        writeln!(self.report, "{}", diag).expect("write report");
+        Error::Invalid(vec![diag])
+    }
+
+    /// Compile the program to bytecode, fails when it does not fit
+    /// the operands of the instructions
+    pub(crate) fn compile(mut self) -> Result<Module, Error> {
+        let tree = self.analyze_for("bytecode")?;
+        self.compile_tree(&tree)
+    }
+
+    fn compile_tree(&mut self, tree: &Program) -> Result<Module, Error> {
+        Compiler::new(&self.resolutions).compile(tree).map_err(|diag| self.invalid(diag))
+    }
+
+    /// Translate the program into C99 source
+    pub fn transpile(mut self) -> Result<String, Error> {
+        let tree = self.analyze_for("C")?;
+        Ok(CGenerator::new(&self.resolutions).generate(&tree))
+    }
+
+    /// Translate the program into WebAssembly text
+    pub fn emit_wat(mut self) -> Result<String, Error> {
+        let tree = self.analyze_for("WebAssembly")?;
+        Ok(WatGenerator::new(&self.resolutions).generate(&tree))
+    }
+
+    /// Lower the program into three-address code
+    pub(crate) fn lower(mut self) -> Result<IrModule, Error> {
+        let tree = self.analyze_for("IR")?;
+        let mut module = Lowering::new(&self.resolutions).lower(&tree);
+        if self.optimize {
+            passes::optimize(&mut module);
+        }
+        Ok(module)
+    }
+
+    /// Analyze and run the program, the diagnostics go to the report
+    /// and errors among them fail the run before it starts
+    pub fn run(self) -> Result<(Context, VariableValue), Error> {
+        self.run_with_warnings().map(|(context, res, _)| (context, res))
+    }
+
+    /// Like `run`, also returning the warnings of the analysis
+    pub(crate) fn run_with_warnings(mut self)
+        -> Result<(Context, VariableValue, Vec<Diagnostic>), Error>
+    {
+        if self.bytecode {
+            let tree = self.analyze_for("bytecode")?;
+            let module = self.compile_tree(&tree)?;
+            let context = Vm::new(&module)
+                .track_undefined(self.track_undefined)
+                .limits(self.limits)
+                .output(&mut self.output)
+                .run()?;
+            return Ok((context, VariableValue::None, self.warnings));
         }
+        let tree = self.analyze()?;
+        self.procedures = procedures(&tree).iter()
+            .map(|body| (body.decl.name.span, Rc::new(body.decl.clone())))
+            .collect();
+        self.start();
+        let res = self.in_program(&tree, |this| this.block(&tree.block));
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.finish();
+        }
+        Ok((self.context, res?, self.warnings))
+    }
+
+    #[cfg(test)]
+    pub(crate) fn exec(self) -> (Context, VariableValue) {
+        match self.run() {
+            Ok(res) => res,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Parse and analyze the program and define its globals without
+    /// running the main block, fails on errors like `run`. The
+    /// tree-walker only.
+    pub fn instantiate(mut self) -> Result<Instance, Error> {
+        let program = self.analyze()?;
+        self.procedures = procedures(&program).iter()
+            .map(|body| (body.decl.name.span, Rc::new(body.decl.clone())))
+            .collect();
+        self.start();
+        self.in_program(&program, |this| this.declare(&program.block))?;
+        Ok(Instance { interpreter: self, program })
+    }
+}
+
+/// Program analyzed once whose procedures are called from Rust,
+/// the globals are kept between the calls
+pub struct Instance {
+    interpreter: Interpreter,
+    program: Program,
+}
+
+/// Failed call of `Instance::call`
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum CallError {
+    /// No procedure of the name at the top level of the program
+    NotDefined {
+        name: String,
+    },
+    Arguments {
+        name: String,
+        expected: usize,
+        got: usize,
+    },
+    /// Argument `index`, from 1, does not fit the parameter
+    ArgumentType {
+        name: String,
+        index: usize,
+        expected: Type,
+        got: VariableValue,
+    },
+    Runtime(RuntimeError),
+}
+
+impl From<RuntimeError> for CallError {
+    fn from(e: RuntimeError) -> Self {
+        CallError::Runtime(e)
     }
+}
 
-    pub fn exec(mut self) -> (Context, VariableValue) {
-        let tree = self.parser.parse();
-        // println!("{:#?}", tree);
-        let mut semantic_analyzer = SemanticAnalyzer::default();
-        semantic_analyzer.visit(&tree);
-        let res = self.visit(&tree);
-        (self.context, res)
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CallError::NotDefined{name} =>
+                write!(f, "procedure \"{}\" not defined", name),
+            CallError::Arguments{name, expected, got} =>
+                write!(f, "procedure \"{}\" expects {} arguments, got {}", name, expected, got),
+            CallError::ArgumentType{name, index, expected, got} =>
+                write!(f, "argument {} of procedure \"{}\" must be {:?}, got {:?}",
+                    index, name, expected, got),
+            CallError::Runtime(e) => write!(f, "{}", e),
+        }
     }
 }
 
-impl NodeVisitor for Interpreter {
-    type Result = VariableValue;
-    fn visit(&mut self, node: &AST) -> VariableValue {
-        match &node.root {
-            Root::Program{name} => self.program(node),
-            Root::Compound => self.compound(node),
-            Root::Num(n) => *n,
-            Root::VarDecl => VariableValue::None,
-            Root::VarID{name, value} => self.variable(node),
-            Root::ProcedureDecl{name} => VariableValue::None,
-            Root::Param => VariableValue::None,
-            Root::Assign => self.assign(node),
-            Root::BinOp(op)   => self.binary(op, node),
-            Root::UnaryOp(op) => self.unary(op, node),
-            Root::NoOp => VariableValue::None,
+impl Instance {
+    /// Run the main block
+    pub fn run_main(&mut self) -> Result<(), RuntimeError> {
+        let program = &self.program;
+        self.interpreter.start();
+        self.interpreter.in_program(program, |this| this.block(&program.block))?;
+        Ok(())
+    }
+
+    /// Call the procedure `name` declared at the top level of the
+    /// program, the arguments must fit the parameter types
+    pub fn call(&mut self, name: &str, args: &[VariableValue]) -> Result<(), CallError> {
+        let name = name.to_ascii_uppercase();
+        let decl = self.program.block.decls.iter()
+            .filter_map(|decl| match decl {
+                Decl::Procedure(decl) => Some(decl),
+                _ => None,
+            })
+            .find(|decl| decl.name.name == name && decl.block.is_some())
+            .ok_or_else(|| CallError::NotDefined { name: name.clone() })?;
+        if decl.params.len() != args.len() {
+            return Err(CallError::Arguments { name, expected: decl.params.len(), got: args.len() });
+        }
+        let mut members = VariableTable::default();
+        for (index, (param, arg)) in decl.params.iter().zip(args).enumerate() {
+            if !param.typ.accepts(*arg) {
+                return Err(CallError::ArgumentType { name, index: index + 1, expected: param.typ, got: *arg });
+            }
+            let mut value = VariableValue::from(param.typ);
+            value.assign(*arg);
+            members.insert(param.name.name.to_string(), value);
         }
+        let interpreter = &mut self.interpreter;
+        interpreter.start();
+        interpreter.in_program(&self.program, |this| this.invoke(&decl.name, decl, 2, Some(0), members))?;
+        Ok(())
+    }
+
+    /// Global variables
+    pub fn globals(&self) -> &Context {
+        &self.interpreter.context
+    }
+
+    pub fn globals_mut(&mut self) -> &mut Context {
+        &mut self.interpreter.context
     }
 }
 
 impl Interpreter {
-    fn program(&mut self, node: &AST) -> VariableValue {
-        VariableValue::None
+    /// Reset the limits for a new run
+    fn start(&mut self) {
+        self.meter = Meter::new(self.limits);
+        self.meter.start();
     }
 
-    fn compound(&mut self, node: &AST) -> VariableValue {
-        let left = node.left.as_ref().unwrap();
-        let mut res = self.visit(left);
-        if node.right.is_some() {
-            let right = node.right.as_ref().unwrap();
-            res = self.visit(right)
+    /// Run `f` in the frame of the program, which holds the globals
+    /// of `context` meanwhile
+    fn in_program<T>(&mut self, program: &Program,
+        f: impl FnOnce(&mut Self) -> Result<T, RuntimeError>) -> Result<T, RuntimeError>
+    {
+        self.meter.allocate(self.context.variables.len(), program.name.span)?;
+        self.call_stack.push(ActivationRecord {
+            name: program.name.name.to_string(),
+            nesting_level: 1,
+            static_link: None,
+            members: std::mem::take(&mut self.context.variables),
+            span: Span::default(),
+        });
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.enter(&program.name.name);
+        }
+        let res = f(self);
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.leave();
         }
+        let frame = self.call_stack.pop().unwrap();
+        self.meter.free(frame.members.len());
+        self.context.variables = frame.members;
         res
     }
 
-    fn assign(&mut self, node: &AST) -> VariableValue {
-        // right-hand side
-        let right = node.right.as_ref().unwrap();
-        let right = self.visit(right);
-        // left-hand side
-        let left = node.left.as_ref().unwrap();
-        let id = match &left.root {
-            Root::VarID{name, value} => name,
-            _ => unreachable!()
-        };
-        // assign
-        if self.context.variables.contains_key(id) {
-            let val = self.context.variables.get_mut(id).unwrap();
-            val.assign(right)
+    /// Define local variables in the current frame and run the body
+    fn block(&mut self, block: &Block) -> Result<VariableValue, RuntimeError> {
+        self.declare(block)?;
+        self.compound(&block.body)
+    }
+
+    /// Define the local variables of `block` missing in the current frame
+    fn declare(&mut self, block: &Block) -> Result<(), RuntimeError> {
+        for decl in &block.decls {
+            if let Decl::Var(var) = decl {
+                let value = if self.track_undefined {
+                    VariableValue::Undefined(var.typ)
+                }
+                else {
+                    VariableValue::from(var.typ)
+                };
+                let frame = self.call_stack.last_mut().unwrap();
+                let count = frame.members.len();
+                frame.members.entry(var.name.name.to_string()).or_insert(value);
+                let count = frame.members.len() - count;
+                self.meter.allocate(count, var.name.span)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn compound(&mut self, body: &[Stmt]) -> Result<VariableValue, RuntimeError> {
+        let mut res = VariableValue::None;
+        for stmt in body {
+            res = self.statement(stmt)?;
+        }
+        Ok(res)
+    }
+
+    fn statement(&mut self, stmt: &Stmt) -> Result<VariableValue, RuntimeError> {
+        if matches!(stmt, Stmt::Compound{..} | Stmt::NoOp) {
+            return self.execute(stmt);
+        }
+        let span = stmt.span();
+        self.meter.step(span)?;
+        self.meter.recurse(span)?;
+        self.call_stack.last_mut().unwrap().span = span;
+        if let Some(debugger) = self.debugger.as_mut() {
+            debugger.statement(span, &self.call_stack)?;
         }
-        else {
-            self.context.variables.insert(id.to_string(), right);
-            *self.context.variables.get(id).unwrap()
+        if self.profiler.is_none() {
+            return self.execute(stmt);
         }
+        let start = Instant::now();
+        let res = self.execute(stmt);
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.statement(span, start.elapsed());
+        }
+        res
     }
 
-    fn variable(&mut self, node: &AST) -> VariableValue {
-        let id = match &node.root {
-            Root::VarID{name, value} => name,
-            _ => unreachable!()
-        };
-        if let Some(val) = self.context.variables.get(id) {
-            *val
+    fn execute(&mut self, stmt: &Stmt) -> Result<VariableValue, RuntimeError> {
+        match stmt {
+            Stmt::Compound{body, ..} => self.compound(body),
+            Stmt::Assign{target, value} => {
+                let res = self.assign(target, value)?;
+                self.trace_line(target.span, format_args!("{} := {}", target.name, res));
+                Ok(res)
+            },
+            Stmt::Call{name, args} => {
+                self.trace_line(name.span, format_args!("call {}", name.name));
+                self.procedure_call(name, args)
+            },
+            Stmt::If{cond, then_branch, else_branch, span} => {
+                let cond = self.expr(cond)?;
+                self.trace_line(*span, format_args!("IF {}", cond));
+                match cond {
+                    VariableValue::Boolean(true) => self.statement(then_branch),
+                    VariableValue::Boolean(false) => match else_branch {
+                        Some(else_branch) => self.statement(else_branch),
+                        None => Ok(VariableValue::None),
+                    },
+                    _ => unreachable!()
+                }
+            },
+            Stmt::While{cond, body, span} => {
+                loop {
+                    let cond = self.expr(cond)?;
+                    self.trace_line(*span, format_args!("WHILE {}", cond));
+                    if cond != VariableValue::Boolean(true) {
+                        break;
+                    }
+                    self.statement(body)?;
+                }
+                Ok(VariableValue::None)
+            },
+            Stmt::Writeln{args, span} => {
+                self.trace_line(*span, format_args!("WRITELN"));
+                let output = |e: io::Error| RuntimeError::Output {
+                    error: e.to_string(),
+                    span: *span,
+                };
+                for arg in args {
+                    let value = self.expr(arg)?;
+                    write!(self.output, "{}", value).map_err(output)?;
+                }
+                writeln!(self.output).map_err(output)?;
+                Ok(VariableValue::None)
+            },
+            Stmt::NoOp => Ok(VariableValue::None),
+        }
+    }
+
+    fn trace_line(&mut self, span: Span, text: fmt::Arguments) {
+        if let Some(trace) = self.trace.as_mut() {
+            writeln!(trace, "{}: {}", span, text).expect("write trace");
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) -> Result<VariableValue, RuntimeError> {
+        self.meter.step(expr.span())?;
+        self.meter.recurse(expr.span())?;
+        match expr {
+            Expr::Num{value, ..} => Ok(*value),
+            Expr::Var(var) => self.variable(var),
+            Expr::BinOp{op, left, right, span} => {
+                let left  = self.expr(left)?;
+                let right = self.expr(right)?;
+                if op.divides_by_zero(right) {
+                    return Err(RuntimeError::DivisionByZero { span: *span });
+                }
+                op.apply(left, right).ok_or(RuntimeError::IntegerOverflow { span: *span })
+            },
+            Expr::UnaryOp{op, operand, span} => {
+                let operand = self.expr(operand)?;
+                match op {
+                    UnaryOp::Plus  => Ok(operand),
+                    UnaryOp::Minus => operand.checked_neg()
+                        .ok_or(RuntimeError::IntegerOverflow { span: *span }),
+                }
+            },
+            Expr::Call{name, args} => self.native_call(name, args),
+        }
+    }
+
+    /// Index of the frame of the scope at `level`, reached by
+    /// static links from the current frame
+    fn frame_index(&self, level: u32) -> usize {
+        let mut index = self.call_stack.len() - 1;
+        while self.call_stack[index].nesting_level > level {
+            index = self.call_stack[index].static_link.unwrap();
+        }
+        assert_eq!(self.call_stack[index].nesting_level, level);
+        index
+    }
+
+    fn frame(&mut self, level: u32) -> &mut ActivationRecord {
+        let index = self.frame_index(level);
+        &mut self.call_stack[index]
+    }
+
+    fn procedure_call(&mut self, name: &Ident, args: &[Expr]) -> Result<VariableValue, RuntimeError> {
+        // evaluate arguments in the caller frame
+        let mut members = VariableTable::default();
+        let resolution = self.resolutions[&name.span];
+        if resolution.level == 0 {
+            return self.native_call(name, args).map(|_| VariableValue::None);
+        }
+        let decl = self.procedures[&resolution.decl].clone();
+        for (param, arg) in decl.params.iter().zip(args) {
+            let mut value = VariableValue::from(param.typ);
+            value.assign(self.expr(arg)?);
+            members.insert(param.name.name.to_string(), value);
+        }
+        let static_link = Some(self.frame_index(resolution.level));
+        self.invoke(name, &decl, resolution.level + 1, static_link, members)
+    }
+
+    /// Run the procedure `decl` called at `name` in a new frame
+    /// with the parameters in `members`
+    fn invoke(&mut self, name: &Ident, decl: &ProcedureDecl, nesting_level: u32,
+        static_link: Option<usize>, members: VariableTable) -> Result<VariableValue, RuntimeError>
+    {
+        self.meter.call(self.call_stack.len(), name.span)?;
+        self.meter.allocate(members.len(), name.span)?;
+        self.call_stack.push(ActivationRecord {
+            name: name.name.to_string(),
+            nesting_level,
+            static_link,
+            members,
+            span: Span::default(),
+        });
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.enter(&name.name);
+        }
+        let res = self.block(decl.block.as_ref().unwrap());
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.leave();
+        }
+        let frame = self.call_stack.pop().unwrap();
+        self.meter.free(frame.members.len());
+        res.map(|_| VariableValue::None)
+    }
+
+    /// Call the native procedure or function `name`, the result
+    /// is `VariableValue::None` for procedures
+    fn native_call(&mut self, name: &Ident, args: &[Expr]) -> Result<VariableValue, RuntimeError> {
+        let params = self.natives[&name.name].params.clone();
+        let mut values = Vec::with_capacity(args.len());
+        for (typ, arg) in params.iter().zip(args) {
+            let mut value = VariableValue::from(*typ);
+            value.assign(self.expr(arg)?);
+            values.push(value);
         }
-        else {
-            unreachable!()
+        let native = self.natives.get_mut(&name.name).unwrap();
+        // the callback is the embedder's code, its panic fails the run
+        let res = panic::catch_unwind(AssertUnwindSafe(|| (native.func)(&values)))
+            .map_err(|payload| RuntimeError::NativePanic {
+                name: name.name.to_string(),
+                message: payload.downcast_ref::<&str>().map(|msg| msg.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "unknown panic".to_string()),
+                span: name.span,
+            })?;
+        match native.result {
+            Some(typ) if typ.accepts(res) => Ok(VariableValue::from(typ).assign(res)),
+            Some(typ) => Err(RuntimeError::NativeResult {
+                name: name.name.to_string(),
+                expected: typ,
+                got: res,
+                span: name.span,
+            }),
+            None => Ok(VariableValue::None),
         }
     }
 
-    fn binary(&mut self, op: &Token, node: &AST) -> VariableValue {
-        // Unwrap and visit
-        let left  = node.left.as_ref().unwrap();
-        let right = node.right.as_ref().unwrap();
-        let left  = self.visit(left);
-        let right = self.visit(right);
-        match op {
-            Token::OpPlus  => left + right,
-            Token::OpMinus => left - right,
-            Token::OpMul   => left * right,
-            Token::OpDiv   => left.as_real() / right.as_real(),
-            Token::OpIntegerDiv => left.as_integer() / right.as_integer(),
-            _ => unreachable!()
+    fn assign(&mut self, target: &Ident, value: &Expr) -> Result<VariableValue, RuntimeError> {
+        // right-hand side
+        let value = self.expr(value)?;
+        // left-hand side
+        let level = self.resolutions[&target.span].level;
+        let var = self.frame(level).members.get_mut(&target.name).unwrap();
+        Ok(var.assign(value))
+    }
+
+    fn variable(&mut self, var: &Ident) -> Result<VariableValue, RuntimeError> {
+        let level = self.resolutions[&var.span].level;
+        match self.frame(level).members.get(&var.name) {
+            Some(VariableValue::Undefined(_)) => Err(RuntimeError::UndefinedVariable {
+                name: var.name.to_string(),
+                span: var.span,
+            }),
+            Some(val) => Ok(*val),
+            None => unreachable!()
         }
     }
+}
 
-    fn unary(&mut self, op: &Token, node: &AST) -> VariableValue {
-        assert!(node.left.is_none());
-        // Unwrap and visit
-        let right = node.right.as_ref().unwrap();
-        let right = self.visit(right);
-        match op {
-            Token::OpPlus  =>  right,
-            Token::OpMinus => -right,
-            _ => unreachable!()
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let span = match self {
+            RuntimeError::UndefinedVariable{span, ..} |
+            RuntimeError::DivisionByZero{span} |
+            RuntimeError::IntegerOverflow{span} |
+            RuntimeError::Stopped{span} |
+            RuntimeError::StepLimit{span, ..} |
+            RuntimeError::DepthLimit{span, ..} |
+            RuntimeError::MemoryLimit{span, ..} |
+            RuntimeError::Timeout{span, ..} |
+            RuntimeError::NativeResult{span, ..} |
+            RuntimeError::StackOverflow{span} |
+            RuntimeError::Output{span, ..} |
+            RuntimeError::NativePanic{span, ..} => span,
+        };
+        // like diagnostics, errors without a location have no prefix
+        if *span != Span::default() {
+            write!(f, "{}: ", span)?;
+        }
+        match self {
+            RuntimeError::UndefinedVariable{name, ..} =>
+                write!(f, "runtime error: variable \"{}\" read before assignment", name),
+            RuntimeError::DivisionByZero{..} =>
+                write!(f, "runtime error: division by zero"),
+            RuntimeError::IntegerOverflow{..} =>
+                write!(f, "runtime error: integer overflow"),
+            RuntimeError::Stopped{..} =>
+                write!(f, "stopped by the debugger"),
+            RuntimeError::StepLimit{limit, ..} =>
+                write!(f, "runtime error: more than {} steps", limit),
+            RuntimeError::DepthLimit{limit, ..} =>
+                write!(f, "runtime error: more than {} procedure calls active", limit),
+            RuntimeError::MemoryLimit{limit, ..} =>
+                write!(f, "runtime error: variables take more than {} bytes", limit),
+            RuntimeError::Timeout{limit, ..} =>
+                write!(f, "runtime error: running longer than {:?}", limit),
+            RuntimeError::NativeResult{name, expected, got, ..} =>
+                write!(f, "runtime error: native function \"{}\" must return {:?}, got {:?}",
+                    name, expected, got),
+            RuntimeError::StackOverflow{..} =>
+                write!(f, "runtime error: procedure calls nested too deep for the stack"),
+            RuntimeError::Output{error, ..} =>
+                write!(f, "runtime error: can not write the output: {}", error),
+            RuntimeError::NativePanic{name, message, ..} =>
+                write!(f, "runtime error: native function \"{}\" panicked: {}", name, message),
         }
     }
 }
@@ -147,12 +844,272 @@ impl Interpreter {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::Capture;
     #[test]
     fn part14() {
         {
-            let (ctx, res) = Interpreter::new("BEGIN END.")
+            let (_, res) = Interpreter::new("BEGIN END.")
+                .report(io::sink())
                 .exec();
             assert_eq!(res, VariableValue::None);
         }
     }
+
+    #[test]
+    fn undefined() {
+        let text = r#"
+        program Undefined;
+        var x, y : integer;
+        begin
+            x := y
+        end."#;
+        let (ctx, _) = Interpreter::new(text).report(io::sink()).exec();
+        assert_eq!(ctx.get_var("x"), Some(VariableValue::Integer(0)));
+        let err = Interpreter::new(text)
+            .report(io::sink())
+            .track_undefined(true)
+            .run()
+            .err();
+        assert_eq!(err, Some(RuntimeError::UndefinedVariable {
+            name: "Y".into(),
+            span: Span { line: 5, column: 18, len: 1 },
+        }.into()));
+    }
+
+    #[test]
+    fn division_by_zero() {
+        let text = r#"
+        program Zero;
+        var a, b : integer;
+        begin
+            b := 7 DIV (3 * 2 - 6) + a
+        end."#;
+        let err = Interpreter::new(text).report(io::sink()).run().err();
+        assert_eq!(err, Some(RuntimeError::DivisionByZero {
+            span: Span { line: 5, column: 20, len: 3 },
+        }.into()));
+    }
+
+    #[test]
+    fn invalid() {
+        let messages = |err: Error| match err {
+            Error::Invalid(diags) => diags.iter().map(|d| d.to_string()).collect::<Vec<_>>(),
+            err => panic!("Not invalid: {}", err),
+        };
+        let err = Interpreter::new("program Bad; begin x := 1 end.").report(io::sink()).run().unwrap_err();
+        assert_eq!(messages(err), vec!["1:20: error: variable \"X\" not defined"]);
+        let err = Interpreter::new("program Bad; begin end").report(io::sink()).compile().unwrap_err();
+        assert_eq!(messages(err), vec!["1:23: error: Expect DOT, got None"]);
+        let err = Interpreter::new("program Natives; begin Log(1) end.")
+            .report(io::sink())
+            .register_fn("Log", &[Type::Integer], None, |_| VariableValue::None)
+            .lower()
+            .unwrap_err();
+        assert_eq!(messages(err), vec!["1:24: error: IR can not call native procedures"]);
+    }
+
+    #[test]
+    fn nested_procedures() {
+        let (ctx, _) = Interpreter::new(r#"
+        program Nested;
+        var a, r : integer;
+        procedure P1(n : integer);
+            var a : integer;
+            procedure P2;
+            begin
+                a := a + n;
+                r := r + 1
+            end;
+            procedure P3;
+                var a : real;
+            begin
+                P2
+            end;
+        begin
+            a := 10;
+            P3;
+            if n > 0 then P1(n - 1);
+            r := r * 2 + a
+        end;
+        begin
+            r := 0;
+            a := 7;
+            P1(2)
+        end."#).report(io::sink()).exec();
+        assert_eq!(ctx.get_var("a"), Some(VariableValue::Integer(7)));
+        assert_eq!(ctx.get_var("r"), Some(VariableValue::Integer(98)));
+    }
+
+    #[test]
+    fn forward() {
+        let (ctx, _) = Interpreter::new(r#"
+        program Forward;
+        var even : integer;
+        procedure IsOdd(n : integer); forward;
+        procedure IsEven(n : integer);
+        begin
+            if n = 0 then even := 1 else IsOdd(n - 1)
+        end;
+        procedure IsOdd(n : integer);
+        begin
+            if n = 0 then even := 0 else IsEven(n - 1)
+        end;
+        begin
+            IsEven(7)
+        end."#).report(io::sink()).exec();
+        assert_eq!(ctx.get_var("even"), Some(VariableValue::Integer(0)));
+    }
+
+    #[test]
+    fn trace() {
+        let trace = Capture::default();
+        Interpreter::new(r#"
+        program Trace;
+        var i : integer;
+        procedure Inc(n : integer);
+        begin
+            i := i + n
+        end;
+        begin
+            i := 0;
+            while i < 2 do Inc(1);
+            if i = 2 then writeln(i)
+        end."#)
+            .report(io::sink())
+            .output(io::sink())
+            .trace(trace.clone())
+            .exec();
+        assert_eq!(trace.text(), "\
+9:13: I := 0
+10:13: WHILE TRUE
+10:28: call INC
+6:13: I := 1
+10:13: WHILE TRUE
+10:28: call INC
+6:13: I := 2
+10:13: WHILE FALSE
+11:13: IF TRUE
+11:27: WRITELN
+");
+    }
+
+    #[test]
+    fn natives() {
+        let log = Rc::new(std::cell::RefCell::new(Vec::new()));
+        let logged = log.clone();
+        let (ctx, _) = Interpreter::new(r#"
+        program Natives;
+        var x : real;
+        procedure Log(n : integer); begin x := n end;
+        procedure P;
+        begin
+            Log(2)
+        end;
+        begin
+            P;
+            LogReal(3, Half(x) + 1)
+        end."#)
+            .report(io::sink())
+            .register_fn("LogReal", &[Type::Integer, Type::Real], None, move |args| {
+                logged.borrow_mut().push(args.to_vec());
+                VariableValue::None
+            })
+            .register_fn("half", &[Type::Real], Some(Type::Real), |args| {
+                VariableValue::Real(f64::try_from(args[0]).unwrap() / 2.0)
+            })
+            // hidden by the declared procedure
+            .register_fn("log", &[Type::Integer], None, |_| unreachable!())
+            .exec();
+        assert_eq!(ctx.get_var("x"), Some(VariableValue::Real(2.0)));
+        assert_eq!(*log.borrow(), vec![vec![VariableValue::Integer(3), VariableValue::Real(2.0)]]);
+    }
+
+    #[test]
+    fn native_result() {
+        let run = |res: VariableValue| Interpreter::new(r#"
+        program Natives;
+        var n : integer;
+        begin
+            n := Answer(1)
+        end."#)
+            .report(io::sink())
+            .register_fn("Answer", &[Type::Integer], Some(Type::Integer), move |_| res)
+            .run()
+            .err();
+        assert_eq!(run(VariableValue::Integer(42)), None);
+        let err = run(VariableValue::Real(4.2));
+        assert_eq!(err, Some(RuntimeError::NativeResult {
+            name: "ANSWER".into(),
+            expected: Type::Integer,
+            got: VariableValue::Real(4.2),
+            span: Span { line: 5, column: 18, len: 6 },
+        }.into()));
+        assert_eq!(err.unwrap().to_string(),
+            "5:18: runtime error: native function \"ANSWER\" must return Integer, got Real(4.2)");
+        assert!(run(VariableValue::None).is_some());
+        assert!(run(VariableValue::Boolean(true)).is_some());
+    }
+
+    #[test]
+    fn native_panic() {
+        let err = Interpreter::new("begin Fail end.")
+            .report(io::sink())
+            .register_fn("Fail", &[], None, |_| panic!("no luck"))
+            .run()
+            .err();
+        assert_eq!(err.unwrap().to_string(),
+            "1:7: runtime error: native function \"FAIL\" panicked: no luck");
+    }
+
+    #[test]
+    fn instance() {
+        let mut instance = Interpreter::new(r#"
+        program Counter;
+        var count : integer;
+            total : real;
+        procedure Add(n : integer; weight : real);
+            procedure Step;
+            begin
+                count := count + 1
+            end;
+        begin
+            Step;
+            total := total + n * weight
+        end;
+        begin
+            count := 100
+        end."#)
+            .report(io::sink())
+            // each call starts with fresh limits
+            .limits(Limits::new().steps(30))
+            .instantiate()
+            .unwrap();
+        assert_eq!(instance.globals().get::<i32>("count"), Some(0));
+        instance.call("add", &[2.into(), 0.5.into()]).unwrap();
+        instance.call("Add", &[3.into(), 1.into()]).unwrap();
+        let globals = instance.globals();
+        assert_eq!((globals.get::<i32>("count"), globals.get::<f64>("total")), (Some(2), Some(4.0)));
+        assert_eq!(globals.get::<i32>("total"), None);
+        assert!(instance.globals_mut().set("count", 10));
+        assert!(!instance.globals_mut().set("count", 1.5));
+        assert!(!instance.globals_mut().set("missing", 1));
+        instance.call("add", &[0.into(), 0.into()]).unwrap();
+        assert_eq!(instance.globals().get::<i32>("count"), Some(11));
+        instance.run_main().unwrap();
+        assert_eq!(instance.globals().get::<i32>("count"), Some(100));
+
+        assert_eq!(instance.call("Step", &[]), Err(CallError::NotDefined { name: "STEP".into() }));
+        assert_eq!(instance.call("Add", &[1.into()]).unwrap_err().to_string(),
+            "procedure \"ADD\" expects 2 arguments, got 1");
+        assert_eq!(instance.call("Add", &[1.5.into(), 1.into()]).unwrap_err().to_string(),
+            "argument 1 of procedure \"ADD\" must be Integer, got Real(1.5)");
+
+        let err = Interpreter::new("program Bad; var n : integer; begin n := m end.")
+            .report(io::sink())
+            .instantiate()
+            .err();
+        assert_eq!(err, Some(Error::Invalid(vec![
+            Diagnostic::error(Span { line: 1, column: 42, len: 1 }, "variable \"M\" not defined"),
+        ])));
+    }
 }
@@ -0,0 +1,673 @@
+use crate::tokens::*;
+use crate::ast::*;
+use crate::symbols::*;
+
+use std::collections::HashMap;
+use std::fmt;
+
+pub type BlockId = usize;
+
+/// Destination of an instruction
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Place {
+    /// Index in `Function::vars`
+    Var(usize),
+    /// Compiler generated temporary
+    Temp(u32),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Operand {
+    Const(VariableValue),
+    Place(Place),
+}
+
+/// Three-address instruction
+#[derive(Debug, Clone, PartialEq)]
+pub enum Inst {
+    /// `dst = src`, assignment converts to the type of a variable
+    Copy {
+        dst: Place,
+        src: Operand,
+    },
+    Binary {
+        dst: Place,
+        op: BinOp,
+        left: Operand,
+        right: Operand,
+        span: Span,
+    },
+    Neg {
+        dst: Place,
+        src: Operand,
+        span: Span,
+    },
+    /// Call function of the module
+    Call {
+        func: usize,
+        args: Vec<Operand>,
+        span: Span,
+    },
+    Writeln {
+        args: Vec<Operand>,
+    },
+    /// Value coming from the predecessor the control arrived from,
+    /// phis are at the start of a block
+    Phi {
+        dst: Place,
+        args: Vec<(BlockId, Operand)>,
+    },
+    /// `dst = src` converted like an assignment to a variable of `typ`
+    Cast {
+        dst: Place,
+        src: Operand,
+        typ: Type,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Terminator {
+    Jump(BlockId),
+    /// Go to `then` when `cond` is true
+    Branch {
+        cond: Operand,
+        then: BlockId,
+        otherwise: BlockId,
+    },
+    Return,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BasicBlock {
+    pub insts: Vec<Inst>,
+    pub term: Terminator,
+}
+
+/// Variable used by a function, its own or of an enclosing scope
+#[derive(Debug, Clone, PartialEq)]
+pub struct VarInfo {
+    pub name: String,
+    pub typ: Type,
+    /// Location of the declaration
+    pub decl: Span,
+    /// Nesting level of the declaring scope
+    pub level: u32,
+}
+
+/// Control-flow graph of the program or a procedure,
+/// the entry is the block 0
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    pub name: String,
+    /// Nesting level of the body, the program is 1
+    pub level: u32,
+    /// Parameters are the first `params` variables
+    pub params: usize,
+    pub vars: Vec<VarInfo>,
+    pub temps: u32,
+    pub blocks: Vec<BasicBlock>,
+}
+
+/// Lowered program, the function 0 is the program body
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct IrModule {
+    pub functions: Vec<Function>,
+}
+
+impl Inst {
+    pub fn dst(&self) -> Option<Place> {
+        match self {
+            Inst::Copy{dst, ..} | Inst::Binary{dst, ..} | Inst::Neg{dst, ..} |
+            Inst::Phi{dst, ..} | Inst::Cast{dst, ..} => Some(*dst),
+            Inst::Call{..} | Inst::Writeln{..} => None,
+        }
+    }
+
+    pub fn dst_mut(&mut self) -> Option<&mut Place> {
+        match self {
+            Inst::Copy{dst, ..} | Inst::Binary{dst, ..} | Inst::Neg{dst, ..} |
+            Inst::Phi{dst, ..} | Inst::Cast{dst, ..} => Some(dst),
+            Inst::Call{..} | Inst::Writeln{..} => None,
+        }
+    }
+
+    pub fn operands(&self) -> Vec<Operand> {
+        match self {
+            Inst::Copy{src, ..} | Inst::Neg{src, ..} | Inst::Cast{src, ..} => vec![*src],
+            Inst::Binary{left, right, ..} => vec![*left, *right],
+            Inst::Call{args, ..} | Inst::Writeln{args} => args.clone(),
+            Inst::Phi{args, ..} => args.iter().map(|(_, arg)| *arg).collect(),
+        }
+    }
+
+    pub fn operands_mut(&mut self) -> Vec<&mut Operand> {
+        match self {
+            Inst::Copy{src, ..} | Inst::Neg{src, ..} | Inst::Cast{src, ..} => vec![src],
+            Inst::Binary{left, right, ..} => vec![left, right],
+            Inst::Call{args, ..} | Inst::Writeln{args} => args.iter_mut().collect(),
+            Inst::Phi{args, ..} => args.iter_mut().map(|(_, arg)| arg).collect(),
+        }
+    }
+
+    pub fn is_phi(&self) -> bool {
+        matches!(self, Inst::Phi{..})
+    }
+}
+
+impl Terminator {
+    pub fn successors(&self) -> Vec<BlockId> {
+        match self {
+            Terminator::Jump(target) => vec![*target],
+            Terminator::Branch{then, otherwise, ..} => vec![*then, *otherwise],
+            Terminator::Return => Vec::new(),
+        }
+    }
+}
+
+impl Function {
+    /// Variable declared in this function
+    pub fn is_local(&self, var: usize) -> bool {
+        self.vars[var].level == self.level
+    }
+
+    /// Drop blocks not reachable from the entry and phi arguments
+    /// of edges which no longer exist
+    pub fn remove_unreachable(&mut self) {
+        let mut reachable = self.reverse_postorder();
+        reachable.sort_unstable();
+        let mut index = vec![None; self.blocks.len()];
+        for (new, old) in reachable.iter().enumerate() {
+            index[*old] = Some(new);
+        }
+        let blocks = std::mem::take(&mut self.blocks);
+        self.blocks = blocks.into_iter().enumerate()
+            .filter(|(id, _)| index[*id].is_some())
+            .map(|(_, block)| block)
+            .collect();
+        let renumber = |id: &mut BlockId| *id = index[*id].unwrap();
+        for block in &mut self.blocks {
+            match &mut block.term {
+                Terminator::Jump(target) => renumber(target),
+                Terminator::Branch{then, otherwise, ..} => {
+                    renumber(then);
+                    renumber(otherwise);
+                },
+                Terminator::Return => {},
+            }
+        }
+        let preds = self.predecessors();
+        for (id, block) in self.blocks.iter_mut().enumerate() {
+            for inst in &mut block.insts {
+                if let Inst::Phi{args, ..} = inst {
+                    args.retain(|(pred, _)| index[*pred].is_some());
+                    for (pred, _) in args.iter_mut() {
+                        renumber(pred);
+                    }
+                    args.retain(|(pred, _)| preds[id].contains(pred));
+                }
+            }
+        }
+    }
+
+    pub fn predecessors(&self) -> Vec<Vec<BlockId>> {
+        let mut preds = vec![Vec::new(); self.blocks.len()];
+        for (id, block) in self.blocks.iter().enumerate() {
+            for succ in block.term.successors() {
+                preds[succ].push(id);
+            }
+        }
+        preds
+    }
+
+    /// Blocks in reverse postorder from the entry, unreachable ones
+    /// are left out
+    pub fn reverse_postorder(&self) -> Vec<BlockId> {
+        let mut visited = vec![false; self.blocks.len()];
+        let mut order = Vec::new();
+        // iterative DFS, the flag marks a block whose successors are done
+        let mut stack = vec![(0, false)];
+        while let Some((id, done)) = stack.pop() {
+            if done {
+                order.push(id);
+                continue;
+            }
+            if visited[id] {
+                continue;
+            }
+            visited[id] = true;
+            stack.push((id, true));
+            for succ in self.blocks[id].term.successors().into_iter().rev() {
+                if !visited[succ] {
+                    stack.push((succ, false));
+                }
+            }
+        }
+        order.reverse();
+        order
+    }
+
+    fn place(&self, place: Place) -> String {
+        match place {
+            Place::Var(var) => self.vars[var].name.to_string(),
+            Place::Temp(temp) => format!("t{}", temp),
+        }
+    }
+
+    fn operand(&self, operand: Operand) -> String {
+        match operand {
+            Operand::Const(VariableValue::Real(n)) => format!("{:?}", n),
+            Operand::Const(value) => value.to_string(),
+            Operand::Place(place) => self.place(place),
+        }
+    }
+
+    fn inst(&self, inst: &Inst, module: &IrModule) -> String {
+        let list = |args: &[Operand]| args.iter()
+            .map(|arg| self.operand(*arg))
+            .collect::<Vec<_>>()
+            .join(", ");
+        match inst {
+            Inst::Copy{dst, src} => format!("{} = {}", self.place(*dst), self.operand(*src)),
+            Inst::Binary{dst, op, left, right, ..} => format!("{} = {} {} {}",
+                self.place(*dst), self.operand(*left), operator(*op), self.operand(*right)),
+            Inst::Neg{dst, src, ..} => format!("{} = -{}", self.place(*dst), self.operand(*src)),
+            Inst::Call{func, args, ..} => format!("call {}({})", module.functions[*func].name, list(args)),
+            Inst::Writeln{args} => format!("writeln({})", list(args)),
+            Inst::Phi{dst, args} => {
+                let args: Vec<_> = args.iter()
+                    .map(|(pred, arg)| format!("bb{}: {}", pred, self.operand(*arg)))
+                    .collect();
+                format!("{} = phi({})", self.place(*dst), args.join(", "))
+            },
+            Inst::Cast{dst, src, typ} => format!("{} = {}({})",
+                self.place(*dst), format!("{:?}", typ).to_lowercase(), self.operand(*src)),
+        }
+    }
+
+    fn term(&self, term: &Terminator) -> String {
+        match term {
+            Terminator::Jump(target) => format!("jump bb{}", target),
+            Terminator::Branch{cond, then, otherwise} =>
+                format!("branch {}, bb{}, bb{}", self.operand(*cond), then, otherwise),
+            Terminator::Return => "return".to_string(),
+        }
+    }
+}
+
+fn operator(op: BinOp) -> &'static str {
+    match op {
+        BinOp::Add => "+",
+        BinOp::Sub => "-",
+        BinOp::Mul => "*",
+        BinOp::Div => "/",
+        BinOp::IntDiv => "DIV",
+        BinOp::Eq => "=",
+        BinOp::Ne => "<>",
+        BinOp::Lt => "<",
+        BinOp::Le => "<=",
+        BinOp::Gt => ">",
+        BinOp::Ge => ">=",
+    }
+}
+
+/// Listing of all functions
+impl fmt::Display for IrModule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, func) in self.functions.iter().enumerate() {
+            if index > 0 {
+                writeln!(f)?;
+            }
+            let vars: Vec<_> = func.vars.iter().enumerate()
+                .map(|(var, info)| {
+                    let kind = if var < func.params { "param " }
+                        else if func.is_local(var) { "" }
+                        else { "outer " };
+                    format!("{}{} : {:?}", kind, info.name, info.typ)
+                })
+                .collect();
+            writeln!(f, "function {} (level {})", func.name, func.level)?;
+            if !vars.is_empty() {
+                writeln!(f, "    vars: {}", vars.join(", "))?;
+            }
+            for (id, block) in func.blocks.iter().enumerate() {
+                writeln!(f, "bb{}:", id)?;
+                for inst in &block.insts {
+                    writeln!(f, "    {}", func.inst(inst, self))?;
+                }
+                writeln!(f, "    {}", func.term(&block.term))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl IrModule {
+    /// Graphviz digraph with a cluster per function
+    pub fn to_dot(&self) -> String {
+        let escape = |text: &str| text
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('<', "\\<")
+            .replace('>', "\\>")
+            .replace('{', "\\{")
+            .replace('}', "\\}")
+            .replace('|', "\\|");
+        let mut dot = String::from("digraph cfg {\n    node [shape=record, fontname=monospace];\n");
+        for (index, func) in self.functions.iter().enumerate() {
+            dot.push_str(&format!("    subgraph cluster_{} {{\n        label=\"{}\";\n", index, func.name));
+            for (id, block) in func.blocks.iter().enumerate() {
+                let mut label = format!("bb{}:\\l", id);
+                for inst in &block.insts {
+                    label.push_str(&escape(&func.inst(inst, self)));
+                    label.push_str("\\l");
+                }
+                label.push_str(&escape(&func.term(&block.term)));
+                label.push_str("\\l");
+                dot.push_str(&format!("        f{}_bb{} [label=\"{{{}}}\"];\n", index, id, label));
+            }
+            for (id, block) in func.blocks.iter().enumerate() {
+                let succs = block.term.successors();
+                for (n, succ) in succs.iter().enumerate() {
+                    let attr = match (succs.len(), n) {
+                        (2, 0) => " [label=T]",
+                        (2, _) => " [label=F]",
+                        _ => "",
+                    };
+                    dot.push_str(&format!("        f{}_bb{} -> f{}_bb{}{};\n", index, id, index, succ, attr));
+                }
+            }
+            dot.push_str("    }\n");
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+/// Lower the analyzed tree into three-address code, one control-flow
+/// graph per procedure with a body
+pub struct Lowering<'a> {
+    resolutions: &'a HashMap<Span, Resolution>,
+    module: IrModule,
+    /// Function indices keyed by location of the procedure declaration
+    functions: HashMap<Span, usize>,
+    /// Function being lowered and its block receiving instructions
+    current: usize,
+    block: BlockId,
+}
+
+impl<'a> Lowering<'a> {
+    pub fn new(resolutions: &'a HashMap<Span, Resolution>) -> Self {
+        Lowering {
+            resolutions,
+            module: IrModule::default(),
+            functions: HashMap::new(),
+            current: 0,
+            block: 0,
+        }
+    }
+
+    pub fn lower(mut self, program: &Program) -> IrModule {
+        self.module.functions.push(new_function(&program.name.name, 1));
+        // number the functions first, a call may precede the body
+        let procedures = procedures(program);
+        for body in &procedures {
+            self.functions.insert(body.decl.name.span, self.module.functions.len());
+            self.module.functions.push(new_function(&body.decl.name.name, body.level));
+        }
+        self.body(0, &[], &program.block);
+        for (index, body) in procedures.iter().enumerate() {
+            self.body(index + 1, &body.decl.params, body.block);
+        }
+        self.module
+    }
+
+    fn body(&mut self, func: usize, params: &[Param], block: &Block) {
+        self.current = func;
+        self.block = self.new_block();
+        let level = self.function().level;
+        for param in params {
+            self.define(&param.name, param.typ, level);
+        }
+        self.function().params = params.len();
+        for decl in &block.decls {
+            if let Decl::Var(var) = decl {
+                self.define(&var.name, var.typ, level);
+            }
+        }
+        for stmt in &block.body {
+            self.statement(stmt);
+        }
+        self.finish(Terminator::Return);
+    }
+
+    fn function(&mut self) -> &mut Function {
+        &mut self.module.functions[self.current]
+    }
+
+    fn define(&mut self, name: &Ident, typ: Type, level: u32) -> usize {
+        let vars = &mut self.function().vars;
+        vars.push(VarInfo {
+            name: name.name.to_string(),
+            typ,
+            decl: name.span,
+            level,
+        });
+        vars.len() - 1
+    }
+
+    /// Variable of the function, outer ones are added on first use
+    fn var(&mut self, ident: &Ident) -> Place {
+        let resolution = self.resolutions[&ident.span];
+        let found = self.function().vars.iter().position(|var| var.decl == resolution.decl);
+        let var = match found {
+            Some(var) => var,
+            None => {
+                let typ = resolution.typ.expect("variable type");
+                let name = Ident { name: ident.name.to_string(), span: resolution.decl };
+                self.define(&name, typ, resolution.level)
+            },
+        };
+        Place::Var(var)
+    }
+
+    fn temp(&mut self) -> Place {
+        let func = self.function();
+        func.temps += 1;
+        Place::Temp(func.temps - 1)
+    }
+
+    /// Empty block, terminated later by `finish`
+    fn new_block(&mut self) -> BlockId {
+        let blocks = &mut self.function().blocks;
+        blocks.push(BasicBlock { insts: Vec::new(), term: Terminator::Return });
+        blocks.len() - 1
+    }
+
+    fn emit(&mut self, inst: Inst) {
+        let block = self.block;
+        self.function().blocks[block].insts.push(inst);
+    }
+
+    fn finish(&mut self, term: Terminator) {
+        let block = self.block;
+        self.function().blocks[block].term = term;
+    }
+
+    fn statement(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Compound{body, ..} => {
+                for stmt in body {
+                    self.statement(stmt);
+                }
+            },
+            Stmt::Assign{target, value} => {
+                let dst = self.var(target);
+                self.expr_into(dst, value);
+            },
+            Stmt::Call{name, args} => {
+                let args = args.iter().map(|arg| self.expr(arg)).collect();
+                let resolution = self.resolutions[&name.span];
+                let func = self.functions[&resolution.decl];
+                self.emit(Inst::Call { func, args, span: name.span });
+            },
+            Stmt::If{cond, then_branch, else_branch, ..} => {
+                let cond = self.expr(cond);
+                let then = self.new_block();
+                let else_block = else_branch.as_ref().map(|_| self.new_block());
+                let end = self.new_block();
+                let otherwise = else_block.unwrap_or(end);
+                self.finish(Terminator::Branch { cond, then, otherwise });
+                self.block = then;
+                self.statement(then_branch);
+                self.finish(Terminator::Jump(end));
+                if let Some(else_branch) = else_branch {
+                    self.block = otherwise;
+                    self.statement(else_branch);
+                    self.finish(Terminator::Jump(end));
+                }
+                self.block = end;
+            },
+            Stmt::While{cond, body, ..} => {
+                let header = self.new_block();
+                let body_block = self.new_block();
+                let end = self.new_block();
+                self.finish(Terminator::Jump(header));
+                self.block = header;
+                let cond = self.expr(cond);
+                self.finish(Terminator::Branch { cond, then: body_block, otherwise: end });
+                self.block = body_block;
+                self.statement(body);
+                self.finish(Terminator::Jump(header));
+                self.block = end;
+            },
+            Stmt::Writeln{args, ..} => {
+                let args = args.iter().map(|arg| self.expr(arg)).collect();
+                self.emit(Inst::Writeln { args });
+            },
+            Stmt::NoOp => {},
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) -> Operand {
+        match expr {
+            Expr::Num{value, ..} => Operand::Const(*value),
+            Expr::Var(var) => Operand::Place(self.var(var)),
+            Expr::UnaryOp{op: UnaryOp::Plus, operand, ..} => self.expr(operand),
+            Expr::Call{name, ..} => unreachable!("native function {}", name.name),
+            _ => {
+                let dst = self.temp();
+                self.expr_into(dst, expr);
+                Operand::Place(dst)
+            },
+        }
+    }
+
+    /// Compute the expression straight into `dst`
+    fn expr_into(&mut self, dst: Place, expr: &Expr) {
+        match expr {
+            Expr::BinOp{op, left, right, span} => {
+                let left = self.expr(left);
+                let right = self.expr(right);
+                self.emit(Inst::Binary { dst, op: *op, left, right, span: *span });
+            },
+            Expr::UnaryOp{op: UnaryOp::Minus, operand, span} => {
+                let src = self.expr(operand);
+                self.emit(Inst::Neg { dst, src, span: *span });
+            },
+            _ => {
+                let src = self.expr(expr);
+                self.emit(Inst::Copy { dst, src });
+            },
+        }
+    }
+}
+
+fn new_function(name: &str, level: u32) -> Function {
+    Function {
+        name: name.to_string(),
+        level,
+        params: 0,
+        vars: Vec::new(),
+        temps: 0,
+        blocks: Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interpreter::Interpreter;
+    use std::io;
+
+    const TEXT: &str = r#"
+    program Sum;
+    var i, sum : integer;
+        avg : real;
+    procedure Add(n : integer);
+    begin
+        if n > 2 then sum := sum + n * 2 else sum := sum + n
+    end;
+    begin
+        i := 0;
+        sum := 0;
+        while i < 5 do
+        begin
+            Add(i);
+            i := i + 1
+        end;
+        avg := sum / i;
+        writeln(sum, -avg)
+    end."#;
+
+    #[test]
+    fn listing() {
+        let module = Interpreter::new(TEXT).report(io::sink()).lower().unwrap();
+        assert_eq!(module.to_string(), "\
+function SUM (level 1)
+    vars: I : Integer, SUM : Integer, AVG : Real
+bb0:
+    I = 0
+    SUM = 0
+    jump bb1
+bb1:
+    t0 = I < 5
+    branch t0, bb2, bb3
+bb2:
+    call ADD(I)
+    I = I + 1
+    jump bb1
+bb3:
+    AVG = SUM / I
+    t1 = -AVG
+    writeln(SUM, t1)
+    return
+
+function ADD (level 2)
+    vars: param N : Integer, outer SUM : Integer
+bb0:
+    t0 = N > 2
+    branch t0, bb1, bb2
+bb1:
+    t1 = N * 2
+    SUM = SUM + t1
+    jump bb3
+bb2:
+    SUM = SUM + N
+    jump bb3
+bb3:
+    return
+");
+    }
+
+    #[test]
+    fn cfg() {
+        let module = Interpreter::new(TEXT).report(io::sink()).lower().unwrap();
+        let main = &module.functions[0];
+        assert_eq!(main.predecessors(), vec![vec![], vec![0, 2], vec![1], vec![1]]);
+        assert_eq!(main.reverse_postorder(), vec![0, 1, 3, 2]);
+        let dot = module.to_dot();
+        assert!(dot.starts_with("digraph cfg {"));
+        assert!(dot.contains("f0_bb1 -> f0_bb2 [label=T];"));
+        assert!(dot.contains("f0_bb1 -> f0_bb3 [label=F];"));
+        assert!(dot.contains("f1_bb0 [label=\"{bb0:\\lt0 = N \\> 2\\lbranch t0, bb1, bb2\\l}\"];"));
+    }
+}
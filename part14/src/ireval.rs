@@ -0,0 +1,200 @@
+use crate::ast::*;
+use crate::ir::*;
+use crate::interpreter::{Context, RuntimeError};
+
+use std::io::{self, Write};
+
+/// Activation of an IR function
+struct IrFrame {
+    func: usize,
+    /// Own variables, slots of outer ones are unused
+    vars: Vec<VariableValue>,
+    temps: Vec<VariableValue>,
+    /// Index of the frame of the lexically enclosing scope
+    static_link: Option<usize>,
+}
+
+/// Executes an `IrModule`, SSA form included, with the runtime
+/// semantics of the tree-walking `Interpreter`
+pub struct IrInterpreter<'a> {
+    module: &'a IrModule,
+    /// Destination of `WRITELN`
+    output: Box<dyn Write + 'a>,
+    frames: Vec<IrFrame>,
+}
+
+impl<'a> IrInterpreter<'a> {
+    pub fn new(module: &'a IrModule) -> Self {
+        IrInterpreter {
+            module,
+            output: Box::new(io::stdout()),
+            frames: Vec::new(),
+        }
+    }
+
+    /// Send program output to `out` instead of stdout
+    pub fn output(mut self, out: impl Write + 'a) -> Self {
+        self.output = Box::new(out);
+        self
+    }
+
+    /// Run the program, the context holds the global variables
+    pub fn run(mut self) -> Result<Context, RuntimeError> {
+        let frame = self.call(0, Vec::new(), None)?;
+        let main = &self.module.functions[0];
+        let mut context = Context::default();
+        for (var, value) in frame.vars.into_iter().enumerate() {
+            if main.is_local(var) {
+                context.variables.insert(main.vars[var].name.to_string(), value);
+            }
+        }
+        Ok(context)
+    }
+
+    fn call(&mut self, index: usize, args: Vec<VariableValue>, static_link: Option<usize>)
+        -> Result<IrFrame, RuntimeError>
+    {
+        let module = self.module;
+        let func = &module.functions[index];
+        let mut vars: Vec<_> = func.vars.iter()
+            .map(|var| VariableValue::from(var.typ))
+            .collect();
+        for (var, arg) in vars.iter_mut().zip(args) {
+            var.assign(arg);
+        }
+        self.frames.push(IrFrame {
+            func: index,
+            vars,
+            temps: vec![VariableValue::None; func.temps as usize],
+            static_link,
+        });
+        let res = self.execute(func);
+        let frame = self.frames.pop().unwrap();
+        res.map(|_| frame)
+    }
+
+    fn execute(&mut self, func: &Function) -> Result<(), RuntimeError> {
+        let mut block = 0;
+        let mut prev = None;
+        loop {
+            let insts = &func.blocks[block].insts;
+            // phis read their arguments before any of them is written
+            let phis: Vec<_> = insts.iter()
+                .filter_map(|inst| match inst {
+                    Inst::Phi{dst, args} => {
+                        let (_, arg) = args.iter().find(|(pred, _)| Some(*pred) == prev).unwrap();
+                        Some((*dst, self.operand(*arg)))
+                    },
+                    _ => None,
+                })
+                .collect();
+            for (dst, value) in phis {
+                self.write(dst, value);
+            }
+            for inst in insts.iter().filter(|inst| !inst.is_phi()) {
+                self.inst(inst)?;
+            }
+            prev = Some(block);
+            block = match &func.blocks[block].term {
+                Terminator::Jump(target) => *target,
+                Terminator::Branch{cond, then, otherwise} => match self.operand(*cond) {
+                    VariableValue::Boolean(true) => *then,
+                    VariableValue::Boolean(false) => *otherwise,
+                    _ => unreachable!()
+                },
+                Terminator::Return => return Ok(()),
+            };
+        }
+    }
+
+    fn inst(&mut self, inst: &Inst) -> Result<(), RuntimeError> {
+        match inst {
+            Inst::Copy{dst, src} => {
+                let value = self.operand(*src);
+                self.write(*dst, value);
+            },
+            Inst::Binary{dst, op, left, right, span} => {
+                let left = self.operand(*left);
+                let right = self.operand(*right);
+                if op.divides_by_zero(right) {
+                    return Err(RuntimeError::DivisionByZero { span: *span });
+                }
+                let value = op.apply(left, right)
+                    .ok_or(RuntimeError::IntegerOverflow { span: *span })?;
+                self.write(*dst, value);
+            },
+            Inst::Neg{dst, src, span} => {
+                let value = self.operand(*src).checked_neg()
+                    .ok_or(RuntimeError::IntegerOverflow { span: *span })?;
+                self.write(*dst, value);
+            },
+            Inst::Cast{dst, src, typ} => {
+                let mut value = VariableValue::from(*typ);
+                value.assign(self.operand(*src));
+                self.write(*dst, value);
+            },
+            Inst::Call{func, args, ..} => {
+                let args = args.iter().map(|arg| self.operand(*arg)).collect();
+                // the callee is declared one level above its body
+                let level = self.module.functions[*func].level - 1;
+                let static_link = Some(self.frame_index(level));
+                self.call(*func, args, static_link)?;
+            },
+            Inst::Writeln{args} => {
+                for arg in args {
+                    let value = self.operand(*arg);
+                    write!(self.output, "{}", value).expect("write output");
+                }
+                writeln!(self.output).expect("write output");
+            },
+            Inst::Phi{..} => unreachable!(),
+        }
+        Ok(())
+    }
+
+    /// Index of the frame of the scope at `level`, reached by
+    /// static links from the current frame
+    fn frame_index(&self, level: u32) -> usize {
+        let mut index = self.frames.len() - 1;
+        while self.module.functions[self.frames[index].func].level > level {
+            index = self.frames[index].static_link.unwrap();
+        }
+        index
+    }
+
+    /// Frame and slot of the variable of the current function
+    fn var(&self, var: usize) -> (usize, usize) {
+        let frame = self.frames.len() - 1;
+        let func = &self.module.functions[self.frames[frame].func];
+        if func.is_local(var) {
+            return (frame, var);
+        }
+        let info = &func.vars[var];
+        let frame = self.frame_index(info.level);
+        let owner = &self.module.functions[self.frames[frame].func];
+        let slot = owner.vars.iter().position(|var| var.decl == info.decl).unwrap();
+        (frame, slot)
+    }
+
+    fn operand(&self, operand: Operand) -> VariableValue {
+        match operand {
+            Operand::Const(value) => value,
+            Operand::Place(Place::Temp(temp)) => self.frames.last().unwrap().temps[temp as usize],
+            Operand::Place(Place::Var(var)) => {
+                let (frame, slot) = self.var(var);
+                self.frames[frame].vars[slot]
+            },
+        }
+    }
+
+    /// Temporaries take the value, variables convert it like an assignment
+    fn write(&mut self, place: Place, value: VariableValue) {
+        match place {
+            Place::Temp(temp) => self.frames.last_mut().unwrap().temps[temp as usize] = value,
+            Place::Var(var) => {
+                let (frame, slot) = self.var(var);
+                self.frames[frame].vars[slot].assign(value);
+            },
+        }
+    }
+}
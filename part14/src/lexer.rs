@@ -1,54 +1,58 @@
 use crate::tokens::*;
 
 pub struct Lexer {
-    text: String,
+    text: Vec<char>,
+    /// Index of the current char in `text`
     pub pos: usize,
+    line: usize,
+    column: usize,
+    /// Location of the last returned token
+    pub span: Span,
+    /// Directives met so far
+    pub directives: Vec<Directive>,
+    /// Comments met so far
+    pub comments: Vec<Comment>,
 }
 
 impl Lexer {
     pub fn new<S: Into<String>>(text: S) -> Lexer {
         Lexer {
-            text: text.into(),
+            text: text.into().chars().collect(),
             pos: 0,
+            line: 1,
+            column: 1,
+            span: Span::default(),
+            directives: Vec::new(),
+            comments: Vec::new(),
         }
     }
 
     fn get_char(&self) -> Option<char> {
-        if self.pos > self.text.len() - 1 {
-            None
-        }
-        else {
-            let text: Vec<char> = self.text.chars().collect();
-            Some(text[self.pos])
-        }
+        self.text.get(self.pos).copied()
     }
 
     fn peek(&self) -> Option<char> {
-        let peek_pos = self.pos + 1;
-        if peek_pos > self.text.len() - 1 {
-            None
-        }
-        else {
-            let text: Vec<char> = self.text.chars().collect();
-            Some(text[peek_pos])
-        }
+        self.text.get(self.pos + 1).copied()
     }
 
-    pub fn rest(&self) -> String {
-        if self.pos > self.text.len() - 1 {
-            "Empty".to_string()
+    /// Move to the next char, keeping track of line and column
+    fn advance(&mut self) {
+        if self.get_char() == Some('\n') {
+            self.line += 1;
+            self.column = 1;
         }
         else {
-            self.text.as_str()[self.pos..].to_string()
+            self.column += 1;
         }
+        self.pos += 1;
     }
 
     fn parse_number(&mut self) -> Option<Token> {
         let mut res = String::new();
         while let Some(c) = self.get_char() {
-            if c.is_digit(10) || c == '.' {
+            if c.is_ascii_digit() || c == '.' {
                 res.push(c);
-                self.pos += 1;
+                self.advance();
                 continue;
             }
             else {
@@ -74,7 +78,7 @@ impl Lexer {
         while let Some(c) = self.get_char() {
             if c.is_alphanumeric() {
                 res.push(c);
-                self.pos += 1;
+                self.advance();
                 continue;
             }
             else {
@@ -84,90 +88,102 @@ impl Lexer {
         res
     }
 
-    pub fn get_next_token(&mut self) -> Option<Token> {
+    /// Skip whitespaces and `{ ... }` comments
+    fn skip_trivia(&mut self) {
         while let Some(c) = self.get_char() {
-
-            // println!("{:02X}", c as u8);
-
             if c.is_whitespace() {
-                self.pos += 1;
+                self.advance();
                 continue;
             }
 
             if c == '{' {
+                let span = Span { line: self.line, column: self.column, len: 0 };
+                let mut comment = String::new();
                 while let Some(c) = self.get_char() {
-                    self.pos += 1;
+                    self.advance();
                     if c == '}' {
                         break;
                     }
+                    comment.push(c);
                 };
+                let len = comment.chars().count() + 1;
+                self.comments.push(Comment {
+                    span: Span { len, ..span },
+                    text: format!("{}}}", comment),
+                });
+                if let Some(directive) = comment.strip_prefix("{$") {
+                    let mut words = directive.split_whitespace()
+                        .map(|w| w.to_ascii_uppercase());
+                    if let Some(name) = words.next() {
+                        let span = Span { len: comment.len() + 1, ..span };
+                        let args = words.collect();
+                        self.directives.push(Directive { span, name, args });
+                    }
+                }
                 continue;
             }
 
-            if c.is_alphabetic() {
-                let id = self.parse_id();
-                return Some(Token::get_token(&id));
-            }
-
-            if c.is_digit(10) {
-                return self.parse_number();
-            }
-
-            if (c == ':') & (self.peek() == Some('=')) {
-                self.pos += 1;
-                self.pos += 1;
-                return Some(Token::ASSIGN);
-            }
-
-            if (c == ':') {
-                self.pos += 1;
-                return Some(Token::COLON);
-            }
+            break;
+        }
+    }
 
-            if c == ',' {
-                self.pos += 1;
-                return Some(Token::COMMA);
-            }
+    pub fn get_next_token(&mut self) -> Option<Token> {
+        self.skip_trivia();
+        let (line, column, start) = (self.line, self.column, self.pos);
+        let tok = self.token();
+        self.span = Span { line, column, len: self.pos - start };
+        tok
+    }
 
-            if c == ';' {
-                self.pos += 1;
-                return Some(Token::SEMI);
-            }
+    fn token(&mut self) -> Option<Token> {
+        let c = self.get_char()?;
 
-            if c == '+' {
-                self.pos += 1;
-                return Some(Token::OpPlus);
-            }
-            if c == '-' {
-                self.pos += 1;
-                return Some(Token::OpMinus);
-            }
-            if c == '*' {
-                self.pos += 1;
-                return Some(Token::OpMul);
-            }
-            if c == '/' {
-                self.pos += 1;
-                return Some(Token::OpDiv);
-            }
+        if c.is_alphabetic() {
+            let id = self.parse_id();
+            return Some(Token::get_token(&id));
+        }
 
-            if c == '(' {
-                self.pos += 1;
-                return Some(Token::LParen);
-            }
-            if c == ')' {
-                self.pos += 1;
-                return Some(Token::RParen);
-            }
+        if c.is_ascii_digit() {
+            return self.parse_number();
+        }
 
-            if c == '.' {
-                self.pos += 1;
-                return Some(Token::DOT);
-            }
+        if (c == ':') & (self.peek() == Some('=')) {
+            self.advance();
+            self.advance();
+            return Some(Token::ASSIGN);
+        }
 
-            break;
+        if c == '<' || c == '>' {
+            let tok = match (c, self.peek()) {
+                ('<', Some('=')) => Token::OpLE,
+                ('<', Some('>')) => Token::OpNE,
+                ('>', Some('=')) => Token::OpGE,
+                ('<', _) => Token::OpLT,
+                (_, _) => Token::OpGT,
+            };
+            if let Token::OpLE | Token::OpNE | Token::OpGE = tok {
+                self.advance();
+            }
+            self.advance();
+            return Some(tok);
         }
-        // Unknown token
-        None
+
+        let tok = match c {
+            '=' => Token::OpEQ,
+            ':' => Token::COLON,
+            ',' => Token::COMMA,
+            ';' => Token::SEMI,
+            '+' => Token::OpPlus,
+            '-' => Token::OpMinus,
+            '*' => Token::OpMul,
+            '/' => Token::OpDiv,
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            '.' => Token::DOT,
+            // Unknown token
+            _ => return None,
+        };
+        self.advance();
+        Some(tok)
     }
 }
@@ -0,0 +1,222 @@
+//! Interpreter of a Pascal subset: lexer, parser, semantic analysis
+//! and a tree-walking interpreter.
+//!
+//! [`parse`] builds the syntax tree, [`analyze`] reports the errors
+//! and warnings of a program and [`run`] runs it, collecting what it
+//! writes. [`Interpreter`] and [`Instance`] give control over the run,
+//! e.g. limits, native procedures, a [`Debugger`] or calling procedures
+//! from Rust.
+//!
+//! ```
+//! let outcome = lsbasi::run(r#"
+//!     program Main;
+//!     var x : integer;
+//!     begin
+//!         x := 6 * 7;
+//!         writeln(x)
+//!     end."#).unwrap();
+//! assert_eq!(outcome.output, "42\n");
+//! assert_eq!(outcome.globals.get::<i32>("x"), Some(42));
+//! ```
+//!
+//! The items exported here follow semantic versioning, the enums
+//! and structs which may grow are `#[non_exhaustive]`.
+#![allow(clippy::upper_case_acronyms)]
+mod tokens;
+mod lexer;
+mod ast;
+mod diagnostics;
+mod parser;
+
+mod symbols;
+mod lints;
+mod optimizer;
+mod analysis;
+mod bytecode;
+mod compiler;
+mod vm;
+mod cgen;
+mod wat;
+mod ir;
+// the IR interpreter only checks the optimizations in tests
+#[cfg(test)]
+mod ireval;
+mod ssa;
+mod passes;
+mod formatter;
+mod debugger;
+mod profiler;
+mod limits;
+mod interpreter;
+// front ends of the `lsbasi` binary
+mod cli;
+mod repl;
+mod lsp;
+mod dap;
+
+pub use crate::tokens::Span;
+pub use crate::ast::{
+    Program, Block, Decl, VarDecl, ProcedureDecl, Param, Ident, Type,
+    Stmt, Expr, BinOp, UnaryOp,
+};
+pub use crate::ast::VariableValue as Value;
+pub use crate::diagnostics::{Diagnostic, Severity, WarningCode};
+pub use crate::interpreter::{
+    Interpreter, Context, Instance, RuntimeError, CallError, ActivationRecord,
+};
+pub use crate::debugger::{Debugger, Pause, Command, Reason};
+pub use crate::limits::Limits;
+pub use crate::profiler::{Profiler, Hits};
+// entry point of the binary, not part of the stable API
+#[doc(hidden)]
+pub use crate::cli::main;
+
+use crate::parser::Parser;
+use crate::analysis::Analysis;
+
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+/// Parse `text`, stopping at the first syntax error
+pub fn parse(text: &str) -> Result<Program, Diagnostic> {
+    Parser::new(text).try_parse()
+}
+
+/// Errors and warnings of `text` sorted by location, `{$WARN}`
+/// directives applied. A syntax error is the only diagnostic.
+pub fn analyze(text: &str) -> Vec<Diagnostic> {
+    Analysis::new(text, |_| {}, false).diagnostics
+}
+
+/// Program run to the end by `run`
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct Outcome {
+    /// Lines written by `WRITELN`
+    pub output: String,
+    pub globals: Context,
+    pub warnings: Vec<Diagnostic>,
+}
+
+/// Why `run` failed
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum Error {
+    /// The program does not compile, the diagnostics of `analyze`
+    Invalid(Vec<Diagnostic>),
+    Runtime(RuntimeError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Invalid(diagnostics) => {
+                let errors: Vec<_> = diagnostics.iter()
+                    .filter(|diag| diag.is_error())
+                    .map(|diag| diag.to_string())
+                    .collect();
+                write!(f, "{}", errors.join("\n"))
+            },
+            Error::Runtime(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<RuntimeError> for Error {
+    fn from(err: RuntimeError) -> Self {
+        Error::Runtime(err)
+    }
+}
+
+/// Analyze and run `text` with the tree-walker and no limits
+pub fn run(text: &str) -> Result<Outcome, Error> {
+    let output = Capture::default();
+    let (globals, _, warnings) = Interpreter::new(text)
+        .output(output.clone())
+        .report(io::sink())
+        .run_with_warnings()?;
+    Ok(Outcome { output: output.text(), globals, warnings })
+}
+
+/// Output of `run`, shared with the interpreter which owns its
+/// writer. The tests capture output with it too.
+#[derive(Clone, Default)]
+pub(crate) struct Capture(Rc<RefCell<Vec<u8>>>);
+
+impl Capture {
+    /// Text written so far
+    pub(crate) fn text(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+
+    /// Text written since the last call
+    #[cfg(test)]
+    pub(crate) fn take(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow_mut().split_off(0)).into_owned()
+    }
+}
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn syntax_error() {
+        let diag = parse("program P; begin x := end.").unwrap_err();
+        assert_eq!(diag.to_string(), "1:23: error: Unexpected END at factor");
+        assert_eq!(analyze("program P; begin x := end."), vec![diag]);
+    }
+
+    #[test]
+    fn invalid() {
+        let text = r#"
+        program Invalid;
+        var a : integer;
+        begin
+            a := b
+        end."#;
+        let diagnostics = analyze(text);
+        assert!(diagnostics.iter().any(|diag| diag.is_error()));
+        assert_eq!(run(text).unwrap_err(), Error::Invalid(diagnostics));
+    }
+
+    #[test]
+    fn runtime_error() {
+        let err = run(r#"
+        program Zero;
+        var a : integer;
+        begin
+            a := 0;
+            writeln(1 div a)
+        end."#).unwrap_err();
+        assert!(matches!(err, Error::Runtime(RuntimeError::DivisionByZero { .. })));
+    }
+
+    #[test]
+    fn warnings() {
+        let outcome = run(r#"
+        program Warn;
+        var a, b : integer;
+        begin
+            a := 1
+        end."#).unwrap();
+        let codes: Vec<_> = outcome.warnings.iter().map(|diag| diag.code).collect();
+        assert_eq!(codes, [Some(WarningCode::UnusedVariable)]);
+        assert_eq!(outcome.output, "");
+        assert_eq!(outcome.globals.get::<i32>("a"), Some(1));
+    }
+}
@@ -0,0 +1,237 @@
+use crate::tokens::*;
+use crate::ast::*;
+use crate::interpreter::RuntimeError;
+
+use std::mem;
+use std::time::{Duration, Instant};
+
+/// Native stack the tree-walker may use when `Limits::stack` is not
+/// set, less than the 2 MiB stack of a spawned thread
+pub const DEFAULT_STACK: usize = 3 << 19;
+
+/// Caps on the resources a program may use, for running untrusted
+/// programs. Everything but the native stack is unlimited by default.
+///
+/// Steps are the statements and expressions evaluated by the
+/// tree-walker and the instructions executed by `Vm`. Memory is
+/// the size of the variables of all active frames.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct Limits {
+    pub steps: Option<u64>,
+    /// Procedure calls active at once
+    pub depth: Option<usize>,
+    /// Bytes
+    pub memory: Option<usize>,
+    pub timeout: Option<Duration>,
+    /// Bytes of native stack the tree-walker may take, `DEFAULT_STACK`
+    /// when `None`. The thread running the program needs some more
+    /// for the native procedures and the output.
+    pub stack: Option<usize>,
+}
+
+impl Limits {
+    pub fn new() -> Limits {
+        Limits::default()
+    }
+
+    pub fn steps(mut self, steps: u64) -> Self {
+        self.steps = Some(steps);
+        self
+    }
+
+    pub fn depth(mut self, depth: usize) -> Self {
+        self.depth = Some(depth);
+        self
+    }
+
+    pub fn memory(mut self, bytes: usize) -> Self {
+        self.memory = Some(bytes);
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn stack(mut self, bytes: usize) -> Self {
+        self.stack = Some(bytes);
+        self
+    }
+}
+
+/// The clock is read once in this many steps
+const CLOCK_STEPS: u64 = 256;
+
+/// Resources used by a running program, checked against the limits
+#[derive(Debug, Default)]
+pub struct Meter {
+    limits: Limits,
+    steps: u64,
+    /// Variables of the active frames
+    variables: usize,
+    deadline: Option<Instant>,
+    /// Stack address when the run started
+    stack_base: usize,
+}
+
+impl Meter {
+    pub fn new(limits: Limits) -> Meter {
+        Meter { limits, ..Meter::default() }
+    }
+
+    /// Start the clock of the timeout
+    pub fn start(&mut self) {
+        self.deadline = self.limits.timeout.map(|timeout| Instant::now() + timeout);
+        self.stack_base = stack_address();
+    }
+
+    /// Count a step at `span`
+    pub fn step(&mut self, span: Span) -> Result<(), RuntimeError> {
+        self.steps += 1;
+        if let Some(limit) = self.limits.steps {
+            if self.steps > limit {
+                return Err(RuntimeError::StepLimit { limit, span });
+            }
+        }
+        if let (Some(deadline), Some(limit)) = (self.deadline, self.limits.timeout) {
+            if self.steps.is_multiple_of(CLOCK_STEPS) && Instant::now() > deadline {
+                return Err(RuntimeError::Timeout { limit, span });
+            }
+        }
+        Ok(())
+    }
+
+    /// Check a call at `span` making `depth` calls active
+    pub fn call(&self, depth: usize, span: Span) -> Result<(), RuntimeError> {
+        match self.limits.depth {
+            Some(limit) if depth > limit => Err(RuntimeError::DepthLimit { limit, span }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check the native stack before the tree-walker recurses into
+    /// the statement or expression at `span`
+    pub fn recurse(&self, span: Span) -> Result<(), RuntimeError> {
+        let used = self.stack_base.saturating_sub(stack_address());
+        if used > self.limits.stack.unwrap_or(DEFAULT_STACK) {
+            return Err(RuntimeError::StackOverflow { span });
+        }
+        Ok(())
+    }
+
+    /// Count `count` new variables declared at `span`
+    pub fn allocate(&mut self, count: usize, span: Span) -> Result<(), RuntimeError> {
+        self.variables += count;
+        match self.limits.memory {
+            Some(limit) if self.variables * mem::size_of::<VariableValue>() > limit =>
+                Err(RuntimeError::MemoryLimit { limit, span }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Forget `count` variables of a returning frame
+    pub fn free(&mut self, count: usize) {
+        self.variables -= count;
+    }
+}
+
+/// Address of a local of the caller, the stack grows down on the
+/// supported targets
+#[inline(never)]
+fn stack_address() -> usize {
+    let local = 0u8;
+    &local as *const u8 as usize
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::Interpreter;
+    use crate::Error;
+    use crate::parser::MAX_NESTING;
+    use std::io;
+
+    const RECURSION: &str = r#"
+    program Recursion;
+    var n : integer;
+    procedure Down;
+        var local : integer;
+    begin
+        local := n;
+        n := n - 1;
+        if local > 0 then Down
+    end;
+    begin
+        n := 100;
+        Down
+    end."#;
+
+    /// Error of the program run on both backends, which must agree
+    fn run(text: &str, limits: Limits) -> Option<Error> {
+        let err = Interpreter::new(text).report(io::sink()).limits(limits).run().err();
+        let vm_err = Interpreter::new(text).report(io::sink()).limits(limits).bytecode(true).run().err();
+        assert_eq!(err.is_some(), vm_err.is_some());
+        err
+    }
+
+    #[test]
+    fn depth() {
+        assert_eq!(run(RECURSION, Limits::new().depth(101)), None);
+        let err = run(RECURSION, Limits::new().depth(100));
+        assert_eq!(err.map(|e| e.to_string()),
+            Some("9:27: runtime error: more than 100 procedure calls active".into()));
+    }
+
+    #[test]
+    fn stack() {
+        // the deepest expression in every call
+        let text = format!(r#"
+        program Forever;
+        var n : integer;
+        procedure Down;
+        begin
+            n := {}n + 1{};
+            Down
+        end;
+        begin
+            n := 0;
+            Down
+        end."#, "(".repeat(MAX_NESTING - 5), ")".repeat(MAX_NESTING - 5));
+        let err = Interpreter::new(text.as_str()).report(io::sink()).run().err();
+        assert!(matches!(err, Some(Error::Runtime(RuntimeError::StackOverflow { .. }))));
+        // the VM keeps its frames on the heap
+        let limits = Limits::new().depth(1000);
+        let err = Interpreter::new(text).report(io::sink()).limits(limits).bytecode(true).run().err();
+        assert!(matches!(err, Some(Error::Runtime(RuntimeError::DepthLimit { limit: 1000, .. }))));
+    }
+
+    #[test]
+    fn memory() {
+        // the global and a local per call
+        let size = mem::size_of::<VariableValue>();
+        assert_eq!(run(RECURSION, Limits::new().memory(102 * size)), None);
+        let err = run(RECURSION, Limits::new().memory(101 * size));
+        assert_eq!(err, Some(RuntimeError::MemoryLimit {
+            limit: 101 * size,
+            span: Span { line: 5, column: 13, len: 5 },
+        }.into()));
+    }
+
+    #[test]
+    fn steps() {
+        let text = r#"
+        program Forever;
+        var i : integer;
+        begin
+            i := 0;
+            while i >= 0 do i := 1
+        end."#;
+        let err = run(text, Limits::new().steps(1000));
+        assert!(matches!(err, Some(Error::Runtime(RuntimeError::StepLimit { limit: 1000, .. }))));
+        let err = run(text, Limits::new().timeout(Duration::from_millis(20)));
+        assert!(matches!(err, Some(Error::Runtime(RuntimeError::Timeout { .. }))));
+    }
+}
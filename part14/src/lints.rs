@@ -0,0 +1,90 @@
+use crate::symbols::*;
+use crate::diagnostics::*;
+
+/// Report unused symbols of the scopes collected by `SemanticAnalyzer`
+pub fn lint(scopes: &[SymbolTable]) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    for scope in scopes {
+        let is_global = scope.enclosing_scope.is_none() && scope.scope_level == 1;
+        for (name, var) in &scope.variables {
+            let warning = match var.kind {
+                VarKind::Parameter if var.reads == 0 => Some((
+                    WarningCode::UnusedParameter,
+                    format!("parameter \"{}\" is never used", name))),
+                VarKind::Variable if var.reads == 0 && var.writes == 0 => Some((
+                    WarningCode::UnusedVariable,
+                    format!("variable \"{}\" is declared but never used", name))),
+                // global values are the program result, only locals are checked
+                VarKind::Variable if var.reads == 0 && !is_global => Some((
+                    WarningCode::UnreadVariable,
+                    format!("variable \"{}\" is assigned but never read", name))),
+                _ => None,
+            };
+            if let Some((code, message)) = warning {
+                diagnostics.push(Diagnostic::warning(code, var.span, message));
+            }
+        }
+        for (name, procedure) in &scope.procedures {
+            if procedure.calls == 0 {
+                diagnostics.push(Diagnostic::warning(
+                    WarningCode::UnusedProcedure, procedure.span,
+                    format!("procedure \"{}\" is never called", name)));
+            }
+        }
+    }
+    diagnostics.sort_by_key(|diag| (diag.span.line, diag.span.column));
+    diagnostics
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::*;
+    use crate::parser::*;
+
+    fn lint_text(text: &str) -> Vec<String> {
+        let mut parser = Parser::new(text);
+        let tree = parser.parse();
+        let mut semantic_analyzer = SemanticAnalyzer::default();
+        semantic_analyzer.visit_program(&tree);
+        apply_directives(lint(&semantic_analyzer.scopes), parser.directives())
+            .iter()
+            .map(|diag| diag.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn unused() {
+        let warnings = lint_text(r#"
+        program Test;
+        var x, y, z : integer;
+        procedure P(a, b : integer);
+            var c, d : integer;
+            begin c := a; d := c end;
+        procedure Q; begin P(x, 1) end;
+        begin
+            Q;
+            y := x
+        end."#);
+        assert_eq!(warnings, vec![
+            "3:19: warning W002: variable \"Z\" is declared but never used",
+            "4:24: warning W003: parameter \"B\" is never used",
+            "5:20: warning W004: variable \"D\" is assigned but never read",
+        ]);
+    }
+
+    #[test]
+    fn directives() {
+        let warnings = lint_text(r#"
+        program Test;
+        {$WARN W002 OFF} var x : integer; {$WARN W002 ON}
+        var y : integer;
+        {$warn W005 off}
+        procedure P; begin end;
+        begin end."#);
+        assert_eq!(warnings, vec![
+            "4:13: warning W002: variable \"Y\" is declared but never used",
+        ]);
+    }
+}
@@ -0,0 +1,624 @@
+use crate::tokens::*;
+use crate::ast::*;
+use crate::analysis::Analysis;
+use crate::diagnostics::*;
+
+use serde_json::{json, Value};
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Read, Write};
+
+/// Language server speaking JSON-RPC over a byte stream, as editors
+/// run it over stdio. Documents are synchronized as a whole on each
+/// change and analyzed right away.
+///
+/// LSP positions are zero based while `Span` is one based, columns
+/// are counted in chars which is what editors send for ASCII text.
+pub struct Server {
+    output: Box<dyn Write>,
+    documents: HashMap<String, Document>,
+    shutdown: bool,
+}
+
+struct Document {
+    /// Results of the last analysis, empty when the text
+    /// does not parse
+    program: Option<Program>,
+    diagnostics: Vec<Diagnostic>,
+    /// Every identifier of a variable or procedure and the name
+    /// in the declaration it refers to
+    occurrences: Vec<(Span, Span)>,
+    /// Hover text of declarations
+    declarations: HashMap<Span, String>,
+}
+
+// JSON-RPC and LSP constants
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_REQUEST: i64 = -32600;
+const TEXT_DOCUMENT_SYNC_FULL: i64 = 1;
+const SEVERITY_ERROR: i64 = 1;
+const SEVERITY_WARNING: i64 = 2;
+const SYMBOL_MODULE: i64 = 2;
+const SYMBOL_FUNCTION: i64 = 12;
+const SYMBOL_VARIABLE: i64 = 13;
+
+impl Default for Server {
+    fn default() -> Self {
+        Server::new()
+    }
+}
+
+impl Server {
+    pub fn new() -> Server {
+        Server {
+            output: Box::new(io::stdout()),
+            documents: HashMap::new(),
+            shutdown: false,
+        }
+    }
+
+    /// Send messages to `out` instead of stdout
+    #[cfg(test)]
+    pub fn output(mut self, out: impl Write + 'static) -> Self {
+        self.output = Box::new(out);
+        self
+    }
+
+    /// Serve messages from `input` until `exit` or the end of input,
+    /// returns the process exit code
+    pub fn run(&mut self, mut input: impl BufRead) -> i32 {
+        while let Some(message) = read_message(&mut input) {
+            let message = match message {
+                Ok(message) => message,
+                // its id is unknown
+                Err(e) => {
+                    self.error(Value::Null, PARSE_ERROR, &e);
+                    continue;
+                },
+            };
+            if message["method"] == "exit" {
+                break;
+            }
+            self.handle(&message);
+        }
+        // a client shuts the server down before it exits
+        if self.shutdown { 0 } else { 1 }
+    }
+
+    fn handle(&mut self, message: &Value) {
+        let method = message["method"].as_str().unwrap_or("");
+        let params = &message["params"];
+        let id = match message.get("id") {
+            Some(id) => id.clone(),
+            None => return self.notification(method, params),
+        };
+        if self.shutdown {
+            return self.error(id, INVALID_REQUEST, "server is shut down");
+        }
+        let result = match method {
+            "initialize" => json!({
+                "capabilities": {
+                    "textDocumentSync": TEXT_DOCUMENT_SYNC_FULL,
+                    "definitionProvider": true,
+                    "referencesProvider": true,
+                    "hoverProvider": true,
+                    "documentSymbolProvider": true,
+                },
+                "serverInfo": {
+                    "name": "lsbasi",
+                    "version": env!("CARGO_PKG_VERSION"),
+                },
+            }),
+            "shutdown" => {
+                self.shutdown = true;
+                Value::Null
+            },
+            "textDocument/definition" => self.definition(params),
+            "textDocument/references" => self.references(params),
+            "textDocument/hover" => self.hover(params),
+            "textDocument/documentSymbol" => self.document_symbols(params),
+            _ => return self.error(id, METHOD_NOT_FOUND, &format!("unknown method \"{}\"", method)),
+        };
+        self.send(json!({ "jsonrpc": "2.0", "id": id, "result": result }));
+    }
+
+    fn notification(&mut self, method: &str, params: &Value) {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or("").to_string();
+        match method {
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or("");
+                self.update(uri, text);
+            },
+            "textDocument/didChange" => {
+                // full synchronization, the last change has the whole text
+                let changes = params["contentChanges"].as_array();
+                if let Some(text) = changes.and_then(|c| c.last()).and_then(|c| c["text"].as_str()) {
+                    self.update(uri, text);
+                }
+            },
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                self.publish(&uri, &[]);
+            },
+            _ => {},
+        }
+    }
+
+    fn update(&mut self, uri: String, text: &str) {
+        let document = Document::analyze(text);
+        self.publish(&uri, &document.diagnostics);
+        self.documents.insert(uri, document);
+    }
+
+    fn publish(&mut self, uri: &str, diagnostics: &[Diagnostic]) {
+        let diagnostics: Vec<_> = diagnostics.iter().map(|diag| {
+            let severity = match diag.severity {
+                Severity::Error => SEVERITY_ERROR,
+                Severity::Warning => SEVERITY_WARNING,
+            };
+            let related: Vec<_> = diag.notes.iter()
+                .map(|(span, message)| json!({
+                    "location": { "uri": uri, "range": range(*span) },
+                    "message": message,
+                }))
+                .collect();
+            let mut value = json!({
+                "range": range(diag.span),
+                "severity": severity,
+                "source": "lsbasi",
+                "message": diag.message,
+            });
+            if let Some(code) = diag.code {
+                value["code"] = json!(code.code());
+            }
+            if !related.is_empty() {
+                value["relatedInformation"] = json!(related);
+            }
+            value
+        }).collect();
+        self.send(json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": { "uri": uri, "diagnostics": diagnostics },
+        }));
+    }
+
+    /// Document and the occurrence under the cursor of a
+    /// `TextDocumentPositionParams`
+    fn occurrence(&self, params: &Value) -> Option<(&str, &Document, (Span, Span))> {
+        let uri = params["textDocument"]["uri"].as_str()?;
+        let (uri, document) = self.documents.get_key_value(uri)?;
+        let line = params["position"]["line"].as_u64()? as usize + 1;
+        let column = params["position"]["character"].as_u64()? as usize + 1;
+        let found = document.occurrences.iter()
+            .find(|(span, _)| span.line == line
+                && span.column <= column && column < span.column + span.len)?;
+        Some((uri, document, *found))
+    }
+
+    fn definition(&self, params: &Value) -> Value {
+        match self.occurrence(params) {
+            Some((uri, _, (_, decl))) => json!({ "uri": uri, "range": range(decl) }),
+            None => Value::Null,
+        }
+    }
+
+    fn references(&self, params: &Value) -> Value {
+        let (uri, document, (_, decl)) = match self.occurrence(params) {
+            Some(found) => found,
+            None => return Value::Null,
+        };
+        let declaration = params["context"]["includeDeclaration"].as_bool().unwrap_or(true);
+        let locations: Vec<_> = document.occurrences.iter()
+            .filter(|(span, d)| *d == decl && (declaration || *span != decl))
+            .map(|(span, _)| json!({ "uri": uri, "range": range(*span) }))
+            .collect();
+        json!(locations)
+    }
+
+    fn hover(&self, params: &Value) -> Value {
+        let (_, document, (span, decl)) = match self.occurrence(params) {
+            Some(found) => found,
+            None => return Value::Null,
+        };
+        match document.declarations.get(&decl) {
+            Some(text) => json!({
+                "contents": {
+                    "kind": "markdown",
+                    "value": format!("```pascal\n{}\n```", text),
+                },
+                "range": range(span),
+            }),
+            None => Value::Null,
+        }
+    }
+
+    fn document_symbols(&self, params: &Value) -> Value {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or("");
+        let program = match self.documents.get(uri).and_then(|doc| doc.program.as_ref()) {
+            Some(program) => program,
+            None => return json!([]),
+        };
+        let name = &program.name;
+        let start = if name.span == Span::default() {
+            Span { line: 1, column: 1, len: 0 }
+        }
+        else {
+            name.span
+        };
+        json!([{
+            "name": name.name,
+            "kind": SYMBOL_MODULE,
+            "range": range_between(start, program.block.end),
+            "selectionRange": range(start),
+            "children": block_symbols(&program.block),
+        }])
+    }
+
+    fn error(&mut self, id: Value, code: i64, message: &str) {
+        self.send(json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": code, "message": message },
+        }));
+    }
+
+    fn send(&mut self, message: Value) {
+        let body = message.to_string();
+        write!(self.output, "Content-Length: {}\r\n\r\n{}", body.len(), body)
+            .expect("write output");
+        self.output.flush().expect("write output");
+    }
+}
+
+impl Document {
+    fn analyze(text: &str) -> Document {
+        let analysis = Analysis::new(text, |_| {}, false);
+        let program = match analysis.program {
+            Some(program) => program,
+            None => return Document {
+                program: None,
+                diagnostics: analysis.diagnostics,
+                occurrences: Vec::new(),
+                declarations: HashMap::new(),
+            },
+        };
+        let mut collector = DeclarationCollector::default();
+        collector.visit_program(&program);
+        let mut occurrences: Vec<_> = collector.declarations.keys()
+            .map(|decl| (*decl, *decl))
+            .chain(analysis.resolutions.iter()
+                .map(|(span, resolution)| (*span, resolution.decl)))
+            .collect();
+        occurrences.sort_by_key(|(span, _)| (span.line, span.column));
+        Document {
+            program: Some(program),
+            diagnostics: analysis.diagnostics,
+            occurrences,
+            declarations: collector.declarations,
+        }
+    }
+}
+
+/// Hover text of every declared name
+#[derive(Default)]
+struct DeclarationCollector {
+    declarations: HashMap<Span, String>,
+}
+
+impl Visitor for DeclarationCollector {
+    fn visit_var_decl(&mut self, decl: &VarDecl) {
+        self.declarations.insert(decl.name.span,
+            format!("var {} : {:?}", decl.name.name, decl.typ));
+    }
+
+    fn visit_param(&mut self, param: &Param) {
+        self.declarations.insert(param.name.span,
+            format!("param {} : {:?}", param.name.name, param.typ));
+    }
+
+    fn visit_procedure_decl(&mut self, decl: &ProcedureDecl) {
+        self.declarations.insert(decl.name.span, signature(decl));
+        walk_procedure_decl(self, decl);
+    }
+}
+
+fn signature(decl: &ProcedureDecl) -> String {
+    let params: Vec<_> = decl.params.iter()
+        .map(|param| format!("{} : {:?}", param.name.name, param.typ))
+        .collect();
+    format!("procedure {}({})", decl.name.name, params.join("; "))
+}
+
+/// Procedures and variables of a block in declaration order
+fn block_symbols(block: &Block) -> Vec<Value> {
+    block.decls.iter().map(|decl| match decl {
+        Decl::Var(var) => json!({
+            "name": var.name.name,
+            "detail": format!("{:?}", var.typ),
+            "kind": SYMBOL_VARIABLE,
+            "range": range(var.name.span),
+            "selectionRange": range(var.name.span),
+        }),
+        Decl::Procedure(decl) => {
+            let params = decl.params.iter().map(|param| json!({
+                "name": param.name.name,
+                "detail": format!("{:?}", param.typ),
+                "kind": SYMBOL_VARIABLE,
+                "range": range(param.name.span),
+                "selectionRange": range(param.name.span),
+            }));
+            let (end, children) = match &decl.block {
+                Some(block) => (block.end, block_symbols(block)),
+                None => (decl.name.span, Vec::new()),
+            };
+            json!({
+                "name": decl.name.name,
+                "detail": signature(decl),
+                "kind": SYMBOL_FUNCTION,
+                "range": range_between(decl.name.span, end),
+                "selectionRange": range(decl.name.span),
+                "children": params.chain(children).collect::<Vec<_>>(),
+            })
+        },
+    }).collect()
+}
+
+fn position(line: usize, column: usize) -> Value {
+    json!({ "line": line.saturating_sub(1), "character": column.saturating_sub(1) })
+}
+
+fn range(span: Span) -> Value {
+    json!({
+        "start": position(span.line, span.column),
+        "end": position(span.line, span.column + span.len),
+    })
+}
+
+/// From the start of `first` to the end of `last`
+fn range_between(first: Span, last: Span) -> Value {
+    json!({
+        "start": position(first.line, first.column),
+        "end": position(last.line, last.column + last.len),
+    })
+}
+
+/// Next message framed by a `Content-Length` header,
+/// `None` at the end of input. The body is read as it comes,
+/// a length past the end of input is an error.
+pub fn read_message(input: &mut impl BufRead) -> Option<Result<Value, String>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        match input.read_line(&mut line) {
+            Ok(0) => return None,
+            Ok(_) => {},
+            Err(e) => return Some(Err(e.to_string())),
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let mut header = line.splitn(2, ':');
+        let name = header.next().unwrap_or("");
+        if name.eq_ignore_ascii_case("Content-Length") {
+            length = header.next().and_then(|value| value.trim().parse::<usize>().ok());
+        }
+    }
+    let length = match length {
+        Some(length) => length,
+        None => return Some(Err("message without Content-Length".into())),
+    };
+    let mut body = Vec::new();
+    if let Err(e) = input.by_ref().take(length as u64).read_to_end(&mut body) {
+        return Some(Err(e.to_string()));
+    }
+    if body.len() < length {
+        return Some(Err(format!("message ends after {} of {} bytes", body.len(), length)));
+    }
+    Some(serde_json::from_slice(&body).map_err(|e| e.to_string()))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Capture;
+
+    const URI: &str = "file:///nested.pas";
+
+    const TEXT: &str = "\
+program Nested;
+var a : integer;
+procedure P1(n : real);
+    var b : integer;
+    procedure P2;
+    begin
+        b := a + 1
+    end;
+begin
+    P2;
+    a := b
+end;
+begin
+    P1(a);
+    c := 1
+end.
+";
+
+    /// Scripted client, the messages are sent to the server at once
+    #[derive(Default)]
+    struct Client {
+        input: Vec<u8>,
+        next_id: i64,
+    }
+
+    impl Client {
+        fn notify(&mut self, method: &str, params: Value) {
+            self.send(json!({ "jsonrpc": "2.0", "method": method, "params": params }));
+        }
+
+        fn request(&mut self, method: &str, params: Value) -> i64 {
+            self.next_id += 1;
+            let id = self.next_id;
+            self.send(json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }));
+            id
+        }
+
+        /// Request about the position in the test document
+        fn at(&mut self, method: &str, line: u64, character: u64) -> i64 {
+            self.request(method, json!({
+                "textDocument": { "uri": URI },
+                "position": { "line": line, "character": character },
+                "context": { "includeDeclaration": true },
+            }))
+        }
+
+        fn send(&mut self, message: Value) {
+            let body = message.to_string();
+            write!(self.input, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        }
+
+        /// Exit code and messages written by the server
+        fn run(self) -> (i32, Vec<Value>) {
+            let output = Capture::default();
+            let code = Server::new()
+                .output(output.clone())
+                .run(io::Cursor::new(self.input));
+            let mut text = io::Cursor::new(output.text());
+            let mut messages = Vec::new();
+            while let Some(message) = read_message(&mut text) {
+                messages.push(message.unwrap());
+            }
+            (code, messages)
+        }
+    }
+
+    fn response(messages: &[Value], id: i64) -> &Value {
+        &messages.iter().find(|m| m["id"] == id).unwrap()["result"]
+    }
+
+    fn at(line: u64, character: u64, len: u64) -> Value {
+        json!({
+            "start": { "line": line, "character": character },
+            "end": { "line": line, "character": character + len },
+        })
+    }
+
+    fn session() -> Client {
+        let mut client = Client::default();
+        client.request("initialize", json!({ "capabilities": {} }));
+        client.notify("initialized", json!({}));
+        client.notify("textDocument/didOpen", json!({
+            "textDocument": { "uri": URI, "languageId": "pascal", "version": 1, "text": TEXT },
+        }));
+        client
+    }
+
+    #[test]
+    fn diagnostics() {
+        let mut client = session();
+        client.notify("textDocument/didChange", json!({
+            "textDocument": { "uri": URI, "version": 2 },
+            "contentChanges": [{ "text": "begin end" }],
+        }));
+        client.request("shutdown", Value::Null);
+        client.notify("exit", Value::Null);
+        let (code, messages) = client.run();
+        assert_eq!(code, 0);
+        assert_eq!(messages[0]["result"]["capabilities"]["hoverProvider"], true);
+        let published: Vec<_> = messages.iter()
+            .filter(|m| m["method"] == "textDocument/publishDiagnostics")
+            .map(|m| &m["params"]["diagnostics"])
+            .collect();
+        assert_eq!(published.len(), 2);
+        assert_eq!(published[0][0], json!({
+            "range": at(2, 13, 1),
+            "severity": SEVERITY_WARNING,
+            "code": "W003",
+            "source": "lsbasi",
+            "message": "parameter \"N\" is never used",
+        }));
+        let messages: Vec<_> = published[0].as_array().unwrap().iter()
+            .map(|diag| diag["message"].as_str().unwrap())
+            .collect();
+        assert_eq!(messages, vec![
+            "parameter \"N\" is never used",
+            "variable \"A\" may be used uninitialized",
+            "variable \"C\" not defined",
+        ]);
+        assert_eq!(published[0][2]["range"], at(14, 4, 1));
+        assert_eq!(*published[1], json!([{
+            "range": at(0, 9, 0),
+            "severity": SEVERITY_ERROR,
+            "source": "lsbasi",
+            "message": "Expect DOT, got None",
+        }]));
+    }
+
+    #[test]
+    fn navigation() {
+        let mut client = session();
+        // `a` in `b := a + 1`
+        let definition = client.at("textDocument/definition", 6, 13);
+        let references = client.at("textDocument/references", 1, 4);
+        let hover = client.at("textDocument/hover", 13, 4);
+        let nothing = client.at("textDocument/hover", 5, 4);
+        let (code, messages) = client.run();
+        assert_eq!(code, 1);
+        assert_eq!(*response(&messages, definition), json!({ "uri": URI, "range": at(1, 4, 1) }));
+        let references: Vec<_> = response(&messages, references).as_array().unwrap().iter()
+            .map(|location| location["range"]["start"]["line"].as_u64().unwrap())
+            .collect();
+        assert_eq!(references, vec![1, 6, 10, 13]);
+        assert_eq!(response(&messages, hover)["contents"]["value"],
+            "```pascal\nprocedure P1(N : Real)\n```");
+        assert_eq!(*response(&messages, nothing), Value::Null);
+    }
+
+    #[test]
+    fn document_symbols() {
+        let mut client = session();
+        let symbols = client.request("textDocument/documentSymbol", json!({
+            "textDocument": { "uri": URI },
+        }));
+        let unknown = client.request("textDocument/rename", json!({}));
+        let (_, messages) = client.run();
+        let program = &response(&messages, symbols)[0];
+        assert_eq!(program["name"], "NESTED");
+        assert_eq!(program["range"]["end"], json!({ "line": 15, "character": 3 }));
+        let names = |symbols: &Value| -> Vec<String> {
+            symbols.as_array().unwrap().iter()
+                .map(|s| format!("{} {}", s["kind"], s["name"].as_str().unwrap()))
+                .collect()
+        };
+        assert_eq!(names(&program["children"]), vec!["13 A", "12 P1"]);
+        let p1 = &program["children"][1];
+        assert_eq!(p1["range"], json!({
+            "start": { "line": 2, "character": 10 },
+            "end": { "line": 11, "character": 3 },
+        }));
+        assert_eq!(names(&p1["children"]), vec!["13 N", "13 B", "12 P2"]);
+        let error = &messages.iter().find(|m| m["id"] == unknown).unwrap()["error"];
+        assert_eq!(error["code"], METHOD_NOT_FOUND);
+    }
+
+    #[test]
+    fn malformed() {
+        let mut client = session();
+        write!(client.input, "Content-Length: 4\r\n\r\n{{\"a\"").unwrap();
+        client.request("shutdown", Value::Null);
+        client.notify("exit", Value::Null);
+        // the length is trusted only as far as the input goes
+        write!(client.input, "Content-Length: {}\r\n\r\n{{}}", usize::MAX).unwrap();
+        let (code, messages) = client.run();
+        assert_eq!(code, 0);
+        let error = messages.iter().find(|m| m.get("id") == Some(&Value::Null)).unwrap();
+        assert_eq!(error["error"]["code"], PARSE_ERROR);
+        let mut input = io::Cursor::new(format!("Content-Length: {}\r\n\r\n{{}}", usize::MAX));
+        assert_eq!(read_message(&mut input),
+            Some(Err(format!("message ends after 2 of {} bytes", usize::MAX))));
+        assert_eq!(read_message(&mut input), None);
+    }
+}
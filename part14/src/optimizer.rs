@@ -0,0 +1,153 @@
+use crate::tokens::*;
+use crate::ast::*;
+use crate::diagnostics::*;
+
+/// Evaluate constant subexpressions and drop identity operations:
+/// `x + 0`, `0 + x`, `x - 0`, `x * 1`, `1 * x`, `+x`, `- - x`.
+/// Only integer 0 and 1 are treated as identities, `x + 0.0` changes
+/// an integer `x` into real.
+#[derive(Default)]
+pub struct ConstantFolder {
+    /// Constant division by zero and integer overflow
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl VisitorMut for ConstantFolder {
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        // fold the operands first
+        walk_expr_mut(self, expr);
+        let placeholder = Expr::Num { value: VariableValue::None, span: Span::default() };
+        let node = std::mem::replace(expr, placeholder);
+        *expr = self.fold(node);
+    }
+}
+
+impl ConstantFolder {
+    fn fold(&mut self, expr: Expr) -> Expr {
+        match expr {
+            Expr::BinOp{op, left, right, span} => self.binary(op, *left, *right, span),
+            Expr::UnaryOp{op: UnaryOp::Plus, operand, ..} => *operand,
+            Expr::UnaryOp{op: UnaryOp::Minus, operand, span} => match *operand {
+                Expr::Num{value, span: num} => match value.checked_neg() {
+                    Some(value) => Expr::Num { value, span },
+                    None => {
+                        self.fault(span, "integer overflow");
+                        Expr::UnaryOp {
+                            op: UnaryOp::Minus,
+                            operand: Box::new(Expr::Num { value, span: num }),
+                            span,
+                        }
+                    },
+                },
+                Expr::UnaryOp{op: UnaryOp::Minus, operand, ..} => *operand,
+                operand => Expr::UnaryOp {
+                    op: UnaryOp::Minus,
+                    operand: Box::new(operand),
+                    span,
+                },
+            },
+            expr => expr,
+        }
+    }
+
+    fn fault(&mut self, span: Span, message: &str) {
+        self.diagnostics.push(Diagnostic::warning(WarningCode::ConstantFault, span, message));
+    }
+
+    fn binary(&mut self, op: BinOp, left: Expr, right: Expr, span: Span) -> Expr {
+        let num = |expr: &Expr| match expr {
+            Expr::Num{value, ..} => Some(*value),
+            _ => None,
+        };
+        let is_int = |expr: &Expr, n| num(expr) == Some(VariableValue::Integer(n));
+        match (op, num(&left), num(&right)) {
+            (_, _, Some(r)) if op.divides_by_zero(r) => {
+                self.fault(span, "division by zero");
+            },
+            (_, Some(l), Some(r)) => match op.apply(l, r) {
+                Some(value) => return Expr::Num { value, span: left.span() },
+                None => self.fault(span, "integer overflow"),
+            },
+            (BinOp::Add, _, _) if is_int(&right, 0) => return left,
+            (BinOp::Add, _, _) if is_int(&left, 0) => return right,
+            (BinOp::Sub, _, _) if is_int(&right, 0) => return left,
+            (BinOp::Mul, _, _) if is_int(&right, 1) => return left,
+            (BinOp::Mul, _, _) if is_int(&left, 1) => return right,
+            _ => {},
+        }
+        Expr::BinOp {
+            op,
+            left: Box::new(left),
+            right: Box::new(right),
+            span,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::*;
+
+    fn fold(expr: &str) -> (Expr, Vec<Diagnostic>) {
+        let text = format!("var a, b : integer; begin a := {} end.", expr);
+        let mut program = Parser::new(text).parse();
+        let mut folder = ConstantFolder::default();
+        folder.visit_program_mut(&mut program);
+        match program.block.body.remove(0) {
+            Stmt::Assign{value, ..} => (value, folder.diagnostics),
+            _ => unreachable!()
+        }
+    }
+
+    fn constant(expr: &str) -> VariableValue {
+        match fold(expr).0 {
+            Expr::Num{value, ..} => value,
+            expr => panic!("Not folded: {:?}", expr),
+        }
+    }
+
+    #[test]
+    fn constants() {
+        assert_eq!(constant("10 * 2 + 10 * 3 DIV 4"), VariableValue::Integer(27));
+        assert_eq!(constant("7 / 2"), VariableValue::Real(3.5));
+        assert_eq!(constant("7.9 DIV 2"), VariableValue::Integer(3));
+        assert_eq!(constant("- (2 - 5)"), VariableValue::Integer(3));
+    }
+
+    #[test]
+    fn identities() {
+        let var = |expr: &str| match fold(expr).0 {
+            Expr::Var(ident) => ident.name,
+            expr => panic!("Not simplified: {:?}", expr),
+        };
+        assert_eq!(var("b * 1"), "B");
+        assert_eq!(var("1 * (b + 0)"), "B");
+        assert_eq!(var("0 + b - (3 - 3)"), "B");
+        assert_eq!(var("- - + b"), "B");
+        assert_eq!(var("(2 - 1) * b"), "B");
+        // real constants may change the type
+        assert!(matches!(fold("b * 1.0").0, Expr::BinOp{..}));
+    }
+
+    #[test]
+    fn division_by_zero() {
+        let (_, diag) = fold("b DIV (2 - 2) + 1 / 0.0 + b DIV 0.5");
+        let diag: Vec<_> = diag.iter().map(|d| d.to_string()).collect();
+        assert_eq!(diag, vec![
+            "1:34: warning W007: division by zero",
+            "1:50: warning W007: division by zero",
+            "1:60: warning W007: division by zero",
+        ]);
+    }
+    #[test]
+    fn integer_overflow() {
+        let (_, diag) = fold("2147483647 + 1 + b");
+        assert_eq!(diag.iter().map(|d| d.to_string()).collect::<Vec<_>>(), vec!["1:43: warning W007: integer overflow"]);
+        let (_, diag) = fold("(-2147483647 - 1) DIV -1");
+        assert_eq!(diag.iter().map(|d| d.to_string()).collect::<Vec<_>>(), vec!["1:50: warning W007: integer overflow"]);
+        let (_, diag) = fold("-(-2147483647 - 1)");
+        assert_eq!(diag.iter().map(|d| d.to_string()).collect::<Vec<_>>(), vec!["1:32: warning W007: integer overflow"]);
+    }
+}
@@ -1,10 +1,20 @@
 use crate::tokens::*;
 use crate::lexer::*;
 use crate::ast::*;
+use crate::diagnostics::*;
+
+type ParseResult<T> = Result<T, Diagnostic>;
+
+/// Deepest nesting of statements, expressions and procedures. The
+/// passes over the tree recurse once per level, deeper programs
+/// would overflow the stack.
+pub const MAX_NESTING: usize = 100;
 
 pub struct Parser {
     lexer: Lexer,
     cur_token: Option<Token>,
+    /// Nesting of the node being parsed
+    depth: usize,
 }
 
 impl Parser {
@@ -14,348 +24,492 @@ impl Parser {
         Parser {
             lexer,
             cur_token: tok,
+            depth: 0,
+        }
+    }
+
+    /// Go one level deeper, the caller restores `depth` when done
+    fn nest(&mut self) -> ParseResult<()> {
+        self.depth += 1;
+        if self.depth > MAX_NESTING {
+            return Err(self.error(format!("nesting deeper than {} levels", MAX_NESTING)));
         }
+        Ok(())
     }
 
-    fn eat(&mut self, tok: Token) {
+    fn eat(&mut self, tok: Token) -> ParseResult<()> {
         // println!("{}", tok);
         self.cur_token = match &self.cur_token {
             Some(ref cur) if (cur == &tok) => self.lexer.get_next_token(),
-            Some(ref cur) => panic!("Expect {}, got {}", tok, cur),
-            None => panic!("Expect {}, got None", tok)
+            Some(ref cur) => return Err(self.error(format!("Expect {}, got {}", tok, cur))),
+            None => return Err(self.error(format!("Expect {}, got None", tok)))
         };
+        Ok(())
     }
 
-    fn eat_type(&mut self) -> Keyword {
-        let kw = match &self.cur_token {
-            Some(Token::KW(kw))
-                if *kw == Keyword::INTEREG || *kw == Keyword::REAL => *kw,
-            Some(tok) => panic!("Expect 'type', got {}", tok),
-            None => panic!("Expect 'type', got None")
+    fn eat_type(&mut self) -> ParseResult<Type> {
+        let typ = match &self.cur_token {
+            Some(Token::KW(Keyword::INTEREG)) => Type::Integer,
+            Some(Token::KW(Keyword::REAL)) => Type::Real,
+            Some(tok) => return Err(self.error(format!("Expect 'type', got {}", tok))),
+            None => return Err(self.error("Expect 'type', got None".into()))
         };
         self.cur_token = self.lexer.get_next_token();
-        kw
+        Ok(typ)
     }
 
-    fn eat_any(&mut self) {
+    fn eat_any(&mut self) -> ParseResult<()> {
         // println!("${}", self.cur_token.as_ref().unwrap());
         if self.cur_token.is_some() {
             self.cur_token = self.lexer.get_next_token();
+            Ok(())
         }
         else {
-            panic!("Expect 'any' token, got None");
+            Err(self.error("Expect 'any' token, got None".into()))
         }
     }
 
-    fn variable(&mut self) -> AST {
+    fn variable(&mut self) -> ParseResult<Ident> {
         let name = match self.cur_token {
             Some(Token::ID(ref name)) => name.to_string(),
-            _ => unreachable!()
+            Some(ref tok) => return Err(self.error(format!("Expect ID, got {}", tok))),
+            None => return Err(self.error("Expect ID, got None".into())),
         };
-        self.eat(Token::ID(name.to_string()));
-        let var = Root::VarID { name, value: VariableValue::None };
-        AST::new(var)
-    }
-
-    /// program : PROGRAM variable SEMI block DOT
-    fn program(&mut self) -> AST {
-        let prog = if self.cur_token == Some(Token::KW(Keyword::PROGRAM)) {
-            self.eat(Token::KW(Keyword::PROGRAM));
-            let name = self.variable().get_name();
-            self.eat(Token::SEMI);
-            AST::new(Root::Program{name})
-                .left(self.block())
+        let span = self.lexer.span;
+        self.eat(Token::ID(name.to_string()))?;
+        Ok(Ident { name, span })
+    }
+
+    /// program : (PROGRAM variable SEMI)? block DOT
+    fn program(&mut self) -> ParseResult<Program> {
+        let name = if self.cur_token == Some(Token::KW(Keyword::PROGRAM)) {
+            self.eat(Token::KW(Keyword::PROGRAM))?;
+            let name = self.variable()?;
+            self.eat(Token::SEMI)?;
+            name
         }
         else {
-            AST::new(Root::Program{name: "noname".into()})
-                .left(self.block())
+            Ident { name: "noname".into(), span: Span::default() }
         };
-        self.eat(Token::DOT);
-        prog
+        let block = self.block()?;
+        self.eat(Token::DOT)?;
+        Ok(Program { name, block })
     }
 
     /// block : declarations compound_statement
-    fn block(&mut self) -> AST {
-        let decl_node = self.declarations();
-        let comp_node = self.compound_statement();
-        AST::new(Root::Compound)
-            .left(decl_node)
-            .right(comp_node)
-    }
-
-    /// declarations : variable_declarations
-    ///              | procedure_declarations
-    ///              | empty
-    fn declarations(&mut self) -> AST {
-        let var  = self.variable_declarations(AST::default());
-        let proc = self.procedure_declarations(AST::default());
-        AST::new(Root::Compound)
-            .left(var)
-            .right(proc)
-    }
-
-    /// variable_declarations : VAR ID (COMMA ID)* COLON type_spec
-    fn variable_declarations(&mut self, comp: AST) -> AST {
-        if self.cur_token == Some(Token::KW(Keyword::VAR)) {
-            self.eat(Token::KW(Keyword::VAR));
-            let decl = self.variable_declaration_helper(AST::default());
-            let new_comp = AST::new(Root::Compound)
-                .left(decl)
-                .right(comp);
-            self.variable_declarations(new_comp)
-        }
-        else {
-            comp
-        }
+    fn block(&mut self) -> ParseResult<Block> {
+        let decls = self.declarations()?;
+        let (body, end) = match self.compound_statement()? {
+            Stmt::Compound{body, end, ..} => (body, end),
+            _ => unreachable!()
+        };
+        Ok(Block { decls, body, end })
     }
 
-    fn variable_declaration_helper(&mut self, decl: AST) -> AST {
-        // eat variable ID, wrap in VarDecl
-        let mut node = AST::new(Root::VarDecl)
-            .left(self.variable())
-            .right(decl);
-        match self.cur_token {
-            Some(Token::COMMA) => {
-                self.eat(Token::COMMA);
-                self.variable_declaration_helper(node)
-            },
-            Some(Token::COLON) => {
-                self.eat(Token::COLON);
-                let typ = self.eat_type();
-                set_type(&mut node, typ);
-                self.eat(Token::SEMI);
-                if let Some(Token::ID(ref _id)) = self.cur_token {
-                    let new_comp = AST::new(Root::Compound).left(node);
-                    self.variable_declaration_helper(new_comp)
-                }
-                else {
-                    node
-                }
-            },
-            _ => panic!("Error at variable_declaration: {}", self.lexer.pos)
+    /// declarations : (VAR (variable_declaration SEMI)+)*
+    ///                procedure_declarations
+    fn declarations(&mut self) -> ParseResult<Vec<Decl>> {
+        let mut decls = Vec::new();
+        while self.cur_token == Some(Token::KW(Keyword::VAR)) {
+            self.eat(Token::KW(Keyword::VAR))?;
+            while let Some(Token::ID(_)) = self.cur_token {
+                decls.extend(self.variable_declaration()?);
+                self.eat(Token::SEMI)?;
+            }
         }
+        self.procedure_declarations(&mut decls)?;
+        Ok(decls)
+    }
+
+    /// variable_declaration : ID (COMMA ID)* COLON type_spec
+    fn variable_declaration(&mut self) -> ParseResult<Vec<Decl>> {
+        let names = self.id_list()?;
+        self.eat(Token::COLON)?;
+        let typ = self.eat_type()?;
+        Ok(names.into_iter()
+            .map(|name| Decl::Var(VarDecl { name, typ }))
+            .collect())
     }
 
-    /// procedure_declarations : (PROCEDURE ID SEMI block SEMI)*
-    fn procedure_declarations(&mut self, comp: AST) -> AST {
-        if self.cur_token == Some(Token::KW(Keyword::PROCEDURE)) {
-            self.eat(Token::KW(Keyword::PROCEDURE));
-            let proc_name = self.variable().get_name();
-            let params = self.formal_parameter_list();
-            self.eat(Token::SEMI);
-            let block = self.block();
-            self.eat(Token::SEMI);
-            let proc_decl = AST::new(Root::ProcedureDecl{name: proc_name})
-                .left(params)
-                .right(block);
-            let new_comp = AST::new(Root::Compound)
-                .left(proc_decl)
-                .right(comp);
-            self.procedure_declarations(new_comp)
+    fn id_list(&mut self) -> ParseResult<Vec<Ident>> {
+        let mut names = vec![self.variable()?];
+        while self.cur_token == Some(Token::COMMA) {
+            self.eat(Token::COMMA)?;
+            names.push(self.variable()?);
         }
-        else {
-            comp
+        Ok(names)
+    }
+
+    /// procedure_declarations :
+    ///     (PROCEDURE ID formal_parameter_list? SEMI (block | FORWARD) SEMI)*
+    fn procedure_declarations(&mut self, decls: &mut Vec<Decl>) -> ParseResult<()> {
+        while self.cur_token == Some(Token::KW(Keyword::PROCEDURE)) {
+            self.eat(Token::KW(Keyword::PROCEDURE))?;
+            let name = self.variable()?;
+            let params = self.formal_parameter_list()?;
+            self.eat(Token::SEMI)?;
+            // FORWARD is a directive, not a reserved word
+            let block = if self.cur_token == Some(Token::ID("FORWARD".into())) {
+                self.eat_any()?;
+                None
+            }
+            else {
+                let depth = self.depth;
+                self.nest()?;
+                let block = self.block()?;
+                self.depth = depth;
+                Some(block)
+            };
+            self.eat(Token::SEMI)?;
+            decls.push(Decl::Procedure(ProcedureDecl { name, params, block }));
         }
+        Ok(())
     }
 
-    fn formal_parameter_list(&mut self) -> AST {
+    /// formal_parameter_list : LPAREN formal_parameters (SEMI formal_parameters)* RPAREN
+    fn formal_parameter_list(&mut self) -> ParseResult<Vec<Param>> {
+        let mut params = Vec::new();
         if self.cur_token == Some(Token::LParen) {
-            self.eat(Token::LParen);
-            let params = self.formal_parameters(AST::default());
-            self.eat(Token::RParen);
-            params
+            self.eat(Token::LParen)?;
+            params.extend(self.formal_parameters()?);
+            while self.cur_token == Some(Token::SEMI) {
+                self.eat(Token::SEMI)?;
+                params.extend(self.formal_parameters()?);
+            }
+            self.eat(Token::RParen)?;
         }
-        else {
-            AST::default()
+        Ok(params)
+    }
+
+    /// formal_parameters : ID (COMMA ID)* COLON type_spec
+    fn formal_parameters(&mut self) -> ParseResult<Vec<Param>> {
+        let names = self.id_list()?;
+        self.eat(Token::COLON)?;
+        let typ = self.eat_type()?;
+        Ok(names.into_iter()
+            .map(|name| Param { name, typ })
+            .collect())
+    }
+
+    /// compound_statement : BEGIN statement_list END
+    fn compound_statement(&mut self) -> ParseResult<Stmt> {
+        let span = self.lexer.span;
+        self.eat(Token::KW(Keyword::BEGIN))?;
+        let body = self.statement_list()?;
+        let end = self.lexer.span;
+        self.eat(Token::KW(Keyword::END))?;
+        Ok(Stmt::Compound { body, span, end })
+    }
+
+    /// statement_list : statement | statement SEMI statement_list
+    fn statement_list(&mut self) -> ParseResult<Vec<Stmt>> {
+        let mut body = vec![self.statement()?];
+        while self.cur_token == Some(Token::SEMI) {
+            self.eat(Token::SEMI)?;
+            body.push(self.statement()?);
         }
+        Ok(body)
     }
 
-    fn formal_parameters(&mut self, decl: AST) -> AST {
-        // eat variable ID, wrap in Param
-        let mut node = AST::new(Root::Param)
-            .left(self.variable())
-            .right(decl);
-        match self.cur_token {
-            Some(Token::COMMA) => {
-                self.eat(Token::COMMA);
-                self.formal_parameters(node)
-            },
-            Some(Token::COLON) => {
-                self.eat(Token::COLON);
-                let typ = self.eat_type();
-                set_type(&mut node, typ);
-                if self.cur_token == Some(Token::SEMI) {
-                    self.eat(Token::SEMI);
-                    let new_comp = AST::new(Root::Compound).left(node);
-                    self.formal_parameters(new_comp)
+    /// statement : compound_statement
+    ///           | if_statement
+    ///           | while_statement
+    ///           | proccall_statement
+    ///           | assignment_statement
+    ///           | empty
+    fn statement(&mut self) -> ParseResult<Stmt> {
+        let depth = self.depth;
+        self.nest()?;
+        let stmt = match self.cur_token {
+            Some(Token::KW(Keyword::BEGIN)) => self.compound_statement(),
+            Some(Token::KW(Keyword::IF)) => self.if_statement(),
+            Some(Token::KW(Keyword::WHILE)) => self.while_statement(),
+            Some(Token::ID(_)) => {
+                let var = self.variable()?;
+                if self.cur_token == Some(Token::ASSIGN) {
+                    self.assignment_statement(var)
+                }
+                else if var.name == "WRITELN" {
+                    self.writeln_statement(var)
                 }
                 else {
-                    node
+                    self.proccall_statement(var)
                 }
             },
-            _ => panic!("Error at formal_parameters: {}", self.lexer.pos)
+            Some(_) => self.empty(),
+            None => Err(self.error("Unexpected end of text at statement".into()))
+        };
+        self.depth = depth;
+        stmt
+    }
+
+    /// if_statement : IF condition THEN statement (ELSE statement)?
+    fn if_statement(&mut self) -> ParseResult<Stmt> {
+        let span = self.lexer.span;
+        self.eat(Token::KW(Keyword::IF))?;
+        let cond = self.condition()?;
+        self.eat(Token::KW(Keyword::THEN))?;
+        let then_branch = Box::new(self.statement()?);
+        let else_branch = if self.cur_token == Some(Token::KW(Keyword::ELSE)) {
+            self.eat(Token::KW(Keyword::ELSE))?;
+            Some(Box::new(self.statement()?))
         }
+        else {
+            None
+        };
+        Ok(Stmt::If { cond, then_branch, else_branch, span })
     }
 
-    /// compound_statement : BEGIN statement_list END
-    fn compound_statement(&mut self) -> AST {
-        self.eat(Token::KW(Keyword::BEGIN));
-        let node = self.statement_list();
-        self.eat(Token::KW(Keyword::END));
-        AST::new(Root::Compound).left(node)
+    /// while_statement : WHILE condition DO statement
+    fn while_statement(&mut self) -> ParseResult<Stmt> {
+        let span = self.lexer.span;
+        self.eat(Token::KW(Keyword::WHILE))?;
+        let cond = self.condition()?;
+        self.eat(Token::KW(Keyword::DO))?;
+        let body = Box::new(self.statement()?);
+        Ok(Stmt::While { cond, body, span })
     }
 
-    /// statement_list : statement | statement SEMI statement_list
-    fn statement_list(&mut self) -> AST {
-        let node = self.statement();
-        if self.cur_token == Some(Token::SEMI) {
-            self.eat(Token::SEMI);
-            AST::new(Root::Compound)
-                .left(node)
-                .right(self.statement_list())
-        }
-        else {
-            AST::new(Root::Compound)
-                .left(node)
-        }
+    /// condition : expr (EQ | NE | LT | LE | GT | GE) expr
+    fn condition(&mut self) -> ParseResult<Expr> {
+        let left = self.expr()?;
+        let span = self.lexer.span;
+        let op = match self.cur_token {
+            Some(Token::OpEQ) => BinOp::Eq,
+            Some(Token::OpNE) => BinOp::Ne,
+            Some(Token::OpLT) => BinOp::Lt,
+            Some(Token::OpLE) => BinOp::Le,
+            Some(Token::OpGT) => BinOp::Gt,
+            Some(Token::OpGE) => BinOp::Ge,
+            Some(ref tok) => return Err(self.error(format!("Expect relational operator, got {}", tok))),
+            None => return Err(self.error("Expect relational operator, got None".into())),
+        };
+        self.eat_any()?;
+        let right = self.expr()?;
+        Ok(Expr::BinOp { op, left: Box::new(left), right: Box::new(right), span })
     }
 
-    /// statement : compound_statement | assignment_statement | empty
-    fn statement(&mut self) -> AST {
-        match self.cur_token {
-            Some(Token::KW(Keyword::BEGIN)) => self.compound_statement(),
-            Some(Token::ID(_)) => self.assignment_statement(),
-            Some(_) => self.empty(),
-            None => panic!("Error at statement: {}", self.lexer.pos)
+    /// proccall_statement : ID arguments
+    fn proccall_statement(&mut self, name: Ident) -> ParseResult<Stmt> {
+        let args = self.arguments()?;
+        Ok(Stmt::Call { name, args })
+    }
+
+    /// writeln_statement : WRITELN arguments
+    fn writeln_statement(&mut self, name: Ident) -> ParseResult<Stmt> {
+        let args = self.arguments()?;
+        Ok(Stmt::Writeln { args, span: name.span })
+    }
+
+    /// arguments : (LPAREN (expr (COMMA expr)*)? RPAREN)?
+    fn arguments(&mut self) -> ParseResult<Vec<Expr>> {
+        let mut args = Vec::new();
+        if self.cur_token == Some(Token::LParen) {
+            self.eat(Token::LParen)?;
+            if self.cur_token != Some(Token::RParen) {
+                args.push(self.expr()?);
+                while self.cur_token == Some(Token::COMMA) {
+                    self.eat(Token::COMMA)?;
+                    args.push(self.expr()?);
+                }
+            }
+            self.eat(Token::RParen)?;
         }
+        Ok(args)
     }
 
     /// assignment_statement : variable ASSIGN expr
-    fn assignment_statement(&mut self) -> AST {
-        let l = self.variable();
-        self.eat(Token::ASSIGN);
-        let r = self.expr();
-        AST::new(Root::Assign)
-            .left(l)
-            .right(r)
+    fn assignment_statement(&mut self, target: Ident) -> ParseResult<Stmt> {
+        self.eat(Token::ASSIGN)?;
+        let value = self.expr()?;
+        Ok(Stmt::Assign { target, value })
     }
 
     /// An empty production
-    fn empty(&mut self) -> AST {
-        AST::new(Root::NoOp)
+    fn empty(&mut self) -> ParseResult<Stmt> {
+        Ok(Stmt::NoOp)
     }
 
     /// expr : term ((PLUS | MINUS) term)*
-    fn expr(&mut self) -> AST {
-        let mut node = self.term();
+    fn expr(&mut self) -> ParseResult<Expr> {
+        // each operator puts the operands one level deeper
+        let depth = self.depth;
+        let mut node = self.term()?;
         loop {
-            node = match self.cur_token.clone() {
-                Some(op @ Token::OpPlus) |
-                Some(op @ Token::OpMinus) => {
-                    self.eat(op.clone());
-                    AST::new(Root::BinOp(op))
-                        .left(node)
-                        .right(self.term())
-                },
+            let op = match self.cur_token {
+                Some(Token::OpPlus) => BinOp::Add,
+                Some(Token::OpMinus) => BinOp::Sub,
                 _ => break
             };
+            let span = self.lexer.span;
+            self.eat_any()?;
+            self.nest()?;
+            node = Expr::BinOp {
+                op,
+                left: Box::new(node),
+                right: Box::new(self.term()?),
+                span,
+            };
         };
-        node
+        self.depth = depth;
+        Ok(node)
     }
 
-    /// term : factor ((MUL | DIV) factor)*
-    fn term(&mut self) -> AST {
-        let mut node = self.factor();
+    /// term : factor ((MUL | DIV | INTEGER_DIV) factor)*
+    fn term(&mut self) -> ParseResult<Expr> {
+        let depth = self.depth;
+        let mut node = self.factor()?;
         loop {
-            node = match self.cur_token.clone() {
-                Some(op @ Token::OpMul) |
-                Some(op @ Token::OpDiv) => {
-                    self.eat(op.clone());
-                    AST::new(Root::BinOp(op))
-                        .left(node)
-                        .right(self.factor())
-                },
-                Some(kw @ Token::KW(Keyword::DIV)) => {
-                    self.eat(kw);
-                    AST::new(Root::BinOp(Token::OpIntegerDiv))
-                        .left(node)
-                        .right(self.factor())
-                },
+            let op = match self.cur_token {
+                Some(Token::OpMul) => BinOp::Mul,
+                Some(Token::OpDiv) => BinOp::Div,
+                Some(Token::KW(Keyword::DIV)) => BinOp::IntDiv,
                 _ => break
             };
+            let span = self.lexer.span;
+            self.eat_any()?;
+            self.nest()?;
+            node = Expr::BinOp {
+                op,
+                left: Box::new(node),
+                right: Box::new(self.factor()?),
+                span,
+            };
         };
-        node
+        self.depth = depth;
+        Ok(node)
     }
 
     /// factor : PLUS factor
     ///        | MINUS factor
-    ///        | INTEGER
+    ///        | INTEGER_CONST
+    ///        | REAL_CONST
     ///        | LPAREN expr RPAREN
+    ///        | ID LPAREN (expr (COMMA expr)*)? RPAREN
     ///        | variable
-    fn factor(&mut self) -> AST {
-        match self.cur_token.clone() {
-            Some(op @ Token::OpPlus) |
-            Some(op @ Token::OpMinus) => {
-                self.eat(op.clone());
-                AST::new(Root::UnaryOp(op))
-                    .right(self.factor())
+    fn factor(&mut self) -> ParseResult<Expr> {
+        let span = self.lexer.span;
+        let depth = self.depth;
+        self.nest()?;
+        let node = match self.cur_token.clone() {
+            Some(Token::OpPlus) => {
+                self.eat(Token::OpPlus)?;
+                let operand = Box::new(self.factor()?);
+                Ok(Expr::UnaryOp { op: UnaryOp::Plus, operand, span })
+            },
+            Some(Token::OpMinus) => {
+                self.eat(Token::OpMinus)?;
+                let operand = Box::new(self.factor()?);
+                Ok(Expr::UnaryOp { op: UnaryOp::Minus, operand, span })
             },
             Some(Token::Integer(n)) => {
-                self.eat(Token::Integer(n));
-                let val = VariableValue::Intereg(n);
-                AST::new(Root::Num(val))
+                self.eat(Token::Integer(n))?;
+                Ok(Expr::Num { value: VariableValue::Integer(n), span })
             },
             Some(Token::Real(n)) => {
-                self.eat(Token::Real(n));
-                let val = VariableValue::Real(n);
-                AST::new(Root::Num(val))
+                self.eat(Token::Real(n))?;
+                Ok(Expr::Num { value: VariableValue::Real(n), span })
             },
             Some(Token::LParen) => {
-                self.eat(Token::LParen);
-                let node = self.expr();
-                self.eat(Token::RParen);
-                node
+                self.eat(Token::LParen)?;
+                let node = self.expr()?;
+                self.eat(Token::RParen)?;
+                Ok(node)
             },
-            Some(Token::ID(_)) => self.variable(),
-            _ => panic!()
-        }
+            Some(Token::ID(_)) => {
+                let name = self.variable()?;
+                if self.cur_token == Some(Token::LParen) {
+                    let args = self.arguments()?;
+                    Ok(Expr::Call { name, args })
+                }
+                else {
+                    Ok(Expr::Var(name))
+                }
+            },
+            Some(tok) => Err(self.error(format!("Unexpected {} at factor", tok))),
+            None => Err(self.error("Unexpected end of text at factor".into())),
+        };
+        self.depth = depth;
+        node
     }
 
-    pub fn parse(&mut self) -> AST {
+    /// Syntax errors abort parsing
+    fn error(&self, message: String) -> Diagnostic {
+        Diagnostic::error(self.lexer.span, message)
+    }
+
+    /// Parse the program, stopping at the first syntax error
+    pub fn try_parse(&mut self) -> Result<Program, Diagnostic> {
         self.program()
     }
 
-    fn debug<S: Into<String>>(&self, lvl: S) {
-        let lvl = lvl.into();
-        if let Some(t) = self.cur_token.as_ref() {
-            println!("{} [{}] {}", lvl, t, self.lexer.rest());
-        }
-        else {
-            println!("{} [_] {}", lvl, self.lexer.rest());
+    /// Like `try_parse`, syntax errors panic with the `Diagnostic`
+    /// as the payload, for the tests
+    #[cfg(test)]
+    pub(crate) fn parse(&mut self) -> Program {
+        match self.program() {
+            Ok(program) => program,
+            Err(diag) => std::panic::panic_any(diag),
         }
     }
+
+    /// `{$...}` directives found in the parsed text
+    pub fn directives(&self) -> &[Directive] {
+        &self.lexer.directives
+    }
+
+    /// Comments found in the parsed text
+    pub fn comments(&self) -> &[Comment] {
+        &self.lexer.comments
+    }
 }
 
-// Some helper functions
-
-/// Set VarID type in VarDecl chain
-fn set_type(decl: &mut AST, typ: Keyword)
-{
-    match decl.root {
-        Root::VarDecl | Root::Param => {
-            let id = decl.left.as_deref_mut().unwrap();
-            if let Root::VarID{ref name, ref mut value} = id.root {
-                assert_eq!(*value, VariableValue::None);
-                *value = VariableValue::from(typ);
-            }
-            else {
-                unreachable!();
-            }
-            let decl = decl.right.as_deref_mut().unwrap();
-            set_type(decl, typ);
-        },
-        Root::Compound => {},
-        Root::NoOp => {},
-        _ => unreachable!()
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn typed_tree() {
+        let program = Parser::new(r#"
+        program Part11;
+        var number : integer;
+            a, b : integer;
+        procedure P(x : real); forward;
+        begin
+            b := 10 * a + - number DIV 4;
+            P(b)
+        end."#).parse();
+        assert_eq!(program.name.name, "PART11");
+        let names: Vec<_> = program.block.decls.iter()
+            .map(|decl| match decl {
+                Decl::Var(var) => var.name.name.as_str(),
+                Decl::Procedure(proc) => proc.name.name.as_str(),
+            })
+            .collect();
+        assert_eq!(names, ["NUMBER", "A", "B", "P"]);
+        match &program.block.body[..] {
+            [Stmt::Assign{target, value: Expr::BinOp{op: BinOp::Add, right, ..}},
+             Stmt::Call{name, args}] => {
+                assert_eq!(target.name, "B");
+                assert!(matches!(**right, Expr::BinOp{op: BinOp::IntDiv, ..}));
+                assert_eq!(name.name, "P");
+                assert_eq!(args.len(), 1);
+            },
+            body => panic!("Unexpected body: {:?}", body),
+        }
+    }
+
+    #[test]
+    fn nesting() {
+        let parse = |body: String| Parser::new(format!("begin {} end.", body)).try_parse()
+            .map_err(|diag| diag.message);
+        let parens = |n| format!("x := {}1{}", "(".repeat(n), ")".repeat(n));
+        assert!(parse(parens(MAX_NESTING - 2)).is_ok());
+        let message = Err(format!("nesting deeper than {} levels", MAX_NESTING));
+        assert_eq!(parse(parens(50_000)), message);
+        assert_eq!(parse(format!("x := 1{}", " + 1".repeat(50_000))), message);
+        assert_eq!(parse("begin ".repeat(50_000)), message);
+        assert_eq!(parse("if x > 0 then ".repeat(50_000)), message);
     }
 }
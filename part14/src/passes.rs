@@ -0,0 +1,495 @@
+use crate::ast::*;
+use crate::ir::*;
+use crate::ssa::*;
+
+use std::collections::{HashMap, HashSet};
+
+/// Run the SSA optimizations over every function of the module
+pub fn optimize(module: &mut IrModule) {
+    for index in 0..module.functions.len() {
+        into_ssa(module, index);
+        let func = &mut module.functions[index];
+        copy_propagation(func);
+        constant_propagation(func);
+        copy_propagation(func);
+        dead_code_elimination(func);
+        out_of_ssa(func);
+        merge_blocks(func);
+    }
+}
+
+/// Append a block to its only predecessor when that one jumps to it
+/// unconditionally. The function must be out of SSA.
+pub fn merge_blocks(func: &mut Function) {
+    let preds = func.predecessors();
+    for id in 0..func.blocks.len() {
+        loop {
+            let target = match func.blocks[id].term {
+                Terminator::Jump(target) if target != id && target != 0 && preds[target].len() == 1 => target,
+                _ => break,
+            };
+            let block = std::mem::replace(&mut func.blocks[target], BasicBlock {
+                insts: Vec::new(),
+                term: Terminator::Return,
+            });
+            func.blocks[id].insts.extend(block.insts);
+            func.blocks[id].term = block.term;
+        }
+    }
+    func.remove_unreachable();
+}
+
+/// Every use of an operand, temporaries only
+fn replace_uses(func: &mut Function, map: &HashMap<u32, Operand>) {
+    let lookup = |operand: &mut Operand| {
+        if let Operand::Place(Place::Temp(temp)) = operand {
+            if let Some(value) = map.get(temp) {
+                *operand = *value;
+            }
+        }
+    };
+    for block in &mut func.blocks {
+        for inst in &mut block.insts {
+            for operand in inst.operands_mut() {
+                lookup(operand);
+            }
+        }
+        if let Terminator::Branch{cond, ..} = &mut block.term {
+            lookup(cond);
+        }
+    }
+}
+
+/// Replace temporaries defined by copies, or by phis with a single
+/// distinct argument, with their source. Variables are not propagated,
+/// their value may change before the use.
+pub fn copy_propagation(func: &mut Function) {
+    let mut map: HashMap<u32, Operand> = HashMap::new();
+    for block in &func.blocks {
+        for inst in &block.insts {
+            let (temp, src) = match inst {
+                Inst::Copy{dst: Place::Temp(temp), src} => (*temp, *src),
+                Inst::Phi{dst: Place::Temp(temp), args} => {
+                    let mut distinct = args.iter()
+                        .map(|(_, arg)| *arg)
+                        .filter(|arg| *arg != Operand::Place(Place::Temp(*temp)));
+                    let first = match distinct.next() {
+                        Some(first) => first,
+                        None => continue,
+                    };
+                    if distinct.any(|arg| arg != first) {
+                        continue;
+                    }
+                    (*temp, first)
+                },
+                _ => continue,
+            };
+            if let Operand::Place(Place::Var(_)) = src {
+                continue;
+            }
+            map.insert(temp, src);
+        }
+    }
+    // resolve chains of copies
+    let temps: Vec<_> = map.keys().copied().collect();
+    for temp in temps {
+        let mut value = map[&temp];
+        let mut seen = HashSet::new();
+        while let Operand::Place(Place::Temp(next)) = value {
+            match map.get(&next) {
+                Some(next_value) if seen.insert(next) => value = *next_value,
+                _ => break,
+            }
+        }
+        map.insert(temp, value);
+    }
+    replace_uses(func, &map);
+}
+
+/// Lattice of a temporary: no value seen yet, a constant, or varying
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Lattice {
+    Top,
+    Const(VariableValue),
+    Bottom,
+}
+
+impl Lattice {
+    fn meet(self, other: Lattice) -> Lattice {
+        match (self, other) {
+            (Lattice::Top, x) | (x, Lattice::Top) => x,
+            (Lattice::Const(a), Lattice::Const(b)) if a == b => self,
+            _ => Lattice::Bottom,
+        }
+    }
+}
+
+/// Sparse conditional constant propagation: constants flow along
+/// the SSA edges and only through branches which can be taken.
+/// Temporaries proven constant are replaced, branches on constant
+/// conditions become jumps and blocks never reached are removed.
+pub fn constant_propagation(func: &mut Function) {
+    let mut values: HashMap<u32, Lattice> = HashMap::new();
+    let mut edges: HashSet<(BlockId, BlockId)> = HashSet::new();
+    let mut visited = vec![false; func.blocks.len()];
+    // uses of temporaries: block and instruction, `None` for the terminator
+    let mut uses: HashMap<u32, Vec<(BlockId, Option<usize>)>> = HashMap::new();
+    for (id, block) in func.blocks.iter().enumerate() {
+        for (n, inst) in block.insts.iter().enumerate() {
+            for operand in inst.operands() {
+                if let Operand::Place(Place::Temp(temp)) = operand {
+                    uses.entry(temp).or_default().push((id, Some(n)));
+                }
+            }
+        }
+        if let Terminator::Branch{cond: Operand::Place(Place::Temp(temp)), ..} = block.term {
+            uses.entry(temp).or_default().push((id, None));
+        }
+    }
+
+    let mut flow_work: Vec<(Option<BlockId>, BlockId)> = vec![(None, 0)];
+    let mut ssa_work: Vec<u32> = Vec::new();
+    let value_of = |values: &HashMap<u32, Lattice>, operand: Operand| match operand {
+        Operand::Const(value) => Lattice::Const(value),
+        Operand::Place(Place::Temp(temp)) => *values.get(&temp).unwrap_or(&Lattice::Top),
+        Operand::Place(Place::Var(_)) => Lattice::Bottom,
+    };
+    loop {
+        let (block, inst) = if let Some((from, to)) = flow_work.pop() {
+            if let Some(from) = from {
+                if !edges.insert((from, to)) {
+                    continue;
+                }
+            }
+            if visited[to] {
+                // only the phis see the new edge
+                let phis = func.blocks[to].insts.iter().take_while(|inst| inst.is_phi()).count();
+                for n in 0..phis {
+                    visit(func, to, Some(n), &mut values, &edges, &mut flow_work, &mut ssa_work, &value_of);
+                }
+                continue;
+            }
+            visited[to] = true;
+            for n in 0..func.blocks[to].insts.len() {
+                visit(func, to, Some(n), &mut values, &edges, &mut flow_work, &mut ssa_work, &value_of);
+            }
+            (to, None)
+        }
+        else if let Some(temp) = ssa_work.pop() {
+            for &(block, inst) in uses.get(&temp).map(|uses| uses.as_slice()).unwrap_or(&[]) {
+                if visited[block] {
+                    visit(func, block, inst, &mut values, &edges, &mut flow_work, &mut ssa_work, &value_of);
+                }
+            }
+            continue;
+        }
+        else {
+            break;
+        };
+        visit(func, block, inst, &mut values, &edges, &mut flow_work, &mut ssa_work, &value_of);
+    }
+
+    // rewrite with the results
+    let constants: HashMap<u32, Operand> = values.iter()
+        .filter_map(|(temp, value)| match value {
+            Lattice::Const(value) => Some((*temp, Operand::Const(*value))),
+            _ => None,
+        })
+        .collect();
+    replace_uses(func, &constants);
+    for (id, block) in func.blocks.iter_mut().enumerate() {
+        if !visited[id] {
+            continue;
+        }
+        block.insts.retain(|inst| match inst.dst() {
+            Some(Place::Temp(temp)) => !constants.contains_key(&temp),
+            _ => true,
+        });
+        block.term = match block.term {
+            Terminator::Branch{cond: Operand::Const(VariableValue::Boolean(taken)), then, otherwise} =>
+                Terminator::Jump(if taken { then } else { otherwise }),
+            Terminator::Branch{then, otherwise, ..} if !edges.contains(&(id, otherwise)) =>
+                Terminator::Jump(then),
+            Terminator::Branch{then, otherwise, ..} if !edges.contains(&(id, then)) =>
+                Terminator::Jump(otherwise),
+            ref term => term.clone(),
+        };
+    }
+    func.remove_unreachable();
+}
+
+/// Evaluate an instruction, or the terminator when `inst` is `None`,
+/// of a reachable block
+#[allow(clippy::too_many_arguments)]
+fn visit(
+    func: &Function,
+    block: BlockId,
+    inst: Option<usize>,
+    values: &mut HashMap<u32, Lattice>,
+    edges: &HashSet<(BlockId, BlockId)>,
+    flow_work: &mut Vec<(Option<BlockId>, BlockId)>,
+    ssa_work: &mut Vec<u32>,
+    value_of: &dyn Fn(&HashMap<u32, Lattice>, Operand) -> Lattice,
+) {
+    let inst = match inst {
+        Some(n) => &func.blocks[block].insts[n],
+        None => {
+            match &func.blocks[block].term {
+                Terminator::Jump(target) => flow_work.push((Some(block), *target)),
+                Terminator::Branch{cond, then, otherwise} => match value_of(values, *cond) {
+                    Lattice::Top => {},
+                    Lattice::Const(VariableValue::Boolean(true)) => flow_work.push((Some(block), *then)),
+                    Lattice::Const(VariableValue::Boolean(false)) => flow_work.push((Some(block), *otherwise)),
+                    _ => {
+                        flow_work.push((Some(block), *then));
+                        flow_work.push((Some(block), *otherwise));
+                    },
+                },
+                Terminator::Return => {},
+            }
+            return;
+        },
+    };
+    let temp = match inst.dst() {
+        Some(Place::Temp(temp)) => temp,
+        _ => return,
+    };
+    let constant = |value: Lattice, f: &dyn Fn(VariableValue) -> Lattice| match value {
+        Lattice::Const(value) => f(value),
+        other => other,
+    };
+    let new = match inst {
+        Inst::Copy{src, ..} => value_of(values, *src),
+        // overflow stays a runtime error
+        Inst::Neg{src, ..} => constant(value_of(values, *src), &|value| match value.checked_neg() {
+            Some(value) => Lattice::Const(value),
+            None => Lattice::Bottom,
+        }),
+        Inst::Cast{src, typ, ..} => constant(value_of(values, *src), &|value| {
+            let mut cast = VariableValue::from(*typ);
+            cast.assign(value);
+            Lattice::Const(cast)
+        }),
+        Inst::Binary{op, left, right, ..} => match (value_of(values, *left), value_of(values, *right)) {
+            (Lattice::Bottom, _) | (_, Lattice::Bottom) => Lattice::Bottom,
+            // division by zero stays a runtime error
+            (Lattice::Const(_), Lattice::Const(r)) if op.divides_by_zero(r) => Lattice::Bottom,
+            (Lattice::Const(l), Lattice::Const(r)) => match op.apply(l, r) {
+                Some(value) => Lattice::Const(value),
+                None => Lattice::Bottom,
+            },
+            _ => Lattice::Top,
+        },
+        Inst::Phi{args, ..} => args.iter()
+            .filter(|(pred, _)| edges.contains(&(*pred, block)))
+            .fold(Lattice::Top, |acc, (_, arg)| acc.meet(value_of(values, *arg))),
+        Inst::Call{..} | Inst::Writeln{..} => unreachable!(),
+    };
+    let old = *values.get(&temp).unwrap_or(&Lattice::Top);
+    let new = old.meet(new);
+    if new != old {
+        values.insert(temp, new);
+        ssa_work.push(temp);
+    }
+}
+
+/// Remove instructions whose result is never used. Calls, output,
+/// stores to variables and divisions which may fail are kept.
+pub fn dead_code_elimination(func: &mut Function) {
+    let mut defs: HashMap<u32, (BlockId, usize)> = HashMap::new();
+    for (id, block) in func.blocks.iter().enumerate() {
+        for (n, inst) in block.insts.iter().enumerate() {
+            if let Some(Place::Temp(temp)) = inst.dst() {
+                defs.insert(temp, (id, n));
+            }
+        }
+    }
+    let critical = |inst: &Inst| match inst {
+        _ if matches!(inst.dst(), Some(Place::Var(_))) => true,
+        Inst::Call{..} | Inst::Writeln{..} => true,
+        Inst::Binary{op, right, ..} => match right {
+            Operand::Const(value) => op.divides_by_zero(*value),
+            _ => matches!(op, BinOp::Div | BinOp::IntDiv),
+        },
+        _ => false,
+    };
+    let mut live: HashSet<(BlockId, usize)> = HashSet::new();
+    let mut work: Vec<Operand> = Vec::new();
+    for (id, block) in func.blocks.iter().enumerate() {
+        for (n, inst) in block.insts.iter().enumerate() {
+            if critical(inst) {
+                live.insert((id, n));
+                work.extend(inst.operands());
+            }
+        }
+        if let Terminator::Branch{cond, ..} = block.term {
+            work.push(cond);
+        }
+    }
+    while let Some(operand) = work.pop() {
+        if let Operand::Place(Place::Temp(temp)) = operand {
+            if let Some(&(id, n)) = defs.get(&temp) {
+                if live.insert((id, n)) {
+                    work.extend(func.blocks[id].insts[n].operands());
+                }
+            }
+        }
+    }
+    for (id, block) in func.blocks.iter_mut().enumerate() {
+        let mut n = 0;
+        block.insts.retain(|_| {
+            n += 1;
+            live.contains(&(id, n - 1))
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::Interpreter;
+    use crate::ireval::IrInterpreter;
+    use std::io;
+
+    /// Run the unoptimized and the optimized IR, both must end
+    /// with the same globals as the tree-walking interpreter
+    fn compare(text: &str) -> IrModule {
+        let (expected, _) = Interpreter::new(text).report(io::sink()).exec();
+        let module = Interpreter::new(text).report(io::sink()).lower().unwrap();
+        let mut optimized = module.clone();
+        optimize(&mut optimized);
+        for module in [&module, &optimized] {
+            let ctx = IrInterpreter::new(module).output(std::io::sink()).run().unwrap();
+            assert_eq!(ctx.variables, expected.variables, "{}", module);
+        }
+        optimized
+    }
+
+    #[test]
+    fn constants() {
+        let module = compare(r#"
+        program Constants;
+        var a, b, c : integer;
+            x : real;
+        begin
+            a := 2;
+            b := a * 3;
+            if b > 5 then c := b + 1 else c := 0;
+            x := c;
+            a := 7
+        end."#);
+        assert_eq!(module.to_string(), "\
+function CONSTANTS (level 1)
+    vars: A : Integer, B : Integer, C : Integer, X : Real
+bb0:
+    A = 7
+    B = 6
+    C = 7
+    X = 7.0
+    return
+");
+    }
+
+    #[test]
+    fn loops() {
+        let module = compare(r#"
+        program Loops;
+        var i, sum, unused, debug : integer;
+            avg : real;
+        procedure Add(n : integer);
+            var twice, dead : integer;
+        begin
+            dead := n * 100;
+            twice := n + n;
+            sum := sum + twice
+        end;
+        begin
+            i := 0;
+            sum := 0;
+            unused := 1;
+            debug := 0;
+            while i < 10 do
+            begin
+                if debug = 1 then writeln(i);
+                Add(i);
+                unused := unused * 2;
+                i := i + 1
+            end;
+            if i = 10 then avg := sum / i else avg := 0
+        end."#);
+        // the dead local and the constant branch are gone
+        let listing = module.to_string();
+        assert!(!listing.contains("100"), "{}", listing);
+        assert!(!listing.contains("writeln"), "{}", listing);
+        assert_eq!(module.functions[0].blocks.iter()
+            .filter(|block| matches!(block.term, Terminator::Branch{..}))
+            .count(), 2);
+    }
+
+    #[test]
+    fn nested_procedures() {
+        compare(r#"
+        program Nested;
+        var a, r : integer;
+        procedure P1(n : integer);
+            var a, k : integer;
+            procedure P2;
+            begin
+                a := a + n;
+                r := r + 1
+            end;
+        begin
+            k := 3;
+            a := 10;
+            while k > 0 do
+            begin
+                P2;
+                k := k - 1
+            end;
+            if n > 0 then P1(n - 1);
+            r := r * 2 + a
+        end;
+        begin
+            r := 0;
+            a := 7;
+            P1(2)
+        end."#);
+    }
+
+    #[test]
+    fn division_by_zero() {
+        let text = r#"
+        program Zero;
+        var a, b : integer;
+        begin
+            a := 0;
+            b := 7 DIV a
+        end."#;
+        let mut module = Interpreter::new(text).report(io::sink()).lower().unwrap();
+        optimize(&mut module);
+        let err = IrInterpreter::new(&module).run().err();
+        assert_eq!(err, Some(crate::interpreter::RuntimeError::DivisionByZero {
+            span: crate::tokens::Span { line: 6, column: 20, len: 3 },
+        }));
+    }
+
+    #[test]
+    fn integer_overflow() {
+        let text = r#"
+        program Overflow;
+        var a, b : integer;
+        begin
+            a := -2147483647 - 1;
+            b := -a
+        end."#;
+        let mut module = Interpreter::new(text).report(io::sink()).lower().unwrap();
+        let err = IrInterpreter::new(&module).run().err();
+        let overflow = Some(crate::interpreter::RuntimeError::IntegerOverflow {
+            span: crate::tokens::Span { line: 6, column: 18, len: 1 },
+        });
+        assert_eq!(err, overflow);
+        // the constant is not propagated into the negation
+        optimize(&mut module);
+        assert_eq!(IrInterpreter::new(&module).run().err(), overflow);
+    }
+}
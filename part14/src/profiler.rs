@@ -0,0 +1,222 @@
+use crate::tokens::*;
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+/// Hit count and time spent, including the procedures called
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+#[non_exhaustive]
+pub struct Hits {
+    pub count: u64,
+    pub total: Duration,
+    /// Time less the procedures called, procedures only
+    pub own: Duration,
+}
+
+/// Measures the statements and procedure calls run by the
+/// interpreter. When the program ends a report sorted by time is
+/// written to `report` and the time of each call stack to `folded`
+/// in the folded format of flamegraph.pl, one line per stack with
+/// the frames separated by `;` and the nanoseconds spent in the
+/// innermost one.
+#[derive(Default)]
+pub struct Profiler {
+    report: Option<Box<dyn Write>>,
+    folded: Option<Box<dyn Write>>,
+    statements: HashMap<Span, Hits>,
+    procedures: HashMap<String, Hits>,
+    stacks: HashMap<String, Duration>,
+    /// Running frames with their start and the time of their calls
+    frames: Vec<(String, Instant, Duration)>,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler::default()
+    }
+
+    /// Write the report to `out`
+    pub fn report(mut self, out: impl Write + 'static) -> Self {
+        self.report = Some(Box::new(out));
+        self
+    }
+
+    /// Write the folded stacks to `out`
+    pub fn folded(mut self, out: impl Write + 'static) -> Self {
+        self.folded = Some(Box::new(out));
+        self
+    }
+
+    /// Count a statement run for `time`
+    pub(crate) fn statement(&mut self, span: Span, time: Duration) {
+        let hits = self.statements.entry(span).or_default();
+        hits.count += 1;
+        hits.total += time;
+    }
+
+    /// Called when the program or a procedure starts
+    pub(crate) fn enter(&mut self, name: &str) {
+        self.frames.push((name.to_string(), Instant::now(), Duration::default()));
+    }
+
+    /// Called when the innermost frame returns
+    pub(crate) fn leave(&mut self) {
+        let stack: Vec<_> = self.frames.iter().map(|(name, ..)| name.as_str()).collect();
+        let stack = stack.join(";");
+        let (name, start, calls) = self.frames.pop().unwrap();
+        let total = start.elapsed();
+        let own = total.saturating_sub(calls);
+        if let Some(caller) = self.frames.last_mut() {
+            caller.2 += total;
+        }
+        let hits = self.procedures.entry(name).or_default();
+        hits.count += 1;
+        hits.total += total;
+        hits.own += own;
+        *self.stacks.entry(stack).or_default() += own;
+    }
+
+    /// Statements by descending time
+    pub fn statements(&self) -> Vec<(Span, Hits)> {
+        let mut statements: Vec<_> = self.statements.iter()
+            .map(|(span, hits)| (*span, *hits))
+            .collect();
+        statements.sort_by(|a, b| b.1.total.cmp(&a.1.total).then((a.0.line, a.0.column).cmp(&(b.0.line, b.0.column))));
+        statements
+    }
+
+    /// Procedures and the program by descending time
+    pub fn procedures(&self) -> Vec<(&str, Hits)> {
+        let mut procedures: Vec<_> = self.procedures.iter()
+            .map(|(name, hits)| (name.as_str(), *hits))
+            .collect();
+        procedures.sort_by(|a, b| b.1.total.cmp(&a.1.total).then(a.0.cmp(b.0)));
+        procedures
+    }
+
+    /// Call stacks in the folded format sorted by name
+    pub fn stacks(&self) -> Vec<String> {
+        let mut stacks: Vec<_> = self.stacks.iter()
+            .map(|(stack, time)| format!("{} {}", stack, time.as_nanos()))
+            .collect();
+        stacks.sort();
+        stacks
+    }
+
+    /// Write the report and the folded stacks, called when
+    /// the program ends
+    pub fn finish(&mut self) {
+        if let Some(mut out) = self.report.take() {
+            writeln!(out, "{:>8} {:>11} {:>11}  procedure", "calls", "total ms", "self ms")
+                .expect("write report");
+            for (name, hits) in self.procedures() {
+                writeln!(out, "{:>8} {:>11} {:>11}  {}",
+                    hits.count, millis(hits.total), millis(hits.own), name).expect("write report");
+            }
+            writeln!(out).expect("write report");
+            writeln!(out, "{:>8} {:>11}  statement", "hits", "total ms").expect("write report");
+            for (span, hits) in self.statements() {
+                writeln!(out, "{:>8} {:>11}  {}", hits.count, millis(hits.total), span)
+                    .expect("write report");
+            }
+        }
+        if let Some(mut out) = self.folded.take() {
+            for stack in self.stacks() {
+                writeln!(out, "{}", stack).expect("write folded stacks");
+            }
+        }
+    }
+}
+
+fn millis(time: Duration) -> String {
+    format!("{:.3}", time.as_secs_f64() * 1e3)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Capture;
+    use crate::interpreter::*;
+    use std::io;
+
+    /// Lines without the times, which change from run to run
+    fn without_times(text: &str) -> Vec<String> {
+        text.lines()
+            .map(|line| {
+                let words: Vec<_> = line.split_whitespace().collect();
+                match words.as_slice() {
+                    [] => String::new(),
+                    [first, .., last] => format!("{} {}", first, last),
+                    [word] => word.to_string(),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn profile() {
+        let report = Capture::default();
+        let folded = Capture::default();
+        let profiler = Profiler::new().report(report.clone()).folded(folded.clone());
+        Interpreter::new(r#"
+        program Profile;
+        var i : integer;
+        procedure Inc(n : integer);
+            procedure Check;
+            begin
+                if i > 10 then i := 0
+            end;
+        begin
+            i := i + n;
+            Check
+        end;
+        begin
+            i := 0;
+            while i < 3 do Inc(1)
+        end."#)
+            .report(io::sink())
+            .profiler(profiler)
+            .exec();
+        let mut counts = without_times(&report.text());
+        // times vary between runs, the program and the loop
+        // are sure to come first
+        counts[1..4].sort();
+        counts[7..].sort();
+        assert_eq!(counts, vec![
+            "calls procedure",
+            "1 PROFILE",
+            "3 CHECK",
+            "3 INC",
+            "",
+            "hits statement",
+            "1 15:13",
+            "1 14:13",
+            "3 10:13",
+            "3 11:13",
+            "3 15:28",
+            "3 7:17",
+        ]);
+        let stacks: Vec<_> = without_times(&folded.text()).into_iter()
+            .map(|line| line.split(' ').next().unwrap().to_string())
+            .collect();
+        assert_eq!(stacks, vec!["PROFILE", "PROFILE;INC", "PROFILE;INC;CHECK"]);
+    }
+
+    #[test]
+    fn own_time() {
+        let mut profiler = Profiler::new();
+        profiler.enter("MAIN");
+        profiler.enter("P");
+        std::thread::sleep(Duration::from_millis(2));
+        profiler.leave();
+        profiler.leave();
+        let procedures = profiler.procedures();
+        assert_eq!(procedures[0].0, "MAIN");
+        let (main, p) = (procedures[0].1, procedures[1].1);
+        assert!(main.total >= p.total && p.total >= Duration::from_millis(2));
+        assert_eq!(main.own + p.own, main.total);
+        assert_eq!(p.own, p.total);
+    }
+}
@@ -0,0 +1,478 @@
+use crate::tokens::*;
+use crate::lexer::*;
+use crate::parser::*;
+use crate::ast::*;
+use crate::diagnostics::*;
+use crate::interpreter::*;
+use crate::Error;
+
+use std::cell::RefCell;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::rc::Rc;
+
+pub const HELP: &str = "\
+Enter VAR and PROCEDURE declarations or statements, input is run
+when every BEGIN has its END.
+
+    :vars         print the global variables
+    :ast          print the syntax tree of the declarations
+    :load FILE    run a program keeping its declarations
+    :reset        forget all declarations and variables
+    :help         print this help
+    :quit         leave";
+
+/// What the REPL expects after a line
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Status {
+    Ready,
+    /// Unfinished BEGIN ... END, IF ... THEN and so on
+    Incomplete,
+    Quit,
+}
+
+/// Interactive session. Declarations entered so far are kept as
+/// source text and every input is run as a program made of them and
+/// the input, global variables are carried from run to run.
+pub struct Repl {
+    /// VAR sections accepted so far, one or more lines each, they
+    /// precede the procedures in the program
+    vars: String,
+    /// Procedures accepted so far
+    procs: String,
+    context: Context,
+    /// Lines of the unfinished input
+    pending: String,
+    output: Shared,
+}
+
+/// Output shared by the session and the interpreters it starts
+#[derive(Clone)]
+pub(crate) struct Shared(Rc<RefCell<Box<dyn Write>>>);
+
+impl Shared {
+    pub(crate) fn new(out: impl Write + 'static) -> Shared {
+        Shared(Rc::new(RefCell::new(Box::new(out))))
+    }
+}
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.borrow_mut().flush()
+    }
+}
+
+impl Default for Repl {
+    fn default() -> Self {
+        Repl::new()
+    }
+}
+
+impl Repl {
+    pub fn new() -> Repl {
+        Repl {
+            vars: String::new(),
+            procs: String::new(),
+            context: Context::default(),
+            pending: String::new(),
+            output: Shared::new(io::stdout()),
+        }
+    }
+
+    /// Send program output and messages to `out` instead of stdout
+    #[cfg(test)]
+    pub fn output(mut self, out: impl Write + 'static) -> Self {
+        self.output = Shared::new(out);
+        self
+    }
+
+    /// Read lines from `input` until it ends or `:quit`
+    pub fn run(&mut self, input: impl BufRead) {
+        let mut lines = input.lines();
+        let mut status = Status::Ready;
+        loop {
+            let prompt = match status {
+                Status::Ready => "pascal> ",
+                _ => "   ...> ",
+            };
+            self.print(format_args!("{}", prompt));
+            self.output.flush().expect("write output");
+            let line = match lines.next() {
+                Some(Ok(line)) => line,
+                _ => break,
+            };
+            status = self.feed(&line);
+            if status == Status::Quit {
+                break;
+            }
+        }
+    }
+
+    /// Take one line of input, it runs once the input is complete
+    pub fn feed(&mut self, line: &str) -> Status {
+        if self.pending.is_empty() {
+            if let Some(command) = line.trim().strip_prefix(':') {
+                return self.command(command);
+            }
+        }
+        self.pending.push_str(line);
+        self.pending.push('\n');
+        if !is_complete(&self.pending) {
+            return Status::Incomplete;
+        }
+        let input = std::mem::take(&mut self.pending);
+        self.input(&input);
+        Status::Ready
+    }
+
+    fn command(&mut self, command: &str) -> Status {
+        let mut words = command.splitn(2, char::is_whitespace);
+        match (words.next().unwrap_or(""), words.next().map(str::trim)) {
+            ("vars", None) => {
+                let mut vars: Vec<_> = self.context.variables.clone().into_iter().collect();
+                vars.sort_by(|a, b| a.0.cmp(&b.0));
+                for (name, value) in vars {
+                    self.print(format_args!("{} = {}\n", name, value));
+                }
+            },
+            ("ast", None) => {
+                let text = format!("PROGRAM REPL;\n{}{}BEGIN\nEND.", self.vars, self.procs);
+                match Parser::new(text).try_parse() {
+                    Ok(program) => self.print(format_args!("{:#?}\n", program)),
+                    Err(diag) => self.print(format_args!("{}\n", diag)),
+                }
+            },
+            ("reset", None) => {
+                self.vars.clear();
+                self.procs.clear();
+                self.context = Context::default();
+            },
+            ("load", Some(path)) => self.load(path),
+            ("help", None) => self.print(format_args!("{}\n", HELP)),
+            ("quit", None) => return Status::Quit,
+            _ => self.print(format_args!("error: unknown command \":{}\", try :help\n", command)),
+        }
+        Status::Ready
+    }
+
+    /// Run a whole program file, its declarations are kept
+    fn load(&mut self, path: &str) {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) => return self.print(format_args!("error: {}: {}\n", path, e)),
+        };
+        match split_program(&text) {
+            Some((decls, body)) => {
+                if decls.trim().is_empty() || self.declare(&decls) {
+                    self.execute(&body);
+                }
+            },
+            None => self.print(format_args!("error: {}: not a program\n", path)),
+        }
+    }
+
+    fn input(&mut self, input: &str) {
+        match Lexer::new(input).get_next_token() {
+            None => {},
+            Some(Token::KW(Keyword::VAR)) | Some(Token::KW(Keyword::PROCEDURE)) => {
+                // the last declaration may miss its semicolon
+                let mut input = input.trim_end().to_string();
+                if !input.ends_with(';') {
+                    input.push(';');
+                }
+                self.declare(&input);
+            },
+            _ => self.execute(input),
+        }
+    }
+
+    /// Add declarations to the session if they are correct, the VAR
+    /// sections go after the earlier ones and the procedures after
+    /// the earlier procedures
+    fn declare(&mut self, decls: &str) -> bool {
+        let (vars, procs, procs_line) = split_decls(decls.trim_end());
+        let vars_offset = 1 + self.vars.lines().count();
+        let procs_offset = vars_offset + vars.lines().count() + self.procs.lines().count();
+        let input = [
+            (vars_offset, vars.lines().count(), 1),
+            (procs_offset, procs.lines().count(), procs_line),
+        ];
+        let text = format!("PROGRAM REPL;\n{}{}{}{}BEGIN\nEND.", self.vars, vars, self.procs, procs);
+        if !self.check(&text, &input) || !self.start(text, &input) {
+            return false;
+        }
+        self.vars.push_str(&vars);
+        self.procs.push_str(&procs);
+        true
+    }
+
+    /// Run statements with the session declarations
+    fn execute(&mut self, body: &str) {
+        let body = format!("{}\n", body.trim_end());
+        let offset = 2 + self.vars.lines().count() + self.procs.lines().count();
+        let input = [(offset, body.lines().count(), 1)];
+        let text = format!("PROGRAM REPL;\n{}{}BEGIN\n{}END.", self.vars, self.procs, body);
+        if self.check(&text, &input) {
+            self.start(text, &input);
+        }
+    }
+
+    /// Report problems of the input, whose lines are at `input` in
+    /// `text`, false on errors
+    fn check(&mut self, text: &str, input: &[(usize, usize, usize)]) -> bool {
+        let diagnostics = crate::analyze(text);
+        let inside = |span: &Span| rebase(*span, input).is_some();
+        for diag in &diagnostics {
+            // variables are assigned and used across inputs
+            let relevant = match diag.code {
+                None => true,
+                Some(code) => code == WarningCode::UnusedParameter || code == WarningCode::Shadowing,
+            };
+            if !relevant || !(diag.is_error() || inside(&diag.span)) {
+                continue;
+            }
+            let mut diag = diag.clone();
+            // e.g. the END added after the input has no location
+            diag.span = rebase(diag.span, input).unwrap_or_default();
+            diag.notes = diag.notes.into_iter()
+                .filter_map(|(span, note)| Some((rebase(span, input)?, note)))
+                .collect();
+            self.print(format_args!("{}\n", diag));
+        }
+        !diagnostics.iter().any(|diag| diag.is_error())
+    }
+
+    /// Run the checked program keeping its globals, they are left
+    /// as they were when it fails
+    fn start(&mut self, text: String, input: &[(usize, usize, usize)]) -> bool {
+        let interpreter = Interpreter::new(text)
+            .output(self.output.clone())
+            .report(io::sink())
+            .context(self.context.clone());
+        match interpreter.run() {
+            Ok((context, res)) => {
+                self.context = context;
+                if res != VariableValue::None {
+                    self.print(format_args!("{}\n", res));
+                }
+                true
+            },
+            // reported by `check`
+            Err(Error::Invalid(_)) => false,
+            Err(Error::Runtime(mut e)) => {
+                match &mut e {
+                    RuntimeError::UndefinedVariable{span, ..} |
+                    RuntimeError::DivisionByZero{span} |
+                    RuntimeError::IntegerOverflow{span} |
+                    RuntimeError::Stopped{span} |
+                    RuntimeError::StepLimit{span, ..} |
+                    RuntimeError::DepthLimit{span, ..} |
+                    RuntimeError::MemoryLimit{span, ..} |
+                    RuntimeError::Timeout{span, ..} |
+                    RuntimeError::NativeResult{span, ..} |
+                    RuntimeError::StackOverflow{span} |
+                    RuntimeError::Output{span, ..} |
+                    RuntimeError::NativePanic{span, ..} => {
+                        // e.g. inside a procedure declared by an earlier input
+                        *span = rebase(*span, input).unwrap_or_default();
+                    },
+                }
+                self.print(format_args!("{}\n", e));
+                false
+            },
+        }
+    }
+
+    fn print(&mut self, text: std::fmt::Arguments) {
+        self.output.write_fmt(text).expect("write output");
+    }
+}
+
+/// `span` relative to the input, whose parts are runs of lines of the
+/// text: `(offset, lines, first)` is `lines` lines after the first
+/// `offset` ones, starting at the input line `first`. `None` when it
+/// lies outside.
+fn rebase(span: Span, input: &[(usize, usize, usize)]) -> Option<Span> {
+    input.iter().find_map(|&(offset, lines, first)| match span.line.checked_sub(offset) {
+        Some(line) if line >= 1 && line <= lines => Some(Span { line: first + line - 1, ..span }),
+        _ => None,
+    })
+}
+
+/// VAR sections of the declarations and the procedures after them,
+/// each empty or ending with a newline, and the line the procedures
+/// start at. The procedures keep their columns.
+fn split_decls(decls: &str) -> (String, String, usize) {
+    let chars: Vec<char> = decls.chars().collect();
+    let mut lexer = Lexer::new(decls);
+    let (mut split, mut indent, mut line) = (chars.len(), 0, 1);
+    while let Some(tok) = lexer.get_next_token() {
+        if tok == Token::KW(Keyword::PROCEDURE) {
+            split = lexer.pos - lexer.span.len;
+            indent = lexer.span.column - 1;
+            line = lexer.span.line;
+            break;
+        }
+    }
+    let lines = |text: String| match text.trim_end() {
+        "" => String::new(),
+        text => format!("{}\n", text),
+    };
+    let vars = lines(chars[..split].iter().collect());
+    let procs = lines(" ".repeat(indent) + &chars[split..].iter().collect::<String>());
+    (vars, procs, line)
+}
+
+/// Every BEGIN has its END, a procedure has its body and
+/// the input does not stop in the middle of a statement
+fn is_complete(text: &str) -> bool {
+    if text.rfind('{') > text.rfind('}') {
+        return false;
+    }
+    let mut lexer = Lexer::new(text);
+    let mut depth = 0;
+    let mut first = None;
+    let mut last = None;
+    let mut body = false;
+    while let Some(tok) = lexer.get_next_token() {
+        match tok {
+            Token::KW(Keyword::BEGIN) => {
+                depth += 1;
+                body = true;
+            },
+            Token::KW(Keyword::END) => depth -= 1,
+            Token::ID(ref id) if id == "FORWARD" => body = true,
+            _ => {},
+        }
+        first = first.or_else(|| Some(tok.clone()));
+        last = Some(tok);
+    }
+    let open = matches!(last,
+        Some(Token::KW(Keyword::THEN)) | Some(Token::KW(Keyword::ELSE)) |
+        Some(Token::KW(Keyword::DO)) | Some(Token::ASSIGN) |
+        Some(Token::COMMA) | Some(Token::LParen));
+    let procedure = first == Some(Token::KW(Keyword::PROCEDURE));
+    depth <= 0 && !open && (body || !procedure)
+}
+
+/// Declarations and statements of the main block of a program,
+/// the header is optional
+fn split_program(text: &str) -> Option<(String, String)> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut lexer = Lexer::new(text);
+    let mut start = 0;
+    let mut begins = Vec::new();
+    // BEGIN and END of the last closed block
+    let mut main = None;
+    let mut header = 0;
+    while let Some(tok) = lexer.get_next_token() {
+        let end = lexer.pos;
+        let begin = end - lexer.span.len;
+        match tok {
+            Token::KW(Keyword::PROGRAM) if header == 0 => header = 1,
+            Token::SEMI if header == 1 => {
+                header = 2;
+                start = end;
+            },
+            Token::KW(Keyword::BEGIN) => begins.push(begin),
+            Token::KW(Keyword::END) => main = begins.pop().map(|b| (b, begin)),
+            _ => {},
+        }
+    }
+    let (begin, end) = main?;
+    let decls = chars[start..begin].iter().collect();
+    let body = chars[begin + "BEGIN".len()..end].iter().collect();
+    Some((decls, body))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Capture;
+
+    #[test]
+    fn session() {
+        let out = Capture::default();
+        let mut repl = Repl::new().output(out.clone());
+        assert_eq!(repl.feed("var x, y : integer"), Status::Ready);
+        assert_eq!(repl.feed("x := 6 * 7"), Status::Ready);
+        assert_eq!(out.take(), "42\n");
+        assert_eq!(repl.feed("procedure Add(n : integer);"), Status::Incomplete);
+        assert_eq!(repl.feed("begin"), Status::Incomplete);
+        assert_eq!(repl.feed("    y := y + n"), Status::Incomplete);
+        assert_eq!(repl.feed("end"), Status::Ready);
+        assert_eq!(repl.feed("while x > 40 do"), Status::Incomplete);
+        assert_eq!(repl.feed("begin Add(x); x := x - 1 end;"), Status::Ready);
+        assert_eq!(repl.feed("writeln(y)"), Status::Ready);
+        assert_eq!(out.take(), "83\n");
+        repl.feed(":vars");
+        assert_eq!(out.take(), "X = 40\nY = 83\n");
+        repl.feed(":reset");
+        repl.feed(":vars");
+        assert_eq!(out.take(), "");
+        assert_eq!(repl.feed(":quit"), Status::Quit);
+    }
+
+    #[test]
+    fn errors() {
+        let out = Capture::default();
+        let mut repl = Repl::new().output(out.clone());
+        repl.feed("var a : integer;");
+        repl.feed("a := 5;");
+        out.take();
+        repl.feed("var a : real;");
+        assert_eq!(out.take(),
+            "1:5: error: variable \"A\" already defined\n");
+        // the state is kept when a statement fails
+        repl.feed("begin a := 1;");
+        repl.feed("  a := a div 0 end");
+        assert_eq!(out.take(), "2:10: runtime error: division by zero\n");
+        repl.feed("writeln(a)");
+        repl.feed("b := 1");
+        assert_eq!(out.take(), "5\n1:1: error: variable \"B\" not defined\n");
+        repl.feed("a := (1");
+        assert_eq!(out.take(), "error: Expect ), got END\n");
+        // the failing statement was declared by an earlier input
+        repl.feed("var z : integer;");
+        repl.feed("procedure P; begin z := 1 div z end;");
+        repl.feed("P;");
+        assert_eq!(out.take(), "runtime error: division by zero\n");
+        repl.feed(":frobnicate");
+        assert_eq!(out.take(), "error: unknown command \":frobnicate\", try :help\n");
+    }
+
+    #[test]
+    fn declaration_order() {
+        let out = Capture::default();
+        let mut repl = Repl::new().output(out.clone());
+        repl.feed("procedure P; begin end;");
+        repl.feed("var y : integer;");
+        repl.feed("y := 3; P; writeln(y)");
+        assert_eq!(out.take(), "3\n");
+        // the procedure goes after the earlier ones, its errors keep
+        // their location in the input
+        repl.feed("var w : integer; procedure Q; begin P; w := v end;");
+        assert_eq!(out.take(), "1:45: error: variable \"V\" not defined\n");
+        repl.feed("var w : integer;\nprocedure Q; begin P; w := 1 end;");
+        repl.feed("Q; writeln(w + y)");
+        assert_eq!(out.take(), "4\n");
+    }
+
+    #[test]
+    fn load() {
+        let out = Capture::default();
+        let mut repl = Repl::new().output(out.clone());
+        repl.feed(":load testdata/part14.pas");
+        let text = out.take();
+        assert!(text.starts_with("8:16: warning W003: parameter \"II\" is never used\n"));
+        assert!(text.ends_with("\n3\n3.500000\n"));
+        repl.feed("foo2(1); writeln(x + y)");
+        assert_eq!(out.take(), "6\n");
+        repl.feed(":ast");
+        assert!(out.take().contains("name: \"FOO3\""));
+    }
+}
@@ -0,0 +1,263 @@
+use crate::tokens::*;
+use crate::ast::*;
+use crate::ir::*;
+
+use std::collections::HashSet;
+
+/// Immediate dominator of every block, the entry dominates itself
+/// and unreachable blocks have none
+pub fn dominators(func: &Function) -> Vec<Option<BlockId>> {
+    let rpo = func.reverse_postorder();
+    let mut order = vec![usize::MAX; func.blocks.len()];
+    for (n, id) in rpo.iter().enumerate() {
+        order[*id] = n;
+    }
+    let preds = func.predecessors();
+    let mut idom = vec![None; func.blocks.len()];
+    idom[0] = Some(0);
+    let intersect = |idom: &[Option<BlockId>], mut a: BlockId, mut b: BlockId| {
+        while a != b {
+            while order[a] > order[b] {
+                a = idom[a].unwrap();
+            }
+            while order[b] > order[a] {
+                b = idom[b].unwrap();
+            }
+        }
+        a
+    };
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &id in &rpo[1..] {
+            let mut new = None;
+            for &pred in &preds[id] {
+                if idom[pred].is_some() {
+                    new = Some(match new {
+                        None => pred,
+                        Some(new) => intersect(&idom, pred, new),
+                    });
+                }
+            }
+            if new != idom[id] {
+                idom[id] = new;
+                changed = true;
+            }
+        }
+    }
+    idom
+}
+
+/// Blocks where the dominance of each block ends
+pub fn dominance_frontiers(func: &Function, idom: &[Option<BlockId>]) -> Vec<HashSet<BlockId>> {
+    let mut frontiers = vec![HashSet::new(); func.blocks.len()];
+    for (id, preds) in func.predecessors().iter().enumerate() {
+        if preds.len() < 2 || idom[id].is_none() {
+            continue;
+        }
+        for &pred in preds {
+            let mut runner = pred;
+            while idom[runner].is_some() && Some(runner) != idom[id] {
+                frontiers[runner].insert(id);
+                runner = idom[runner].unwrap();
+            }
+        }
+    }
+    frontiers
+}
+
+/// Own variables of the function which no nested procedure accesses,
+/// they can be kept in temporaries
+pub fn promotable(module: &IrModule, index: usize) -> Vec<usize> {
+    let func = &module.functions[index];
+    let captured: HashSet<Span> = module.functions.iter()
+        .flat_map(|other| other.vars.iter().filter(move |var| var.level < other.level))
+        .map(|var| var.decl)
+        .collect();
+    (0..func.vars.len())
+        .filter(|var| func.is_local(*var) && !captured.contains(&func.vars[*var].decl))
+        .collect()
+}
+
+/// Rewrite the promotable variables of the function into SSA
+/// temporaries with phis at the iterated dominance frontiers.
+/// Globals of the program are stored back before returning.
+pub fn into_ssa(module: &mut IrModule, index: usize) {
+    let promoted = promotable(module, index);
+    let func = &mut module.functions[index];
+    func.remove_unreachable();
+    let idom = dominators(func);
+    let frontiers = dominance_frontiers(func, &idom);
+
+    // phi placement, `phi_vars[block]` lists the variables of its phis
+    let preds = func.predecessors();
+    let mut phi_vars = vec![Vec::new(); func.blocks.len()];
+    for &var in &promoted {
+        let mut work: Vec<BlockId> = (0..func.blocks.len())
+            .filter(|id| func.blocks[*id].insts.iter().any(|inst| inst.dst() == Some(Place::Var(var))))
+            .collect();
+        let mut placed = HashSet::new();
+        while let Some(id) = work.pop() {
+            for &frontier in &frontiers[id] {
+                if placed.insert(frontier) {
+                    let args = preds[frontier].iter()
+                        .map(|pred| (*pred, Operand::Place(Place::Var(var))))
+                        .collect();
+                    let block = &mut func.blocks[frontier];
+                    block.insts.insert(0, Inst::Phi { dst: Place::Var(var), args });
+                    phi_vars[frontier].insert(0, var);
+                    work.push(frontier);
+                }
+            }
+        }
+    }
+
+    let mut children = vec![Vec::new(); func.blocks.len()];
+    for (id, dom) in idom.iter().enumerate().skip(1) {
+        children[dom.unwrap()].push(id);
+    }
+    let mut renamer = Renamer {
+        func,
+        promoted: &promoted,
+        stacks: Vec::new(),
+        phi_vars,
+        children,
+        store_globals: index == 0,
+    };
+    renamer.stacks = (0..renamer.func.vars.len())
+        .map(|var| {
+            let initial = if var < renamer.func.params {
+                Operand::Place(Place::Var(var))
+            }
+            else {
+                Operand::Const(VariableValue::from(renamer.func.vars[var].typ))
+            };
+            vec![initial]
+        })
+        .collect();
+    renamer.rename(0);
+}
+
+struct Renamer<'a> {
+    func: &'a mut Function,
+    promoted: &'a [usize],
+    /// Current value of every variable
+    stacks: Vec<Vec<Operand>>,
+    phi_vars: Vec<Vec<usize>>,
+    /// Dominator tree
+    children: Vec<Vec<BlockId>>,
+    store_globals: bool,
+}
+
+impl<'a> Renamer<'a> {
+    fn temp(&mut self) -> Place {
+        self.func.temps += 1;
+        Place::Temp(self.func.temps - 1)
+    }
+
+    fn rename(&mut self, block: BlockId) {
+        let mut pushed = Vec::new();
+        let insts = std::mem::take(&mut self.func.blocks[block].insts);
+        let mut renamed = Vec::with_capacity(insts.len());
+        for mut inst in insts {
+            if !inst.is_phi() {
+                for operand in inst.operands_mut() {
+                    if let Some(var) = promoted_var(self.promoted, operand) {
+                        *operand = *self.stacks[var].last().unwrap();
+                    }
+                }
+            }
+            let var = match inst.dst() {
+                Some(Place::Var(var)) if self.promoted.contains(&var) => var,
+                _ => {
+                    renamed.push(inst);
+                    continue;
+                },
+            };
+            let dst = self.temp();
+            let typ = self.func.vars[var].typ;
+            // a real variable converts integers assigned to it
+            let value = match inst {
+                Inst::Copy{src, ..} if typ == Type::Real => {
+                    renamed.push(Inst::Cast { dst, src, typ });
+                    dst
+                },
+                Inst::Binary{..} | Inst::Neg{..} if typ == Type::Real => {
+                    *inst.dst_mut().unwrap() = dst;
+                    renamed.push(inst);
+                    let cast = self.temp();
+                    renamed.push(Inst::Cast { dst: cast, src: Operand::Place(dst), typ });
+                    cast
+                },
+                _ => {
+                    *inst.dst_mut().unwrap() = dst;
+                    renamed.push(inst);
+                    dst
+                },
+            };
+            self.stacks[var].push(Operand::Place(value));
+            pushed.push(var);
+        }
+        if self.store_globals && self.func.blocks[block].term == Terminator::Return {
+            for &var in self.promoted {
+                let src = *self.stacks[var].last().unwrap();
+                renamed.push(Inst::Copy { dst: Place::Var(var), src });
+            }
+        }
+        self.func.blocks[block].insts = renamed;
+        if let Terminator::Branch{cond, ..} = &mut self.func.blocks[block].term {
+            if let Some(var) = promoted_var(self.promoted, cond) {
+                *cond = *self.stacks[var].last().unwrap();
+            }
+        }
+
+        for succ in self.func.blocks[block].term.successors() {
+            for (n, var) in self.phi_vars[succ].clone().into_iter().enumerate() {
+                let value = *self.stacks[var].last().unwrap();
+                if let Inst::Phi{args, ..} = &mut self.func.blocks[succ].insts[n] {
+                    for (pred, arg) in args.iter_mut() {
+                        if *pred == block {
+                            *arg = value;
+                        }
+                    }
+                }
+            }
+        }
+        for child in self.children[block].clone() {
+            self.rename(child);
+        }
+        for var in pushed {
+            self.stacks[var].pop();
+        }
+    }
+}
+
+fn promoted_var(promoted: &[usize], operand: &Operand) -> Option<usize> {
+    match operand {
+        Operand::Place(Place::Var(var)) if promoted.contains(var) => Some(*var),
+        _ => None,
+    }
+}
+
+/// Replace phis with copies: every predecessor writes a fresh
+/// temporary which the phi block copies into the phi destination
+pub fn out_of_ssa(func: &mut Function) {
+    for block in 0..func.blocks.len() {
+        let phis: Vec<_> = func.blocks[block].insts.iter()
+            .take_while(|inst| inst.is_phi())
+            .cloned()
+            .collect();
+        for (n, phi) in phis.into_iter().enumerate() {
+            let (dst, args) = match phi {
+                Inst::Phi{dst, args} => (dst, args),
+                _ => unreachable!(),
+            };
+            func.temps += 1;
+            let copy = Place::Temp(func.temps - 1);
+            for (pred, arg) in args {
+                func.blocks[pred].insts.push(Inst::Copy { dst: copy, src: arg });
+            }
+            func.blocks[block].insts[n] = Inst::Copy { dst, src: Operand::Place(copy) };
+        }
+    }
+}
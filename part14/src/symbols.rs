@@ -1,15 +1,79 @@
+use crate::tokens::*;
 use crate::ast::*;
+use crate::diagnostics::*;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 pub type VariableTable = HashMap<String, VariableValue>;
 
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum VarKind {
+    Variable,
+    Parameter,
+}
+
+#[derive(Debug)]
+pub struct VarSymbol {
+    pub typ: Type,
+    pub kind: VarKind,
+    pub span: Span,
+    pub reads: u32,
+    pub writes: u32,
+}
+
+#[derive(Debug)]
+pub struct ProcSymbol {
+    /// Parameter names and types
+    pub params: Vec<(String, Type)>,
+    /// Location of the first declaration
+    pub span: Span,
+    pub calls: u32,
+    /// Declared FORWARD and the body is not met yet
+    pub forward: bool,
+    /// Type of the result of native functions
+    pub result: Option<Type>,
+}
+
+/// Declaration a name refers to: level of the declaring scope
+/// and location of the declaration
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Resolution {
+    pub level: u32,
+    pub decl: Span,
+    /// Type of the variable or the function result
+    pub typ: Option<Type>,
+}
+
+/// Static type of an expression from the types of the names it
+/// resolves to, mixed arithmetic is real and conditions are integer
+/// 0 or 1. `None` when a name is not defined.
+pub fn type_of(expr: &Expr, resolutions: &HashMap<Span, Resolution>) -> Option<Type> {
+    match expr {
+        Expr::Num{value: VariableValue::Boolean(_), ..} => Some(Type::Integer),
+        Expr::Num{value, ..} => Some(value.type_of()),
+        Expr::Var(var) => resolutions.get(&var.span)?.typ,
+        Expr::BinOp{op, ..} if op.is_relational() => Some(Type::Integer),
+        Expr::BinOp{op: BinOp::Div, ..} => Some(Type::Real),
+        Expr::BinOp{op: BinOp::IntDiv, ..} => Some(Type::Integer),
+        Expr::BinOp{left, right, ..} =>
+            match (type_of(left, resolutions)?, type_of(right, resolutions)?) {
+                (Type::Integer, Type::Integer) => Some(Type::Integer),
+                _ => Some(Type::Real),
+            },
+        Expr::UnaryOp{operand, ..} => type_of(operand, resolutions),
+        Expr::Call{name, ..} => resolutions.get(&name.span)?.typ,
+    }
+}
+
 #[derive(Debug)]
 pub struct SymbolTable {
     pub scope_name: String,
     pub scope_level: u32,
     pub enclosing_scope: Option<Box<SymbolTable>>,
-    pub variables: VariableTable,
+    pub variables: HashMap<String, VarSymbol>,
+    pub procedures: HashMap<String, ProcSymbol>,
+    /// Variables of this scope definitely assigned at the current point
+    pub initialized: HashSet<String>,
 }
 
 impl SymbolTable {
@@ -18,31 +82,61 @@ impl SymbolTable {
             scope_name: name.into(),
             scope_level: lvl,
             enclosing_scope: None,
-            variables: VariableTable::default(),
+            variables: HashMap::default(),
+            procedures: HashMap::default(),
+            initialized: HashSet::default(),
         }
     }
 
-    pub fn define(&mut self, var: &Root) {
-        let (id, value) = match var {
-            Root::VarID{name, value} => (name, value),
-            _ => unreachable!()
+    pub fn define(&mut self, var: &Ident, typ: Type, kind: VarKind) {
+        assert!(!self.variables.contains_key(&var.name));
+        let symbol = VarSymbol {
+            typ,
+            kind,
+            span: var.span,
+            reads: 0,
+            writes: 0,
         };
+        self.variables.insert(var.name.to_string(), symbol);
+    }
+
+    pub fn define_procedure(&mut self, name: &str, symbol: ProcSymbol) {
+        assert!(!self.procedures.contains_key(name));
+        self.procedures.insert(name.to_string(), symbol);
+    }
+
+    pub fn lookup(&mut self, id: &str) -> (u32, &mut VarSymbol) {
+        match self.resolve_mut(id) {
+            Some(scope) => (scope.scope_level, scope.variables.get_mut(id).unwrap()),
+            None => panic!("Variable \"{}\" not defined", id),
+        }
+    }
+
+    /// Find the procedure `id` and the level of the scope declaring it
+    pub fn resolve_procedure(&mut self, id: &str) -> Option<(u32, &mut ProcSymbol)> {
+        if self.procedures.contains_key(id) {
+            return Some((self.scope_level, self.procedures.get_mut(id).unwrap()));
+        }
+        self.enclosing_scope.as_deref_mut()?.resolve_procedure(id)
+    }
+
+    /// Find the scope where `id` is defined, starting from this one
+    pub fn resolve(&self, id: &str) -> Option<&SymbolTable> {
         if self.variables.contains_key(id) {
-            panic!("Variable \"{}\" already defined", id);
+            Some(self)
+        }
+        else {
+            self.enclosing_scope.as_ref()?.resolve(id)
         }
-        self.variables.insert(id.to_string(), *value);
-        println!("Define: {:?}", var);
     }
 
-    pub fn lookup(&mut self, var: &Root) {
-        let id = match var {
-            Root::VarID{name, value} => name,
-            _ => unreachable!()
-        };
-        if !self.variables.contains_key(id) {
-            panic!("Variable \"{}\" not defined", id);
+    pub fn resolve_mut(&mut self, id: &str) -> Option<&mut SymbolTable> {
+        if self.variables.contains_key(id) {
+            Some(self)
+        }
+        else {
+            self.enclosing_scope.as_deref_mut()?.resolve_mut(id)
         }
-        println!("Lookup: {}", id);
     }
 }
 
@@ -50,6 +144,14 @@ impl SymbolTable {
 #[derive(Default)]
 pub struct SemanticAnalyzer {
     pub scope: Option<Box<SymbolTable>>,
+    /// Scopes already left, innermost first
+    pub scopes: Vec<SymbolTable>,
+    pub diagnostics: Vec<Diagnostic>,
+    /// Declarations of variable references and procedure calls
+    /// keyed by the location of the reference
+    pub resolutions: HashMap<Span, Resolution>,
+    /// Bodies of FORWARD declared procedures keyed by the declaration
+    forwards: HashMap<Span, Span>,
 }
 
 impl SemanticAnalyzer {
@@ -73,103 +175,467 @@ impl SemanticAnalyzer {
     }
 }
 
-impl NodeVisitor for SemanticAnalyzer {
-    type Result = ();
-    fn visit(&mut self, node: &AST) {
-        match &node.root {
-            Root::Program{name} => self.program(node),
-            Root::Compound => self.compound(node),
-            Root::Num(_) => (),
-            Root::VarDecl => self.variable_decl(node),
-            Root::VarID{name, value} => self.variable(node),
-            Root::ProcedureDecl{name} => self.procedure_decl(node),
-            Root::Param => self.param(node),
-            Root::Assign => self.assign(node),
-            Root::BinOp(_)   => self.binary(node),
-            Root::UnaryOp(_) => self.unary(node),
-            Root::NoOp => (),
-        }
-    }
-}
-
-impl SemanticAnalyzer {
-    fn program(&mut self, node: &AST) {
+impl Visitor for SemanticAnalyzer {
+    fn visit_program(&mut self, program: &Program) {
         let global_scope = SymbolTable::new("global", 1);
         self.push_scope(global_scope);
-        println!("ENTER scope: global");
         // visit subtree
-        let left = node.left.as_ref().unwrap();
-        self.visit(left);
+        self.visit_block(&program.block);
 
         let global_scope = self.pop_scope();
-        println!("{:?}", global_scope);
-        println!("LEAVE scope: global");
-        assert!(self.scope.is_none());
-    }
-
-    fn compound(&mut self, node: &AST) {
-        let left = node.left.as_ref().unwrap();
-        self.visit(left);
-        if node.right.is_some() {
-            let right = node.right.as_ref().unwrap();
-            self.visit(right);
+        assert!(self.scope.as_ref().is_none_or(|scope| scope.scope_level == 0));
+        self.check_forwards(&global_scope);
+        self.scopes.push(global_scope);
+        // calls made before the body refer to the FORWARD declaration
+        for resolution in self.resolutions.values_mut() {
+            if let Some(body) = self.forwards.get(&resolution.decl) {
+                resolution.decl = *body;
+            }
         }
     }
 
-    fn procedure_decl(&mut self, node: &AST) {
-        println!("ENTER scope: {}", node.get_name());
+    fn visit_procedure_decl(&mut self, decl: &ProcedureDecl) {
+        self.define_procedure(decl);
+        let block = match &decl.block {
+            Some(block) => block,
+            None => return,
+        };
+        let name = &decl.name.name;
         let proc_scope = SymbolTable::new(
-            node.get_name(),
+            name.as_str(),
             self.current_scope().scope_level + 1);
         self.push_scope(proc_scope);
-        let left = node.left.as_ref().unwrap();
-        self.visit(left);
-        let right = node.right.as_ref().unwrap();
-        self.visit(right);
+        for param in &decl.params {
+            self.visit_param(param);
+        }
+        self.visit_block(block);
         let proc_scope = self.pop_scope();
-        println!("{:?}", proc_scope);
-        println!("LEAVE scope: {}", node.get_name());
+        self.check_forwards(&proc_scope);
+        self.scopes.push(proc_scope);
+    }
+
+    fn visit_param(&mut self, param: &Param) {
+        self.declare(&param.name, param.typ, VarKind::Parameter);
+        // parameters are always assigned by the caller
+        self.current_scope().initialized.insert(param.name.name.to_string());
+    }
+
+    fn visit_var_decl(&mut self, decl: &VarDecl) {
+        self.declare(&decl.name, decl.typ, VarKind::Variable);
+    }
+
+    fn visit_stmt(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Assign{target, value} => self.assign(target, value),
+            Stmt::Call{name, args} => self.procedure_call(name, args, false),
+            Stmt::If{cond, then_branch, else_branch, ..} =>
+                self.if_statement(cond, then_branch, else_branch.as_deref()),
+            Stmt::While{cond, body, ..} => {
+                self.visit_expr(cond);
+                // the body may be skipped, it initializes nothing
+                let before = self.current_scope().initialized.clone();
+                self.visit_stmt(body);
+                self.current_scope().initialized = before;
+            },
+            _ => walk_stmt(self, stmt),
+        }
+    }
+
+    fn visit_expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Call{name, args} => self.procedure_call(name, args, true),
+            _ => walk_expr(self, expr),
+        }
     }
 
-    fn param(&mut self, node: &AST) {
-        let left = node.left.as_ref().unwrap();
-        self.current_scope().define(&left.root);
-        let right = node.right.as_ref().unwrap();
-        self.visit(right);
+    fn visit_var(&mut self, var: &Ident) {
+        match self.reference(var) {
+            Some(symbol) => symbol.reads += 1,
+            None => return,
+        }
+        let scope = self.current_scope();
+        let name = &var.name;
+        if scope.variables.contains_key(name) && !scope.initialized.contains(name) {
+            self.diagnostics.push(Diagnostic::warning(
+                WarningCode::Uninitialized, var.span,
+                format!("variable \"{}\" may be used uninitialized", name)));
+            // report each variable once
+            self.current_scope().initialized.insert(name.to_string());
+        }
     }
+}
 
-    fn variable_decl(&mut self, node: &AST) {
-        let left = node.left.as_ref().unwrap();
-        self.current_scope().define(&left.root);
-        let right = node.right.as_ref().unwrap();
-        self.visit(right);
+impl SemanticAnalyzer {
+    /// Define a native procedure, or function when it has a `result`,
+    /// in the builtin scope enclosing the program. Calls of it resolve
+    /// to level 0 and no declaration.
+    pub fn define_builtin(&mut self, name: &str, params: &[Type], result: Option<Type>) {
+        let scope = self.scope.get_or_insert_with(|| Box::new(SymbolTable::new("builtins", 0)));
+        assert_eq!(scope.scope_level, 0);
+        let params = params.iter().enumerate()
+            .map(|(n, typ)| (format!("ARG{}", n + 1), *typ))
+            .collect();
+        scope.define_procedure(&name.to_ascii_uppercase(), ProcSymbol {
+            params,
+            span: Span::default(),
+            calls: 0,
+            forward: false,
+            result,
+        });
     }
 
-    fn assign(&mut self, node: &AST) {
+    /// Define procedure symbol in the current scope, the body of
+    /// a FORWARD declaration must repeat its parameters
+    fn define_procedure(&mut self, decl: &ProcedureDecl) {
+        let forward = decl.block.is_none();
+        let params: Vec<_> = decl.params.iter()
+            .map(|param| (param.name.name.to_string(), param.typ))
+            .collect();
+        let name = &decl.name.name;
+        let span = decl.name.span;
+        let diag = match self.current_scope().procedures.get_mut(name) {
+            None => {
+                let symbol = ProcSymbol {
+                    params,
+                    span,
+                    calls: 0,
+                    forward,
+                    result: None,
+                };
+                self.current_scope().define_procedure(name, symbol);
+                return;
+            },
+            Some(prev) if prev.forward && !forward => {
+                prev.forward = false;
+                let prev_span = prev.span;
+                let same = prev.params == params;
+                self.forwards.insert(prev_span, span);
+                if same {
+                    return;
+                }
+                Diagnostic::error(span,
+                        format!("procedure \"{}\" does not match its FORWARD declaration", name))
+                    .note(prev_span, "FORWARD declaration here")
+            },
+            Some(prev) => {
+                Diagnostic::error(span,
+                        format!("procedure \"{}\" already defined", name))
+                    .note(prev.span, "previous definition here")
+            },
+        };
+        self.diagnostics.push(diag);
+    }
+
+    /// Every FORWARD declaration must be followed by the body
+    /// in the same scope
+    fn check_forwards(&mut self, scope: &SymbolTable) {
+        let mut unresolved: Vec<_> = scope.procedures.iter()
+            .filter(|(_, symbol)| symbol.forward)
+            .collect();
+        unresolved.sort_by_key(|(_, symbol)| (symbol.span.line, symbol.span.column));
+        for (name, symbol) in unresolved {
+            self.diagnostics.push(Diagnostic::error(symbol.span,
+                format!("FORWARD declared procedure \"{}\" has no body", name)));
+        }
+    }
+
+    /// Define variable in the current scope checking for redeclaration
+    /// and shadowing of the enclosing scopes
+    fn declare(&mut self, var: &Ident, typ: Type, kind: VarKind) {
+        let name = &var.name;
+        let scope = self.current_scope();
+        if let Some(prev) = scope.variables.get(name) {
+            let diag = match (prev.kind, kind) {
+                (VarKind::Parameter, VarKind::Variable) =>
+                    Diagnostic::error(var.span,
+                            format!("variable \"{}\" has the same name as a parameter", name))
+                        .note(prev.span, "parameter declared here"),
+                _ =>
+                    Diagnostic::error(var.span,
+                            format!("variable \"{}\" already defined", name))
+                        .note(prev.span, "previous definition here"),
+            };
+            self.diagnostics.push(diag);
+            return;
+        }
+        let outer = scope.enclosing_scope.as_ref()
+            .and_then(|outer| outer.resolve(name))
+            .map(|outer| (outer.scope_name.clone(), outer.variables[name].span));
+        if let Some((scope_name, span)) = outer {
+            let diag = Diagnostic::warning(WarningCode::Shadowing, var.span,
+                    format!("\"{}\" shadows a variable of scope \"{}\"", name, scope_name))
+                .note(span, "shadowed declaration here");
+            self.diagnostics.push(diag);
+        }
+        self.current_scope().define(var, typ, kind);
+    }
+
+    /// Check a call of a procedure, or of a function for a `result`
+    fn procedure_call(&mut self, name: &Ident, args: &[Expr], result: bool) {
+        for arg in args {
+            self.visit_expr(arg);
+        }
+        let types: Vec<_> = args.iter().map(|arg| type_of(arg, &self.resolutions)).collect();
+        let scope = self.current_scope();
+        let nested = scope.procedures.contains_key(&name.name);
+        let (level, symbol) = match scope.resolve_procedure(&name.name) {
+            Some(found) => found,
+            None => {
+                let kind = if result { "function" } else { "procedure" };
+                self.diagnostics.push(Diagnostic::error(name.span,
+                    format!("{} \"{}\" not defined", kind, name.name)));
+                return;
+            },
+        };
+        symbol.calls += 1;
+        let resolution = Resolution { level, decl: symbol.span, typ: symbol.result };
+        let kind = if symbol.result.is_some() { "function" } else { "procedure" };
+        let mut errors = Vec::new();
+        if result && symbol.result.is_none() {
+            errors.push(format!("procedure \"{}\" has no result", name.name));
+        }
+        if symbol.params.len() != args.len() {
+            errors.push(format!("{} \"{}\" expects {} arguments, got {}",
+                kind, name.name, symbol.params.len(), args.len()));
+        }
+        // integers convert to real but not the other way round
+        for (n, ((_, param), arg)) in symbol.params.iter().zip(&types).enumerate() {
+            if *param == Type::Integer && *arg == Some(Type::Real) {
+                errors.push(format!("argument {} of {} \"{}\" must be {:?}, got {:?}",
+                    n + 1, kind, name.name, param, Type::Real));
+            }
+        }
+        // native procedures have no declaration to point at
+        let decl = Some(symbol.span).filter(|_| level > 0);
+        for message in errors {
+            let mut diag = Diagnostic::error(name.span, message);
+            if let Some(decl) = decl {
+                diag = diag.note(decl, "declared here");
+            }
+            self.diagnostics.push(diag);
+        }
+        self.resolutions.insert(name.span, resolution);
+        // a nested procedure may assign our locals
+        if nested {
+            let scope = self.current_scope();
+            let locals = scope.variables.keys().cloned();
+            scope.initialized.extend(locals.collect::<Vec<_>>());
+        }
+    }
+
+    fn assign(&mut self, target: &Ident, value: &Expr) {
         // right-hand side
-        let right = node.right.as_ref().unwrap();
-        self.visit(right);
+        self.visit_expr(value);
+        let typ = type_of(value, &self.resolutions);
         // left-hand side
-        let left = node.left.as_ref().unwrap();
-        self.visit(left);
+        let (decl, target_type) = match self.reference(target) {
+            Some(symbol) => {
+                symbol.writes += 1;
+                (symbol.span, symbol.typ)
+            },
+            None => return,
+        };
+        // integers convert to real but not the other way round, like
+        // the arguments of `procedure_call`
+        if target_type == Type::Integer && typ == Some(Type::Real) {
+            self.diagnostics.push(Diagnostic::error(value.span(),
+                    format!("{:?} value assigned to {:?} variable \"{}\"",
+                        Type::Real, target_type, target.name))
+                .note(decl, "declared here"));
+        }
+        let scope = self.current_scope();
+        // only locals are tracked, assignments to outer variables
+        // happen at unknown time
+        if scope.variables.contains_key(&target.name) {
+            scope.initialized.insert(target.name.to_string());
+        }
+    }
+
+    /// Resolve variable reference and remember its declaration
+    fn reference(&mut self, var: &Ident) -> Option<&mut VarSymbol> {
+        let scope = self.scope.as_mut().unwrap();
+        if scope.resolve(&var.name).is_none() {
+            self.diagnostics.push(Diagnostic::error(var.span,
+                format!("variable \"{}\" not defined", var.name)));
+            return None;
+        }
+        let (level, symbol) = scope.lookup(&var.name);
+        let resolution = Resolution { level, decl: symbol.span, typ: Some(symbol.typ) };
+        self.resolutions.insert(var.span, resolution);
+        Some(symbol)
+    }
+
+    fn if_statement(&mut self, cond: &Expr, then_branch: &Stmt, else_branch: Option<&Stmt>) {
+        self.visit_expr(cond);
+        // variable is initialized after IF only when both branches assign it
+        let before = self.current_scope().initialized.clone();
+        self.visit_stmt(then_branch);
+        let scope = self.current_scope();
+        let after_then = std::mem::replace(&mut scope.initialized, before);
+        if let Some(else_branch) = else_branch {
+            self.visit_stmt(else_branch);
+        }
+        let scope = self.current_scope();
+        scope.initialized = scope.initialized.intersection(&after_then)
+            .cloned()
+            .collect();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::*;
+
+    fn analyze(text: &str) -> Vec<Diagnostic> {
+        let tree = Parser::new(text).parse();
+        let mut semantic_analyzer = SemanticAnalyzer::default();
+        semantic_analyzer.visit_program(&tree);
+        semantic_analyzer.diagnostics
+    }
+
+    #[test]
+    fn uninitialized() {
+        let diag = analyze(r#"
+        program Test;
+        var x, y, z : integer;
+        procedure P(a : integer);
+            var b : real;
+            begin b := a + x end;
+        begin
+            if x > 0 then z := 1 else x := y;
+            y := x + z;
+            x := 2
+        end."#);
+        let diag: Vec<_> = diag.iter().map(|d| d.to_string()).collect();
+        assert_eq!(diag, vec![
+            "8:16: warning W001: variable \"X\" may be used uninitialized",
+            "8:44: warning W001: variable \"Y\" may be used uninitialized",
+            "9:22: warning W001: variable \"Z\" may be used uninitialized",
+        ]);
+    }
+
+    #[test]
+    fn forward() {
+        let diag = analyze(r#"
+        program Test;
+        procedure A(x : integer); forward;
+        procedure B(y : real); forward;
+        procedure C; forward;
+        procedure A(x : integer); begin B(x) end;
+        procedure B(x : real); begin A(1) end;
+        begin A(2) end."#);
+        let diag: Vec<_> = diag.iter().map(|d| d.to_string()).collect();
+        assert_eq!(diag, vec![
+            "7:19: error: procedure \"B\" does not match its FORWARD declaration\n    \
+                4:19: note: FORWARD declaration here",
+            "5:19: error: FORWARD declared procedure \"C\" has no body",
+        ]);
+    }
+
+    #[test]
+    fn redeclaration() {
+        let diag = analyze(r#"
+        program Test;
+        var a : integer;
+        procedure P1(k : integer);
+            var a : real;
+                k : integer;
+            procedure P2; var a, a : integer; begin end;
+            begin end;
+        procedure P1; begin end;
+        begin end."#);
+        let diag: Vec<_> = diag.iter().map(|d| d.to_string()).collect();
+        assert_eq!(diag, vec![
+            "5:17: warning W006: \"A\" shadows a variable of scope \"global\"\n    \
+                3:13: note: shadowed declaration here",
+            "6:17: error: variable \"K\" has the same name as a parameter\n    \
+                4:22: note: parameter declared here",
+            "7:31: warning W006: \"A\" shadows a variable of scope \"P1\"\n    \
+                5:17: note: shadowed declaration here",
+            "7:34: error: variable \"A\" already defined\n    \
+                7:31: note: previous definition here",
+            "9:19: error: procedure \"P1\" already defined\n    \
+                4:19: note: previous definition here",
+        ]);
     }
 
-    fn variable(&mut self, node: &AST) {
-        self.current_scope().lookup(&node.root);
+    #[test]
+    fn undefined() {
+        let diag = analyze(r#"
+        program Test;
+        var a : integer;
+        procedure P(x : integer); begin a := x end;
+        begin
+            a := b;
+            c := a;
+            Q(1);
+            P(1, 2)
+        end."#);
+        let diag: Vec<_> = diag.iter().map(|d| d.to_string()).collect();
+        assert_eq!(diag, vec![
+            "6:18: error: variable \"B\" not defined",
+            "7:13: error: variable \"C\" not defined",
+            "8:13: error: procedure \"Q\" not defined",
+            "9:13: error: procedure \"P\" expects 1 arguments, got 2\n    \
+                4:19: note: declared here",
+        ]);
     }
 
-    fn binary(&mut self, node: &AST) {
-        // Unwrap and visit
-        let left  = node.left.as_ref().unwrap();
-        let right = node.right.as_ref().unwrap();
-        self.visit(left);
-        self.visit(right);
+    #[test]
+    fn call_types() {
+        let tree = Parser::new(r#"
+        program Test;
+        var i : integer;
+            r : real;
+        procedure P(n : integer; x : real); begin end;
+        begin
+            P(i, i);
+            P(r, i);
+            P(i DIV 2, r / 2);
+            LOG(r * 2);
+            i := SQR(i) + P(1, 2.0);
+            r := SQR(1, 2)
+        end."#).parse();
+        let mut semantic_analyzer = SemanticAnalyzer::default();
+        semantic_analyzer.define_builtin("log", &[Type::Integer], None);
+        semantic_analyzer.define_builtin("sqr", &[Type::Integer], Some(Type::Integer));
+        semantic_analyzer.visit_program(&tree);
+        let diag: Vec<_> = semantic_analyzer.diagnostics.iter()
+            .filter(|d| d.is_error())
+            .map(|d| d.to_string())
+            .collect();
+        assert_eq!(diag, vec![
+            "8:13: error: argument 1 of procedure \"P\" must be Integer, got Real\n    \
+                5:19: note: declared here",
+            "10:13: error: argument 1 of procedure \"LOG\" must be Integer, got Real",
+            "11:27: error: procedure \"P\" has no result\n    \
+                5:19: note: declared here",
+            "12:18: error: function \"SQR\" expects 1 arguments, got 2",
+        ]);
+        assert_eq!(semantic_analyzer.resolutions[&Span { line: 11, column: 18, len: 3 }],
+            Resolution { level: 0, decl: Span::default(), typ: Some(Type::Integer) });
     }
 
-    fn unary(&mut self, node: &AST) {
-        assert!(node.left.is_none());
-        // Unwrap and visit
-        let right = node.right.as_ref().unwrap();
-        self.visit(right);
+    #[test]
+    fn assign_types() {
+        let diag = analyze(r#"
+        program Test;
+        var i : integer;
+            r : real;
+        begin
+            i := 5 DIV 2.5;
+            r := i;
+            i := 5 / 2;
+            i := -r
+        end."#);
+        let diag: Vec<_> = diag.iter().map(|d| d.to_string()).collect();
+        assert_eq!(diag, vec![
+            "8:20: error: Real value assigned to Integer variable \"I\"\n    \
+                3:13: note: declared here",
+            "9:18: error: Real value assigned to Integer variable \"I\"\n    \
+                3:13: note: declared here",
+        ]);
     }
 }
@@ -1,5 +1,28 @@
 use std::fmt;
 
+/// Token location in the source text (1-based line and column)
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+    pub len: usize,
+}
+
+/// Compiler directive comment, e.g. `{$WARN W002 OFF}`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Directive {
+    pub span: Span,
+    pub name: String,
+    pub args: Vec<String>,
+}
+
+/// `{ ... }` comment with the braces, directives included
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comment {
+    pub span: Span,
+    pub text: String,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     // Numbers
@@ -10,7 +33,12 @@ pub enum Token {
     OpMinus,      // '-'
     OpMul,        // '*'
     OpDiv,        // '/'
-    OpIntegerDiv, // 'DIV'
+    OpEQ,         // '='
+    OpNE,         // '<>'
+    OpLT,         // '<'
+    OpLE,         // '<='
+    OpGT,         // '>'
+    OpGE,         // '>='
     // Lexems
     ID(String),
     KW(Keyword),
@@ -22,7 +50,6 @@ pub enum Token {
     COLON,    // ':'
     COMMA,    // ','
     DOT,
-    EOF,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq)]
@@ -35,6 +62,11 @@ pub enum Keyword {
     REAL,
     BEGIN,
     END,
+    IF,
+    THEN,
+    ELSE,
+    WHILE,
+    DO,
     RESERVED,
 }
 
@@ -47,6 +79,11 @@ const RESERVED_KEYWORDS: &[(&str, Keyword)] = &[
     ("REAL",      Keyword::REAL),
     ("BEGIN",     Keyword::BEGIN),
     ("END",       Keyword::END),
+    ("IF",        Keyword::IF),
+    ("THEN",      Keyword::THEN),
+    ("ELSE",      Keyword::ELSE),
+    ("WHILE",     Keyword::WHILE),
+    ("DO",        Keyword::DO),
 ];
 
 impl Token {
@@ -75,7 +112,12 @@ impl fmt::Display for Token {
             Token::OpMinus => write!(f, "-"),
             Token::OpMul  => write!(f, "*"),
             Token::OpDiv  => write!(f, "/"),
-            Token::OpIntegerDiv => write!(f, "INTEGER_DIV"),
+            Token::OpEQ => write!(f, "="),
+            Token::OpNE => write!(f, "<>"),
+            Token::OpLT => write!(f, "<"),
+            Token::OpLE => write!(f, "<="),
+            Token::OpGT => write!(f, ">"),
+            Token::OpGE => write!(f, ">="),
             // Lexems
             Token::ID(id) => write!(f, "ID \"{}\"", id),
             Token::KW(k)  => write!(f, "{:?}", k),
@@ -87,8 +129,13 @@ impl fmt::Display for Token {
             Token::COLON  => write!(f, "COLON"),
             Token::COMMA  => write!(f, "COMMA"),
             Token::DOT => write!(f, "DOT"),
-            Token::EOF => write!(f, "EOF"),
         }
     }
 }
 
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
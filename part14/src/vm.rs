@@ -0,0 +1,328 @@
+use crate::tokens::*;
+use crate::ast::*;
+use crate::bytecode::*;
+use crate::interpreter::{Context, RuntimeError};
+use crate::limits::*;
+
+use std::io::{self, Write};
+
+/// Frame of the program or procedure call
+#[derive(Debug)]
+struct Frame {
+    proc: usize,
+    /// Index of the first local in `Vm::slots`
+    base: usize,
+    /// Index of the frame of the lexically enclosing scope
+    static_link: Option<usize>,
+    pc: usize,
+}
+
+/// Stack machine executing a compiled `Module`
+pub struct Vm<'a> {
+    module: &'a Module,
+    track_undefined: bool,
+    /// Destination of `WRITELN`
+    output: Box<dyn Write + 'a>,
+    stack: Vec<VariableValue>,
+    /// Locals of all active frames
+    slots: Vec<VariableValue>,
+    frames: Vec<Frame>,
+    meter: Meter,
+}
+
+impl<'a> Vm<'a> {
+    pub fn new(module: &'a Module) -> Self {
+        Vm {
+            module,
+            track_undefined: false,
+            output: Box::new(io::stdout()),
+            stack: Vec::new(),
+            slots: Vec::new(),
+            frames: Vec::new(),
+            meter: Meter::default(),
+        }
+    }
+
+    /// Leave declared variables undefined instead of zero-initialized,
+    /// reading them before assignment is a runtime error
+    pub fn track_undefined(mut self, on: bool) -> Self {
+        self.track_undefined = on;
+        self
+    }
+
+    /// Abort with a `RuntimeError` when the program goes over `limits`
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.meter = Meter::new(limits);
+        self
+    }
+
+    /// Send program output to `out` instead of stdout
+    pub fn output(mut self, out: impl Write + 'a) -> Self {
+        self.output = Box::new(out);
+        self
+    }
+
+    /// Run the program, the context holds the global variables
+    pub fn run(mut self) -> Result<Context, RuntimeError> {
+        self.meter.start();
+        self.enter(0, None, Span::default())?;
+        while !self.frames.is_empty() {
+            self.step()?;
+        }
+        Ok(self.context())
+    }
+
+    fn context(&self) -> Context {
+        let mut context = Context::default();
+        let main = &self.module.procedures[0];
+        for ((name, _), value) in main.locals.iter().zip(&self.slots) {
+            context.variables.insert(name.to_string(), *value);
+        }
+        context
+    }
+
+    /// Push frame of `proc` called at `span`, its arguments are
+    /// on the stack
+    fn enter(&mut self, proc: usize, static_link: Option<usize>, span: Span) -> Result<(), RuntimeError> {
+        let procedure = &self.module.procedures[proc];
+        self.meter.call(self.frames.len(), span)?;
+        self.meter.allocate(procedure.locals.len(), span)?;
+        let base = self.slots.len();
+        let params = procedure.params as usize;
+        for (_, typ) in &procedure.locals[params..] {
+            let value = if self.track_undefined {
+                VariableValue::Undefined(*typ)
+            }
+            else {
+                VariableValue::from(*typ)
+            };
+            self.slots.push(value);
+        }
+        // arguments are converted to the parameter types
+        let args = self.stack.split_off(self.stack.len() - params);
+        let params = procedure.locals[..params].iter().zip(args).map(|((_, typ), arg)| {
+            let mut value = VariableValue::from(*typ);
+            value.assign(arg);
+            value
+        });
+        self.slots.splice(base..base, params);
+        self.frames.push(Frame { proc, base, static_link, pc: 0 });
+        Ok(())
+    }
+
+    /// Index of the frame `depth` static links up from the current one
+    fn frame_index(&self, depth: u8) -> usize {
+        let mut index = self.frames.len() - 1;
+        for _ in 0..depth {
+            index = self.frames[index].static_link.unwrap();
+        }
+        index
+    }
+
+    fn slot(&self, depth: u8, slot: u16) -> usize {
+        self.frames[self.frame_index(depth)].base + slot as usize
+    }
+
+    fn pop(&mut self) -> VariableValue {
+        self.stack.pop().unwrap()
+    }
+
+    fn step(&mut self) -> Result<(), RuntimeError> {
+        let module = self.module;
+        let frame = self.frames.last_mut().unwrap();
+        let procedure = &module.procedures[frame.proc];
+        let pc = frame.pc;
+        frame.pc += 1;
+        self.meter.step(procedure.spans[pc])?;
+        match procedure.code[pc] {
+            Op::Const(index) => self.stack.push(module.constants[index as usize]),
+            Op::Load{depth, slot} => {
+                let frame = &self.frames[self.frame_index(depth)];
+                let value = self.slots[frame.base + slot as usize];
+                if let VariableValue::Undefined(_) = value {
+                    let (name, _) = &module.procedures[frame.proc].locals[slot as usize];
+                    return Err(RuntimeError::UndefinedVariable {
+                        name: name.to_string(),
+                        span: procedure.spans[pc],
+                    });
+                }
+                self.stack.push(value);
+            },
+            Op::Store{depth, slot} => {
+                let value = self.pop();
+                let index = self.slot(depth, slot);
+                self.slots[index].assign(value);
+            },
+            Op::Neg => {
+                let value = self.pop().checked_neg()
+                    .ok_or(RuntimeError::IntegerOverflow { span: procedure.spans[pc] })?;
+                self.stack.push(value);
+            },
+            Op::Binary(op) => {
+                let right = self.pop();
+                let left = self.pop();
+                if op.divides_by_zero(right) {
+                    return Err(RuntimeError::DivisionByZero { span: procedure.spans[pc] });
+                }
+                let value = op.apply(left, right)
+                    .ok_or(RuntimeError::IntegerOverflow { span: procedure.spans[pc] })?;
+                self.stack.push(value);
+            },
+            Op::Jump(target) => self.frames.last_mut().unwrap().pc = target as usize,
+            Op::JumpIfFalse(target) => {
+                if let VariableValue::Boolean(false) = self.pop() {
+                    self.frames.last_mut().unwrap().pc = target as usize;
+                }
+            },
+            Op::Call{proc, depth} => {
+                let static_link = Some(self.frame_index(depth));
+                self.enter(proc as usize, static_link, procedure.spans[pc])?;
+            },
+            Op::Writeln(count) => {
+                let args = self.stack.split_off(self.stack.len() - count as usize);
+                let output = |e: io::Error| RuntimeError::Output {
+                    error: e.to_string(),
+                    span: procedure.spans[pc],
+                };
+                for arg in args {
+                    write!(self.output, "{}", arg).map_err(output)?;
+                }
+                writeln!(self.output).map_err(output)?;
+            },
+            Op::Return => {
+                let frame = self.frames.pop().unwrap();
+                self.meter.free(module.procedures[frame.proc].locals.len());
+                // keep the globals for the context
+                if !self.frames.is_empty() {
+                    self.slots.truncate(frame.base);
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::Interpreter;
+    use crate::Error;
+
+    /// Run on both backends and check that the globals agree
+    fn compare(text: &str) -> Context {
+        let (expected, _) = Interpreter::new(text).report(io::sink()).exec();
+        let (ctx, _) = Interpreter::new(text).report(io::sink()).bytecode(true).exec();
+        assert_eq!(ctx.variables, expected.variables);
+        ctx
+    }
+
+    #[test]
+    fn procedures() {
+        let ctx = compare(r#"
+        program Nested;
+        var a, r : integer;
+            x : real;
+        procedure P1(n : integer);
+            var a : integer;
+            procedure P2;
+            begin
+                a := a + n;
+                r := r + 1
+            end;
+            procedure P3;
+                var a : real;
+            begin
+                P2
+            end;
+        begin
+            a := 10;
+            P3;
+            if n > 0 then P1(n - 1);
+            r := r * 2 + a
+        end;
+        begin
+            r := 0;
+            a := 7;
+            P1(2);
+            x := -r / 4
+        end."#);
+        assert_eq!(ctx.get_var("r"), Some(VariableValue::Integer(98)));
+        assert_eq!(ctx.get_var("x"), Some(VariableValue::Real(-24.5)));
+    }
+
+    #[test]
+    fn loops() {
+        let ctx = compare(r#"
+        program Loops;
+        var i, fact : integer;
+        begin
+            i := 1;
+            fact := 1;
+            while i <= 10 do
+            begin
+                fact := fact * i;
+                i := i + 1
+            end;
+            if fact <> 3628800 then fact := 0 else i := 0
+        end."#);
+        assert_eq!(ctx.get_var("fact"), Some(VariableValue::Integer(3628800)));
+        assert_eq!(ctx.get_var("i"), Some(VariableValue::Integer(0)));
+    }
+
+    #[test]
+    fn runtime_errors() {
+        let text = r#"
+        program Undefined;
+        var x, y : integer;
+        begin
+            x := y
+        end."#;
+        let err = Interpreter::new(text)
+            .report(io::sink())
+            .track_undefined(true)
+            .bytecode(true)
+            .run()
+            .err();
+        assert_eq!(err, Some(RuntimeError::UndefinedVariable {
+            name: "Y".into(),
+            span: Span { line: 5, column: 18, len: 1 },
+        }.into()));
+        let text = r#"
+        program Zero;
+        var a, b : integer;
+        begin
+            b := 7 DIV (3 * 2 - 6) + a
+        end."#;
+        let err = Interpreter::new(text).report(io::sink()).bytecode(true).run().err();
+        assert_eq!(err, Some(RuntimeError::DivisionByZero {
+            span: Span { line: 5, column: 20, len: 3 },
+        }.into()));
+    }
+
+    #[test]
+    fn integer_overflow() {
+        let run = |expr: &str| {
+            let text = format!(r#"
+            program Overflow;
+            var max, min, x : integer;
+            begin
+                max := 2147483647;
+                min := -max - 1;
+                x := {}
+            end."#, expr);
+            let err = Interpreter::new(text.as_str()).report(io::sink()).run().err();
+            let vm_err = Interpreter::new(text).report(io::sink()).bytecode(true).run().err();
+            assert_eq!(vm_err, err);
+            err
+        };
+        let overflow = |column, len| Some(Error::Runtime(RuntimeError::IntegerOverflow {
+            span: Span { line: 7, column, len },
+        }));
+        assert_eq!(run("max + 1"), overflow(26, 1));
+        assert_eq!(run("min - 1"), overflow(26, 1));
+        assert_eq!(run("max * 2"), overflow(26, 1));
+        assert_eq!(run("min DIV -1"), overflow(26, 3));
+        assert_eq!(run("-min"), overflow(22, 1));
+        assert_eq!(run("max + min"), None);
+    }
+}
@@ -0,0 +1,565 @@
+use crate::tokens::*;
+use crate::ast::*;
+use crate::symbols::*;
+
+use std::collections::HashMap;
+
+/// Host functions and helpers of the generated module. Output goes
+/// through the `env` imports, division by zero calls the host with
+/// the location and traps.
+const PRELUDE: &str = r#"  (import "env" "write_i32" (func $write_i32 (param i32)))
+  (import "env" "write_f64" (func $write_f64 (param f64)))
+  (import "env" "writeln" (func $writeln))
+  (import "env" "division_by_zero" (func $division_by_zero (param i32 i32)))
+  (memory 16)
+  ;; top of the stack of procedure frames
+  (global $sp (mut i32) (i32.const 0))
+  (func $int_div (param $a i32) (param $b i32) (param $line i32) (param $column i32) (result i32)
+    local.get $b
+    i32.eqz
+    if
+      local.get $line
+      local.get $column
+      call $division_by_zero
+      unreachable
+    end
+    local.get $a
+    local.get $b
+    i32.div_s)
+  (func $real_div (param $a f64) (param $b f64) (param $line i32) (param $column i32) (result f64)
+    local.get $b
+    f64.const 0
+    f64.eq
+    if
+      local.get $line
+      local.get $column
+      call $division_by_zero
+      unreachable
+    end
+    local.get $a
+    local.get $b
+    f64.div)
+"#;
+
+/// Procedure with a body as seen by the generated code
+struct WatProc {
+    /// Unique function name, nested procedures may share Pascal names
+    name: String,
+    /// Nesting level of the body
+    level: u32,
+    params: Vec<(String, Type)>,
+    locals: Vec<(String, Type)>,
+    /// Variables live in a memory frame because nested procedures
+    /// access them, otherwise they are wasm locals
+    framed: bool,
+}
+
+/// Declared variable: owning procedure (`None` for globals),
+/// index among parameters and locals, and type
+#[derive(Copy, Clone)]
+struct WatVar {
+    owner: Option<usize>,
+    slot: usize,
+    typ: Type,
+}
+
+/// Translate the analyzed tree into WebAssembly text. INTEGER maps to
+/// `i32`, REAL to `f64`, globals to exported wasm globals and the
+/// program body to the exported `main` function.
+///
+/// A procedure containing nested procedures keeps its frame in linear
+/// memory: the static link at offset 0 followed by 8 byte slots.
+/// Every procedure function takes the frame of the enclosing
+/// procedure as the first parameter `$up`.
+pub struct WatGenerator<'a> {
+    resolutions: &'a HashMap<Span, Resolution>,
+    procs: Vec<WatProc>,
+    /// Indices of `procs` keyed by location of the declaration
+    proc_index: HashMap<Span, usize>,
+    /// Variables keyed by location of the declaration
+    vars: HashMap<Span, WatVar>,
+    /// Procedure being generated, `None` for the program body
+    current: Option<usize>,
+    out: String,
+    indent: usize,
+}
+
+impl<'a> WatGenerator<'a> {
+    pub fn new(resolutions: &'a HashMap<Span, Resolution>) -> Self {
+        WatGenerator {
+            resolutions,
+            procs: Vec::new(),
+            proc_index: HashMap::new(),
+            vars: HashMap::new(),
+            current: None,
+            out: String::new(),
+            indent: 0,
+        }
+    }
+
+    pub fn generate(mut self, program: &Program) -> String {
+        let procedures = procedures(program);
+        self.collect(program, &procedures);
+        self.out.push_str("(module\n");
+        self.out.push_str(PRELUDE);
+        for decl in &program.block.decls {
+            if let Decl::Var(var) = decl {
+                let name = &var.name.name;
+                let typ = wasm_type(var.typ);
+                self.out.push_str(&format!("  (global ${} (mut {}) ({}.const 0))\n", name, typ, typ));
+                self.out.push_str(&format!("  (export \"{}\" (global ${}))\n", name, name));
+            }
+        }
+        for (index, body) in procedures.iter().enumerate() {
+            self.current = Some(index);
+            self.function(index, body.block);
+        }
+        self.current = None;
+        self.out.push_str("  (func $main (export \"main\")\n");
+        self.indent = 2;
+        for stmt in &program.block.body {
+            self.statement(stmt);
+        }
+        self.close();
+        self.out.push_str(")\n");
+        self.out
+    }
+
+    /// Name the procedures and place the variables
+    fn collect(&mut self, program: &Program, procedures: &[ProcedureBody]) {
+        for decl in &program.block.decls {
+            if let Decl::Var(var) = decl {
+                self.vars.insert(var.name.span, WatVar { owner: None, slot: 0, typ: var.typ });
+            }
+        }
+        for (index, body) in procedures.iter().enumerate() {
+            let owner = Some(index);
+            let mut proc = WatProc {
+                name: format!("p{}_{}", index, body.decl.name.name),
+                level: body.level,
+                params: Vec::new(),
+                locals: Vec::new(),
+                framed: false,
+            };
+            for (slot, param) in body.decl.params.iter().enumerate() {
+                proc.params.push((param.name.name.to_string(), param.typ));
+                self.vars.insert(param.name.span, WatVar { owner, slot, typ: param.typ });
+            }
+            for decl in &body.block.decls {
+                if let Decl::Var(var) = decl {
+                    let slot = proc.params.len() + proc.locals.len();
+                    proc.locals.push((var.name.name.to_string(), var.typ));
+                    self.vars.insert(var.name.span, WatVar { owner, slot, typ: var.typ });
+                }
+            }
+            self.procs.push(proc);
+            self.proc_index.insert(body.decl.name.span, index);
+            if let Some(parent) = body.parent {
+                self.procs[parent].framed = true;
+            }
+        }
+    }
+
+    fn function(&mut self, index: usize, block: &Block) {
+        let proc = &self.procs[index];
+        let mut head = format!("  (func ${} (param $up i32)", proc.name);
+        for (name, typ) in &proc.params {
+            head.push_str(&format!(" (param ${} {})", name, wasm_type(*typ)));
+        }
+        self.out.push_str(&head);
+        self.out.push('\n');
+        self.indent = 2;
+        if proc.framed {
+            let size = 8 * (1 + proc.params.len() + proc.locals.len());
+            let params = proc.params.clone();
+            let slots = params.len() + proc.locals.len();
+            self.ins("(local $fp i32)");
+            self.ins("global.get $sp");
+            self.ins("local.tee $fp");
+            self.ins(&format!("i32.const {}", size));
+            self.ins("i32.add");
+            self.ins("global.set $sp");
+            self.ins("local.get $fp");
+            self.ins("local.get $up");
+            self.ins("i32.store");
+            for slot in 0..slots {
+                let typ = match params.get(slot) {
+                    Some((name, typ)) => {
+                        self.ins("local.get $fp");
+                        self.ins(&format!("local.get ${}", name));
+                        *typ
+                    },
+                    None => {
+                        let typ = self.procs[index].locals[slot - params.len()].1;
+                        self.ins("local.get $fp");
+                        self.ins(&format!("{}.const 0", wasm_type(typ)));
+                        typ
+                    },
+                };
+                self.ins(&format!("{}.store offset={}", wasm_type(typ), offset(slot)));
+            }
+        }
+        else {
+            let locals: Vec<_> = proc.locals.iter()
+                .map(|(name, typ)| format!("(local ${} {})", name, wasm_type(*typ)))
+                .collect();
+            for local in locals {
+                self.ins(&local);
+            }
+        }
+        for stmt in &block.body {
+            self.statement(stmt);
+        }
+        if self.procs[index].framed {
+            self.ins("local.get $fp");
+            self.ins("global.set $sp");
+        }
+        self.close();
+    }
+
+    /// Append instruction on its own line
+    fn ins(&mut self, text: &str) {
+        for _ in 0..self.indent {
+            self.out.push_str("  ");
+        }
+        self.out.push_str(text);
+        self.out.push('\n');
+    }
+
+    /// Close the function started with `(func`
+    fn close(&mut self) {
+        if self.out.ends_with('\n') {
+            self.out.pop();
+        }
+        self.out.push_str(")\n");
+    }
+
+    fn level(&self) -> u32 {
+        match self.current {
+            Some(index) => self.procs[index].level,
+            None => 1,
+        }
+    }
+
+    fn statement(&mut self, stmt: &Stmt) {
+        match stmt {
+            Stmt::Compound{body, ..} => {
+                for stmt in body {
+                    self.statement(stmt);
+                }
+            },
+            Stmt::Assign{target, value} => {
+                let var = self.var(target);
+                let framed = self.frame_address(var);
+                self.expr(value, var.typ);
+                match var.owner {
+                    None => self.ins(&format!("global.set ${}", target.name)),
+                    Some(_) if framed =>
+                        self.ins(&format!("{}.store offset={}", wasm_type(var.typ), offset(var.slot))),
+                    Some(_) => self.ins(&format!("local.set ${}", target.name)),
+                }
+            },
+            Stmt::Call{name, args} => {
+                let resolution = self.resolutions[&name.span];
+                let index = self.proc_index[&resolution.decl];
+                self.static_link(resolution.level);
+                let params: Vec<_> = self.procs[index].params.iter().map(|(_, typ)| *typ).collect();
+                for (arg, typ) in args.iter().zip(params) {
+                    self.expr(arg, typ);
+                }
+                self.ins(&format!("call ${}", self.procs[index].name));
+            },
+            Stmt::If{cond, then_branch, else_branch, ..} => {
+                self.expr(cond, Type::Integer);
+                self.ins("if");
+                self.indent += 1;
+                self.statement(then_branch);
+                self.indent -= 1;
+                if let Some(else_branch) = else_branch {
+                    self.ins("else");
+                    self.indent += 1;
+                    self.statement(else_branch);
+                    self.indent -= 1;
+                }
+                self.ins("end");
+            },
+            Stmt::While{cond, body, ..} => {
+                self.ins("block");
+                self.indent += 1;
+                self.ins("loop");
+                self.indent += 1;
+                self.expr(cond, Type::Integer);
+                self.ins("i32.eqz");
+                self.ins("br_if 1");
+                self.statement(body);
+                self.ins("br 0");
+                self.indent -= 1;
+                self.ins("end");
+                self.indent -= 1;
+                self.ins("end");
+            },
+            Stmt::Writeln{args, ..} => {
+                for arg in args {
+                    let typ = type_of(arg, self.resolutions).unwrap();
+                    self.expr(arg, typ);
+                    self.ins(&format!("call $write_{}", wasm_type(typ)));
+                }
+                self.ins("call $writeln");
+            },
+            Stmt::NoOp => {},
+        }
+    }
+
+    /// Emit the expression converted to `want`
+    fn expr(&mut self, expr: &Expr, want: Type) {
+        let typ = type_of(expr, self.resolutions).unwrap();
+        match expr {
+            Expr::Num{value, ..} => match value {
+                VariableValue::Integer(n) => self.ins(&format!("i32.const {}", n)),
+                VariableValue::Real(n) => self.ins(&format!("f64.const {:?}", n)),
+                VariableValue::Boolean(b) => self.ins(&format!("i32.const {}", *b as i32)),
+                _ => unreachable!("constant {:?}", value),
+            },
+            Expr::Var(ident) => {
+                let var = self.var(ident);
+                let framed = self.frame_address(var);
+                match var.owner {
+                    None => self.ins(&format!("global.get ${}", ident.name)),
+                    Some(_) if framed =>
+                        self.ins(&format!("{}.load offset={}", wasm_type(var.typ), offset(var.slot))),
+                    Some(_) => self.ins(&format!("local.get ${}", ident.name)),
+                }
+            },
+            Expr::BinOp{op, left, right, span} => {
+                let operands = match op {
+                    BinOp::Div => Type::Real,
+                    BinOp::IntDiv => Type::Integer,
+                    _ => {
+                        let operand = |expr| type_of(expr, self.resolutions).unwrap();
+                        arithmetic_type(operand(left), operand(right))
+                    },
+                };
+                self.expr(left, operands);
+                self.expr(right, operands);
+                match op {
+                    BinOp::Div | BinOp::IntDiv => {
+                        self.ins(&format!("i32.const {}", span.line));
+                        self.ins(&format!("i32.const {}", span.column));
+                        let helper = if *op == BinOp::Div { "real_div" } else { "int_div" };
+                        self.ins(&format!("call ${}", helper));
+                    },
+                    _ => self.ins(&format!("{}.{}", wasm_type(operands), wasm_operator(*op, operands))),
+                }
+            },
+            Expr::UnaryOp{op: UnaryOp::Plus, operand, ..} => self.expr(operand, typ),
+            Expr::UnaryOp{op: UnaryOp::Minus, operand, ..} => match typ {
+                Type::Integer => {
+                    self.ins("i32.const 0");
+                    self.expr(operand, typ);
+                    self.ins("i32.sub");
+                },
+                Type::Real => {
+                    self.expr(operand, typ);
+                    self.ins("f64.neg");
+                },
+            },
+            Expr::Call{name, ..} => unreachable!("native function {}", name.name),
+        }
+        match (typ, want) {
+            (Type::Integer, Type::Real) => self.ins("f64.convert_i32_s"),
+            (Type::Real, Type::Integer) => self.ins("i32.trunc_sat_f64_s"),
+            _ => {},
+        }
+    }
+
+    fn var(&self, ident: &Ident) -> WatVar {
+        self.vars[&self.resolutions[&ident.span].decl]
+    }
+
+    /// Push the frame address of a variable living in memory,
+    /// returns whether it does
+    fn frame_address(&mut self, var: WatVar) -> bool {
+        let owner = match var.owner {
+            Some(owner) if self.procs[owner].framed => owner,
+            _ => return false,
+        };
+        if Some(owner) == self.current {
+            self.ins("local.get $fp");
+        }
+        else {
+            self.outer_frame(self.procs[owner].level);
+        }
+        true
+    }
+
+    /// Push the frame of the enclosing procedure at `level`
+    fn outer_frame(&mut self, level: u32) {
+        self.ins("local.get $up");
+        for _ in level..self.level() - 1 {
+            self.ins("i32.load");
+        }
+    }
+
+    /// Frame passed to a procedure declared in the scope at `level`
+    fn static_link(&mut self, level: u32) {
+        if level == 1 {
+            self.ins("i32.const 0");
+        }
+        else if level == self.level() {
+            self.ins("local.get $fp");
+        }
+        else {
+            self.outer_frame(level);
+        }
+    }
+}
+
+/// Byte offset of the slot in a frame, after the static link
+fn offset(slot: usize) -> usize {
+    8 * (slot + 1)
+}
+
+fn wasm_type(typ: Type) -> &'static str {
+    match typ {
+        Type::Integer => "i32",
+        Type::Real => "f64",
+    }
+}
+
+fn arithmetic_type(left: Type, right: Type) -> Type {
+    match (left, right) {
+        (Type::Integer, Type::Integer) => Type::Integer,
+        _ => Type::Real,
+    }
+}
+
+fn wasm_operator(op: BinOp, typ: Type) -> &'static str {
+    match (op, typ) {
+        (BinOp::Add, _) => "add",
+        (BinOp::Sub, _) => "sub",
+        (BinOp::Mul, _) => "mul",
+        (BinOp::Eq, _) => "eq",
+        (BinOp::Ne, _) => "ne",
+        (BinOp::Lt, Type::Integer) => "lt_s",
+        (BinOp::Le, Type::Integer) => "le_s",
+        (BinOp::Gt, Type::Integer) => "gt_s",
+        (BinOp::Ge, Type::Integer) => "ge_s",
+        (BinOp::Lt, Type::Real) => "lt",
+        (BinOp::Le, Type::Real) => "le",
+        (BinOp::Gt, Type::Real) => "gt",
+        (BinOp::Ge, Type::Real) => "ge",
+        (BinOp::Div, _) | (BinOp::IntDiv, _) => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::interpreter::Interpreter;
+    use std::io;
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+
+    const SAMPLES: [(&str, &str); 3] = [
+        ("part14", r#"
+        program Part14;
+        var
+            x, y : integer;
+            a, b : real;
+        procedure foo2(ii : integer); begin ii := 42 end;
+        procedure foo3(iii, jjj : integer; kkk : real);
+            var x : real;
+        begin
+            x := iii / jjj + kkk
+        end;
+        begin
+            y := 3;
+            x := y * 2 - 1;
+            a := x DIV 2;
+            b := -a / 4;
+            foo2(x);
+            foo3(x, y, b);
+            writeln(x, a, b)
+        end."#),
+        ("nested", r#"
+        program Nested;
+        var a, r : integer;
+        procedure P1(n : integer);
+            var a : integer;
+            procedure P2;
+            begin
+                a := a + n;
+                r := r + 1
+            end;
+            procedure P3;
+                var a : real;
+            begin
+                a := 0.5;
+                P2
+            end;
+        begin
+            a := 10;
+            P3;
+            if n > 0 then P1(n - 1);
+            r := r * 2 + a
+        end;
+        begin
+            r := 0;
+            a := 7;
+            P1(2);
+            writeln(a, r)
+        end."#),
+        ("loops", r#"
+        program Loops;
+        var i, even : integer;
+            sum : real;
+        procedure IsOdd(n : integer); forward;
+        procedure IsEven(n : integer);
+        begin
+            if n = 0 then even := 1 else IsOdd(n - 1)
+        end;
+        procedure IsOdd(n : integer);
+        begin
+            if n = 0 then even := 0 else IsEven(n - 1)
+        end;
+        begin
+            i := 0;
+            sum := 0;
+            while i < 5 do
+            begin
+                IsEven(i);
+                sum := sum + even / 2;
+                i := i + 1
+            end;
+            writeln(sum)
+        end."#),
+    ];
+
+    /// Compare with `testdata/wat/<name>.wat`, `UPDATE_GOLDEN=1`
+    /// rewrites the files instead
+    #[test]
+    fn golden() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/wat");
+        for (name, text) in SAMPLES.iter() {
+            let wat = Interpreter::new(*text).report(io::sink()).emit_wat().unwrap();
+            let path = dir.join(format!("{}.wat", name));
+            if env::var_os("UPDATE_GOLDEN").is_some() {
+                fs::write(&path, &wat).unwrap();
+            }
+            let expected = fs::read_to_string(&path).unwrap();
+            assert_eq!(wat, expected, "{}", name);
+        }
+    }
+
+    #[cfg(feature = "validate")]
+    #[test]
+    fn validate() {
+        for (name, text) in SAMPLES.iter() {
+            let wat = Interpreter::new(*text).report(io::sink()).emit_wat().unwrap();
+            let wasm = ::wat::parse_str(&wat).unwrap_or_else(|e| panic!("{}: {}", name, e));
+            if let Err(e) = wasmparser::validate(&wasm) {
+                panic!("{}: {}", name, e);
+            }
+        }
+    }
+}
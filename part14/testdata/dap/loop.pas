@@ -0,0 +1,8 @@
+PROGRAM Loop;
+VAR
+    i : INTEGER;
+BEGIN
+    i := 0;
+    WHILE i >= 0 DO
+        i := i + 1
+END.
@@ -0,0 +1,30 @@
+PROGRAM Part12;
+VAR
+    a : INTEGER;
+
+PROCEDURE P1;
+VAR
+    a : REAL;
+    k : INTEGER;
+
+    PROCEDURE P2;
+    VAR
+        a, z : INTEGER;
+    BEGIN {P2}
+        z := 777;
+        a := z + k;
+        WRITELN(a)
+    END;  {P2}
+
+BEGIN {P1}
+    k := 5;
+    a := k / 2;
+    P2;
+    WRITELN(a)
+END;  {P1}
+
+BEGIN {Part12}
+    a := 10;
+    P1;
+    WRITELN(a)
+END.  {Part12}
@@ -0,0 +1,20 @@
+program Part14;
+var
+    x, y : integer;
+    a, b : real;
+var c : integer;
+    d : integer;
+procedure foo1; begin end;
+procedure foo2(ii:integer); begin ii:=42 end;
+procedure foo3(iii, jjj:integer; kkk:real);
+    var x : real;
+    begin
+        {y := x + iii;}
+    end;
+begin
+    y := 3;
+    x := y;
+    a := 7 / 2;
+    writeln(x);
+    writeln(a)
+end.
@@ -0,0 +1,104 @@
+(module
+  (import "env" "write_i32" (func $write_i32 (param i32)))
+  (import "env" "write_f64" (func $write_f64 (param f64)))
+  (import "env" "writeln" (func $writeln))
+  (import "env" "division_by_zero" (func $division_by_zero (param i32 i32)))
+  (memory 16)
+  ;; top of the stack of procedure frames
+  (global $sp (mut i32) (i32.const 0))
+  (func $int_div (param $a i32) (param $b i32) (param $line i32) (param $column i32) (result i32)
+    local.get $b
+    i32.eqz
+    if
+      local.get $line
+      local.get $column
+      call $division_by_zero
+      unreachable
+    end
+    local.get $a
+    local.get $b
+    i32.div_s)
+  (func $real_div (param $a f64) (param $b f64) (param $line i32) (param $column i32) (result f64)
+    local.get $b
+    f64.const 0
+    f64.eq
+    if
+      local.get $line
+      local.get $column
+      call $division_by_zero
+      unreachable
+    end
+    local.get $a
+    local.get $b
+    f64.div)
+  (global $I (mut i32) (i32.const 0))
+  (export "I" (global $I))
+  (global $EVEN (mut i32) (i32.const 0))
+  (export "EVEN" (global $EVEN))
+  (global $SUM (mut f64) (f64.const 0))
+  (export "SUM" (global $SUM))
+  (func $p0_ISEVEN (param $up i32) (param $N i32)
+    local.get $N
+    i32.const 0
+    i32.eq
+    if
+      i32.const 1
+      global.set $EVEN
+    else
+      i32.const 0
+      local.get $N
+      i32.const 1
+      i32.sub
+      call $p1_ISODD
+    end)
+  (func $p1_ISODD (param $up i32) (param $N i32)
+    local.get $N
+    i32.const 0
+    i32.eq
+    if
+      i32.const 0
+      global.set $EVEN
+    else
+      i32.const 0
+      local.get $N
+      i32.const 1
+      i32.sub
+      call $p0_ISEVEN
+    end)
+  (func $main (export "main")
+    i32.const 0
+    global.set $I
+    i32.const 0
+    f64.convert_i32_s
+    global.set $SUM
+    block
+      loop
+        global.get $I
+        i32.const 5
+        i32.lt_s
+        i32.eqz
+        br_if 1
+        i32.const 0
+        global.get $I
+        call $p0_ISEVEN
+        global.get $SUM
+        global.get $EVEN
+        f64.convert_i32_s
+        i32.const 2
+        f64.convert_i32_s
+        i32.const 20
+        i32.const 35
+        call $real_div
+        f64.add
+        global.set $SUM
+        global.get $I
+        i32.const 1
+        i32.add
+        global.set $I
+        br 0
+      end
+    end
+    global.get $SUM
+    call $write_f64
+    call $writeln)
+)
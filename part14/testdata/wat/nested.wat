@@ -0,0 +1,111 @@
+(module
+  (import "env" "write_i32" (func $write_i32 (param i32)))
+  (import "env" "write_f64" (func $write_f64 (param f64)))
+  (import "env" "writeln" (func $writeln))
+  (import "env" "division_by_zero" (func $division_by_zero (param i32 i32)))
+  (memory 16)
+  ;; top of the stack of procedure frames
+  (global $sp (mut i32) (i32.const 0))
+  (func $int_div (param $a i32) (param $b i32) (param $line i32) (param $column i32) (result i32)
+    local.get $b
+    i32.eqz
+    if
+      local.get $line
+      local.get $column
+      call $division_by_zero
+      unreachable
+    end
+    local.get $a
+    local.get $b
+    i32.div_s)
+  (func $real_div (param $a f64) (param $b f64) (param $line i32) (param $column i32) (result f64)
+    local.get $b
+    f64.const 0
+    f64.eq
+    if
+      local.get $line
+      local.get $column
+      call $division_by_zero
+      unreachable
+    end
+    local.get $a
+    local.get $b
+    f64.div)
+  (global $A (mut i32) (i32.const 0))
+  (export "A" (global $A))
+  (global $R (mut i32) (i32.const 0))
+  (export "R" (global $R))
+  (func $p0_P1 (param $up i32) (param $N i32)
+    (local $fp i32)
+    global.get $sp
+    local.tee $fp
+    i32.const 24
+    i32.add
+    global.set $sp
+    local.get $fp
+    local.get $up
+    i32.store
+    local.get $fp
+    local.get $N
+    i32.store offset=8
+    local.get $fp
+    i32.const 0
+    i32.store offset=16
+    local.get $fp
+    i32.const 10
+    i32.store offset=16
+    local.get $fp
+    call $p2_P3
+    local.get $fp
+    i32.load offset=8
+    i32.const 0
+    i32.gt_s
+    if
+      i32.const 0
+      local.get $fp
+      i32.load offset=8
+      i32.const 1
+      i32.sub
+      call $p0_P1
+    end
+    global.get $R
+    i32.const 2
+    i32.mul
+    local.get $fp
+    i32.load offset=16
+    i32.add
+    global.set $R
+    local.get $fp
+    global.set $sp)
+  (func $p1_P2 (param $up i32)
+    local.get $up
+    local.get $up
+    i32.load offset=16
+    local.get $up
+    i32.load offset=8
+    i32.add
+    i32.store offset=16
+    global.get $R
+    i32.const 1
+    i32.add
+    global.set $R)
+  (func $p2_P3 (param $up i32)
+    (local $A f64)
+    f64.const 0.5
+    local.set $A
+    local.get $up
+    call $p1_P2)
+  (func $main (export "main")
+    i32.const 0
+    global.set $R
+    i32.const 7
+    global.set $A
+    i32.const 0
+    i32.const 2
+    call $p0_P1
+    global.get $A
+    call $write_i32
+    global.get $R
+    call $write_i32
+    call $writeln)
+)
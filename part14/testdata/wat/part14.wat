@@ -0,0 +1,96 @@
+(module
+  (import "env" "write_i32" (func $write_i32 (param i32)))
+  (import "env" "write_f64" (func $write_f64 (param f64)))
+  (import "env" "writeln" (func $writeln))
+  (import "env" "division_by_zero" (func $division_by_zero (param i32 i32)))
+  (memory 16)
+  ;; top of the stack of procedure frames
+  (global $sp (mut i32) (i32.const 0))
+  (func $int_div (param $a i32) (param $b i32) (param $line i32) (param $column i32) (result i32)
+    local.get $b
+    i32.eqz
+    if
+      local.get $line
+      local.get $column
+      call $division_by_zero
+      unreachable
+    end
+    local.get $a
+    local.get $b
+    i32.div_s)
+  (func $real_div (param $a f64) (param $b f64) (param $line i32) (param $column i32) (result f64)
+    local.get $b
+    f64.const 0
+    f64.eq
+    if
+      local.get $line
+      local.get $column
+      call $division_by_zero
+      unreachable
+    end
+    local.get $a
+    local.get $b
+    f64.div)
+  (global $X (mut i32) (i32.const 0))
+  (export "X" (global $X))
+  (global $Y (mut i32) (i32.const 0))
+  (export "Y" (global $Y))
+  (global $A (mut f64) (f64.const 0))
+  (export "A" (global $A))
+  (global $B (mut f64) (f64.const 0))
+  (export "B" (global $B))
+  (func $p0_FOO2 (param $up i32) (param $II i32)
+    i32.const 42
+    local.set $II)
+  (func $p1_FOO3 (param $up i32) (param $III i32) (param $JJJ i32) (param $KKK f64)
+    (local $X f64)
+    local.get $III
+    f64.convert_i32_s
+    local.get $JJJ
+    f64.convert_i32_s
+    i32.const 10
+    i32.const 22
+    call $real_div
+    local.get $KKK
+    f64.add
+    local.set $X)
+  (func $main (export "main")
+    i32.const 3
+    global.set $Y
+    global.get $Y
+    i32.const 2
+    i32.mul
+    i32.const 1
+    i32.sub
+    global.set $X
+    global.get $X
+    i32.const 2
+    i32.const 15
+    i32.const 20
+    call $int_div
+    f64.convert_i32_s
+    global.set $A
+    global.get $A
+    f64.neg
+    i32.const 4
+    f64.convert_i32_s
+    i32.const 16
+    i32.const 21
+    call $real_div
+    global.set $B
+    i32.const 0
+    global.get $X
+    call $p0_FOO2
+    i32.const 0
+    global.get $X
+    global.get $Y
+    global.get $B
+    call $p1_FOO3
+    global.get $X
+    call $write_i32
+    global.get $A
+    call $write_f64
+    global.get $B
+    call $write_f64
+    call $writeln)
+)